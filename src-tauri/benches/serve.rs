@@ -0,0 +1,76 @@
+//! Compares serving chunks as raw payloads (sendfile on Linux) against JSON
+//! frames, by timing downloads between two nodes on this machine.
+//! Run with `cargo bench --bench serve`.
+
+use peapod::config::Config;
+use peapod::events::Event;
+use peapod::Node;
+use std::path::Path;
+use std::time::Instant;
+use tokio::time::Duration;
+
+const FILE_SIZE: usize = 256 * 1024 * 1024;
+const ROUNDS: usize = 3;
+/// Keeps the bench nodes from finding, or being found by, nodes on the default port.
+const DISCOVERY_PORT: u16 = 50020;
+
+fn node(id: &str, tcp_port: u16, raw_payloads: bool, source_dir: &Path) -> Node {
+    let mut config = Config::default();
+    config.node.data_dir = Some(std::env::temp_dir().join("peapod_bench").join(id));
+    config.transport.port = tcp_port;
+    config.discovery.port = DISCOVERY_PORT;
+    config.transport.raw_payloads = raw_payloads;
+    config.files.download_dir = Some(source_dir.to_path_buf());
+    Node::builder(config).device_id(id).api(false).build()
+}
+
+/// Best throughput in MB/s over a few downloads of `source` from a fresh pair of nodes.
+async fn measure(raw_payloads: bool, ports: (u16, u16), source: &Path) -> f64 {
+    let mode = if raw_payloads { "RAW" } else { "JSON" };
+    let source_dir = source.parent().unwrap();
+    let server = node(&format!("BENCH-{}-A", mode), ports.0, raw_payloads, source_dir);
+    let client = node(&format!("BENCH-{}-B", mode), ports.1, raw_payloads, source_dir);
+    server.start().await.expect("start server");
+    client.start().await.expect("start client");
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    let manifest = server.share(source).await.expect("share");
+
+    let mut best: f64 = 0.0;
+    for round in 0..ROUNDS {
+        let mut events = client.subscribe();
+        let name = format!("peapod_bench_{}_{}.bin", mode, round);
+        let started = Instant::now();
+        let file_id = client.download(&manifest.file_id, &name, true).await.expect("download");
+        loop {
+            match events.recv().await.expect("event stream closed") {
+                Event::TransferCompleted { file_id: id } if id == file_id => break,
+                Event::TransferFailed { file_id: id, reason } if id == file_id => panic!("download failed: {}", reason),
+                _ => {}
+            }
+        }
+        let mb_per_sec = FILE_SIZE as f64 / 1e6 / started.elapsed().as_secs_f64();
+        println!("{:>4} round {}: {:.0} MB/s", mode, round + 1, mb_per_sec);
+        best = best.max(mb_per_sec);
+        if let Some(transfer) = client.transfer(&file_id) {
+            let _ = std::fs::remove_file(transfer.output_path);
+        }
+    }
+    let _ = tokio::join!(server.shutdown(), client.shutdown());
+    best
+}
+
+#[tokio::main]
+async fn main() {
+    let source_dir = std::env::temp_dir().join("peapod_bench").join("files");
+    std::fs::create_dir_all(&source_dir).unwrap();
+    let source = source_dir.join("peapod_bench_source.bin");
+    let data: Vec<u8> = (0..FILE_SIZE as u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&source, &data).unwrap();
+    drop(data);
+
+    let json = measure(false, (50021, 50022), &source).await;
+    let raw = measure(true, (50023, 50024), &source).await;
+    println!("JSON frames: {:.0} MB/s", json);
+    println!("Raw payloads: {:.0} MB/s ({:.2}x)", raw, raw / json);
+    let _ = std::fs::remove_file(&source);
+}
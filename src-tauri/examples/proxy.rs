@@ -0,0 +1,93 @@
+use axum::extract::State;
+use axum::http::header::{ACCEPT_RANGES, CONTENT_RANGE, RANGE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use peapod::config::Config;
+use peapod::Node;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::time::Duration;
+
+const ORIGIN_PORT: u16 = 50080;
+const PROXY_PORT: u16 = 50088;
+
+struct Origin {
+    data: Vec<u8>,
+    range_requests: AtomicUsize,
+}
+
+/// A plain web server with byte range support, standing in for the internet.
+async fn serve_file(State(origin): State<Arc<Origin>>, headers: HeaderMap) -> Response {
+    let total = origin.data.len();
+    let range = headers.get(RANGE).and_then(|v| v.to_str().ok()).and_then(|r| r.strip_prefix("bytes="));
+    let Some((start, end)) = range.and_then(|r| r.split_once('-')) else {
+        return ([(ACCEPT_RANGES, "bytes")], origin.data.clone()).into_response();
+    };
+    origin.range_requests.fetch_add(1, Ordering::Relaxed);
+    let start: usize = start.parse().unwrap_or(0);
+    let end = end.parse::<usize>().map(|e| e + 1).unwrap_or(total).min(total);
+    let content_range = format!("bytes {}-{}/{}", start, end - 1, total);
+    (
+        StatusCode::PARTIAL_CONTENT,
+        [(ACCEPT_RANGES, "bytes".to_string()), (CONTENT_RANGE, content_range)],
+        origin.data[start..end].to_vec(),
+    )
+        .into_response()
+}
+
+#[tokio::main]
+async fn main() {
+    println!("Starting PeaPod proxy simulation...");
+    peapod::logging::init(&Default::default()).expect("logging already set up");
+
+    let origin = Arc::new(Origin {
+        data: (0..24 * 1024 * 1024).map(|i: u32| (i % 251) as u8).collect(),
+        range_requests: AtomicUsize::new(0),
+    });
+    let app = Router::new()
+        .route("/big.bin", get(serve_file))
+        .route("/small.txt", get(|| async { "hello from the origin" }))
+        .with_state(origin.clone());
+    let listener = TcpListener::bind(("127.0.0.1", ORIGIN_PORT)).await.unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    // Node A helps out, node B runs the proxy. They find each other by discovery.
+    let mut config_a = Config::default();
+    config_a.node.data_dir = Some(std::env::temp_dir().join("peapod_proxy_sim").join("a"));
+    config_a.transport.port = 50003;
+    // The web server is on this machine, so A must be let fetch from loopback.
+    config_a.proxy.serve_peers = true;
+    config_a.proxy.serve_private = true;
+    let mut config_b = Config::default();
+    config_b.node.data_dir = Some(std::env::temp_dir().join("peapod_proxy_sim").join("b"));
+    config_b.transport.port = 50004;
+    config_b.proxy.enabled = true;
+    config_b.proxy.port = PROXY_PORT;
+    config_b.proxy.min_size = 1024 * 1024;
+    let node_a = Node::builder(config_a).device_id("NODE-A").api(false).build();
+    let node_b = Node::builder(config_b).device_id("NODE-B").api(false).build();
+    let state_a = node_a.state().clone();
+    node_a.start().await.expect("start A");
+    node_b.start().await.expect("start B");
+    tokio::time::sleep(Duration::from_secs(5)).await;
+
+    let proxy = reqwest::Proxy::http(format!("http://127.0.0.1:{}", PROXY_PORT)).unwrap();
+    let client = reqwest::Client::builder().proxy(proxy).build().unwrap();
+
+    let small = client.get(format!("http://127.0.0.1:{}/small.txt", ORIGIN_PORT)).send().await.unwrap();
+    println!("Small request passed through: {:?}", small.text().await.unwrap());
+
+    let big = client.get(format!("http://127.0.0.1:{}/big.bin", ORIGIN_PORT)).send().await.unwrap();
+    let body = big.bytes().await.unwrap();
+    println!(
+        "Big request: {} bytes, identical: {}, {} range requests hit the origin",
+        body.len(),
+        body[..] == origin.data[..],
+        origin.range_requests.load(Ordering::Relaxed)
+    );
+    println!("Node A proxied: {:?}", state_a.usage.report().today);
+    println!("Simulation finished.");
+}
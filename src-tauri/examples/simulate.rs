@@ -1,94 +1,90 @@
-use peapod::discovery::run_discovery;
-use peapod::protocol::Beacon;
-use peapod::state::{AppState, PeerEmitter};
-use peapod::transport::run_tcp_listener;
-use peapod::chunk::ChunkManager;
-use std::sync::Arc;
+use peapod::config::Config;
+use peapod::events::{run_event_sink, Event, EventSink};
+use peapod::Node;
 use tokio::time::Duration;
 
+fn node(id: &str, name: &str, tcp_port: u16) -> Node {
+    let mut config = Config::default();
+    config.node.name = Some(name.into());
+    config.node.data_dir = Some(std::env::temp_dir().join("peapod_sim").join(id));
+    config.transport.port = tcp_port;
+    config.files.download_dir = config.node.data_dir.as_ref().map(|dir| dir.join("downloads"));
+    Node::builder(config).device_id(id).api(false).build()
+}
+
 struct ConsoleEmitter {
     owner: String,
 }
 
-impl PeerEmitter for ConsoleEmitter {
-    fn emit(&self, peer: Beacon) {
-        println!(
-            "[{}] UI Event: Discovered peer {} on port {}",
-            self.owner, peer.name, peer.port
-        );
+impl EventSink for ConsoleEmitter {
+    fn emit(&self, event: &Event) {
+        match event {
+            Event::PeerDiscovered { peer } => println!(
+                "[{}] UI Event: Discovered peer {} on port {}",
+                self.owner, peer.name, peer.port
+            ),
+            Event::TransferCompleted { file_id } => {
+                println!("[{}] UI Event: Transfer {} completed", self.owner, file_id)
+            }
+            _ => {}
+        }
     }
 }
 
 #[tokio::main]
 async fn main() {
     println!("Starting PeaPod Simulation...");
+    peapod::logging::init(&Default::default()).expect("logging already set up");
 
-    // NODE A
-    let id_a = "NODE-A".to_string();
-    let port_a = 50001;
-    let chunk_manager_a = Arc::new(ChunkManager::new());
-    let state_a = Arc::new(AppState {
-        chunk_manager: chunk_manager_a,
-        ..Default::default()
-    });
-    let emitter_a = ConsoleEmitter { owner: "A".into() };
+    // Both nodes discover each other on UDP 45678
+    let node_a = node("NODE-A", "Node A", 50001);
+    let node_b = node("NODE-B", "Node B", 50002);
+    tokio::spawn(run_event_sink(node_a.state().clone(), ConsoleEmitter { owner: "A".into() }));
+    tokio::spawn(run_event_sink(node_b.state().clone(), ConsoleEmitter { owner: "B".into() }));
+    node_a.start().await.expect("start A");
+    node_b.start().await.expect("start B");
 
-    // NODE B
-    let id_b = "NODE-B".to_string();
-    let port_b = 50002;
-    let chunk_manager_b = Arc::new(ChunkManager::new());
-    let state_b = Arc::new(AppState {
-        chunk_manager: chunk_manager_b,
-        ..Default::default()
-    });
-    let emitter_b = ConsoleEmitter { owner: "B".into() };
+    // Allow the nodes to find each other
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    println!("Node B sees: {:?}", node_b.peers().iter().map(|p| &p.name).collect::<Vec<_>>());
 
-    // Start TCP Listeners
-    let id_a_clone = id_a.clone();
-    let state_a_tcp = state_a.clone();
-    tokio::spawn(async move {
-        run_tcp_listener(id_a_clone, port_a, state_a_tcp).await;
-    });
+    // A shares a 5MB file from its download directory, B pulls it
+    let source = node_a.state().sandbox.download_dir().join("peapod_sim_source.bin");
+    let data: Vec<u8> = (0..5 * 1024 * 1024).map(|i: u32| (i % 253) as u8).collect();
+    std::fs::create_dir_all(source.parent().unwrap()).unwrap();
+    std::fs::write(&source, &data).unwrap();
+    let manifest = node_a.share(&source).await.expect("share");
+    println!("Manifest: {:?}", manifest);
 
-    let id_b_clone = id_b.clone();
-    let state_b_tcp = state_b.clone();
-    tokio::spawn(async move {
-        run_tcp_listener(id_b_clone, port_b, state_b_tcp).await;
-    });
-
-    // Allow listeners to start
-    tokio::time::sleep(Duration::from_secs(1)).await;
-
-    // Start Discovery (Both on UDP 45678)
-    let id_a_clone = id_a.clone();
-    let state_a_clone = state_a.clone();
-    tokio::spawn(async move {
-        run_discovery(
-            id_a_clone,
-            "Node A".into(),
-            port_a,
-            45678,
-            state_a_clone,
-            emitter_a,
-        )
-        .await;
-    });
-
-    let id_b_clone = id_b.clone();
-    let state_b_clone = state_b.clone();
-    tokio::spawn(async move {
-        run_discovery(
-            id_b_clone,
-            "Node B".into(),
-            port_b,
-            45678,
-            state_b_clone,
-            emitter_b,
-        )
-        .await;
+    let mut events = node_b.subscribe();
+    let file_id = match node_b.download(&manifest.file_id, "peapod_sim_output.bin", true).await {
+        Ok(id) => id,
+        Err(e) => {
+            println!("Download failed to start: {}", e);
+            return;
+        }
+    };
+    let finished = tokio::time::timeout(Duration::from_secs(15), async {
+        while let Ok(event) = events.recv().await {
+            match event {
+                Event::TransferCompleted { file_id: id } if id == file_id => return Ok(()),
+                Event::TransferFailed { file_id: id, reason } if id == file_id => return Err(reason),
+                _ => {}
+            }
+        }
+        Err("event stream closed".to_string())
     });
+    match finished.await {
+        Ok(Ok(())) => {
+            let output = node_b.transfer(&file_id).map(|t| t.output_path).unwrap_or_default();
+            let identical = std::fs::read(&output).is_ok_and(|written| written == data);
+            println!("Transfer complete, identical: {}: {:?}", identical, node_b.state().scheduler.peer_stats());
+        }
+        Ok(Err(reason)) => println!("Transfer failed: {}", reason),
+        Err(_) => println!("Transfer timed out"),
+    }
 
-    // Run for 15 seconds then exit
-    tokio::time::sleep(Duration::from_secs(15)).await;
+    let (clean_a, clean_b) = tokio::join!(node_a.shutdown(), node_b.shutdown());
+    println!("Clean shutdown: {}", clean_a && clean_b);
     println!("Simulation finished.");
 }
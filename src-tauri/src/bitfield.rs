@@ -0,0 +1,112 @@
+use serde::{Deserialize, Serialize};
+
+/// One bit per chunk of a file, set for the chunks a peer holds. Chunk `i`
+/// is bit `7 - i % 8` of byte `i / 8`. On the wire it is a hex string, so a
+/// file of 4,096 chunks takes 1,024 characters.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+}
+
+impl Bitfield {
+    pub fn from_indices(indices: impl IntoIterator<Item = u64>) -> Self {
+        let mut bits = Self::default();
+        for index in indices {
+            bits.set(index);
+        }
+        bits
+    }
+
+    pub fn has(&self, index: u64) -> bool {
+        let (byte, mask) = Self::position(index);
+        self.bytes.get(byte).is_some_and(|b| b & mask != 0)
+    }
+
+    pub fn set(&mut self, index: u64) {
+        let (byte, mask) = Self::position(index);
+        if byte >= self.bytes.len() {
+            self.bytes.resize(byte + 1, 0);
+        }
+        self.bytes[byte] |= mask;
+    }
+
+    /// Whether every set bit is below `chunks`, and there are no more bytes
+    /// than that many chunks take. Checked before a peer's bitfield is kept.
+    pub fn fits(&self, chunks: u64) -> bool {
+        let len = chunks.div_ceil(8);
+        if self.bytes.len() as u64 > len {
+            return false;
+        }
+        // Bits past the last chunk in its byte must be clear too.
+        match self.bytes.last() {
+            Some(last) if self.bytes.len() as u64 == len && !chunks.is_multiple_of(8) => last & (0xff >> (chunks % 8)) == 0,
+            _ => true,
+        }
+    }
+
+    /// How many chunks are set.
+    pub fn count(&self) -> u64 {
+        self.bytes.iter().map(|b| b.count_ones() as u64).sum()
+    }
+
+    fn position(index: u64) -> (usize, u8) {
+        ((index / 8) as usize, 0x80 >> (index % 8))
+    }
+}
+
+impl From<Bitfield> for String {
+    fn from(bits: Bitfield) -> Self {
+        bits.bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl TryFrom<String> for Bitfield {
+    type Error = String;
+
+    fn try_from(hex: String) -> Result<Self, Self::Error> {
+        if !hex.len().is_multiple_of(2) {
+            return Err("bitfield has an odd number of hex digits".to_string());
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2).unwrap_or("?"), 16))
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("bitfield is not hex: {}", e))?;
+        Ok(Self { bytes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_round_trip() {
+        let bits = Bitfield::from_indices([0, 9, 15]);
+        let hex = String::from(bits.clone());
+        assert_eq!(hex, "8041");
+        assert_eq!(Bitfield::try_from(hex).unwrap(), bits);
+        assert!(bits.has(9) && !bits.has(8) && !bits.has(100));
+        assert_eq!(bits.count(), 3);
+    }
+
+    #[test]
+    fn rejects_bad_hex() {
+        assert!(Bitfield::try_from("abc".to_string()).is_err());
+        assert!(Bitfield::try_from("zz".to_string()).is_err());
+        assert_eq!(Bitfield::try_from(String::new()).unwrap(), Bitfield::default());
+    }
+
+    #[test]
+    fn fits_chunk_count() {
+        let bits = Bitfield::from_indices([0, 9]);
+        assert!(bits.fits(10));
+        assert!(bits.fits(16));
+        assert!(!bits.fits(9));
+        assert!(!bits.fits(8));
+        assert!(Bitfield::from_indices([7]).fits(8));
+        assert!(!Bitfield::try_from("0000".to_string()).unwrap().fits(8));
+        assert!(Bitfield::default().fits(0));
+    }
+}
@@ -0,0 +1,319 @@
+use reqwest::header::{HeaderMap, CACHE_CONTROL, ETAG};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+use crate::bitfield::Bitfield;
+use crate::chunk::MIN_CHUNK_SIZE;
+use crate::state::AppState;
+use crate::persist::{load_json, to_json, write_file};
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Keep ranges this node fetches from the internet and serve them to the
+    /// pod instead of fetching them again. Off by default.
+    pub enabled: bool,
+    /// Bytes kept before the least recently used ranges are dropped.
+    pub max_size: u64,
+    /// Defaults to `cache` in the data directory.
+    pub dir: Option<PathBuf>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self { enabled: false, max_size: 1024 * 1024 * 1024, dir: None }
+    }
+}
+
+/// How long a fetched range may be kept, going by the response headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Storable {
+    No,
+    /// For as long as the URL keeps this strong ETag. Downloads probe the URL
+    /// first and only ask for ranges under its current ETag.
+    WhileUnchanged,
+    /// Until this Unix time, from `s-maxage` or `max-age`, for URLs without an ETag.
+    Until(u64),
+}
+
+impl Storable {
+    /// `etag` is the ETag the range was asked for under.
+    pub fn from_headers(headers: &HeaderMap, etag: Option<&str>) -> Self {
+        let directives: Vec<String> = headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|d| d.trim().to_ascii_lowercase())
+            .collect();
+        let has = |name: &str| directives.iter().any(|d| d == name);
+        // The whole pod shares the cache, so nothing meant for one user goes in.
+        if has("no-store") || has("private") {
+            return Storable::No;
+        }
+        if etag.is_some() && strong_etag(headers).as_deref() == etag {
+            return Storable::WhileUnchanged;
+        }
+        let max_age = |prefix: &str| directives.iter().find_map(|d| d.strip_prefix(prefix)?.parse::<u64>().ok());
+        match max_age("s-maxage=").or_else(|| max_age("max-age=")) {
+            Some(secs) if secs > 0 && !has("no-cache") => Storable::Until(unix_now() + secs),
+            _ => Storable::No,
+        }
+    }
+}
+
+/// The response's ETag, unless it is a weak one, which doesn't promise
+/// byte-for-byte identical content and so can't tie ranges together.
+pub fn strong_etag(headers: &HeaderMap) -> Option<String> {
+    headers.get(ETAG)?.to_str().ok().filter(|e| !e.starts_with("W/")).map(str::to_string)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// One cached byte range of a URL, kept in a file named after its id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    url: String,
+    etag: Option<String>,
+    start: u64,
+    end: u64,
+    /// Unix time the range goes stale, for URLs without an ETag.
+    expires: Option<u64>,
+    /// When the range was last stored or served, on the cache's own clock.
+    last_used: u64,
+}
+
+impl Entry {
+    fn matches(&self, url: &str, etag: Option<&str>, now: u64) -> bool {
+        self.url == url && self.etag.as_deref() == etag && self.expires.is_none_or(|t| t > now)
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Inner {
+    entries: HashMap<u64, Entry>,
+    next_id: u64,
+    clock: u64,
+    #[serde(skip)]
+    size: u64,
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl Inner {
+    /// Pieces `(id, offset in the entry, length)` that together cover `[start, end)`
+    /// from fresh entries for the URL, or `None` if some byte isn't cached.
+    fn cover(&self, url: &str, etag: Option<&str>, start: u64, end: u64) -> Option<Vec<(u64, u64, u64)>> {
+        debug_assert!(start < end, "empty range {}..{}", start, end);
+        let now = unix_now();
+        let mut entries: Vec<(&u64, &Entry)> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| e.matches(url, etag, now) && e.end > start && e.start < end)
+            .collect();
+        entries.sort_by_key(|(_, e)| e.start);
+        let mut pieces = Vec::new();
+        let mut pos = start;
+        for (id, entry) in entries {
+            if pos >= end || entry.start > pos {
+                break;
+            }
+            if entry.end <= pos {
+                continue;
+            }
+            let len = entry.end.min(end) - pos;
+            pieces.push((*id, pos - entry.start, len));
+            pos += len;
+        }
+        (pos >= end).then_some(pieces)
+    }
+}
+
+/// Ranges of internet downloads kept on disk for the pod, keyed by URL and
+/// ETag and evicted least recently used first. A disabled cache holds nothing.
+#[derive(Default)]
+pub struct PodCache {
+    dir: Option<PathBuf>,
+    max_size: u64,
+    inner: Mutex<Inner>,
+}
+
+impl PodCache {
+    /// Opens the cache in `config.dir`, or `cache` under `data_dir`. Ranges
+    /// whose files went missing are forgotten.
+    pub fn open(config: &CacheConfig, data_dir: Option<&Path>) -> io::Result<Self> {
+        let dir = config.dir.clone().or_else(|| data_dir.map(|d| d.join("cache")));
+        let Some(dir) = dir.filter(|_| config.enabled) else { return Ok(Self::default()) };
+        fs::create_dir_all(&dir)?;
+        let mut inner: Inner = load_json(Some(&dir.join("index.json")))?;
+        inner.entries.retain(|id, e| fs::metadata(dir.join(id.to_string())).is_ok_and(|m| m.len() == e.end - e.start));
+        inner.size = inner.entries.values().map(|e| e.end - e.start).sum();
+        Ok(Self { dir: Some(dir), max_size: config.max_size, inner: Mutex::new(inner) })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    /// Bytes `[start, end)` of `url` under `etag`, if the cache holds all of
+    /// them. The range must not be empty.
+    pub async fn get(&self, url: &str, etag: Option<&str>, start: u64, end: u64) -> Option<Vec<u8>> {
+        debug_assert!(start < end, "empty range {}..{}", start, end);
+        let dir = self.dir.as_ref()?;
+        let pieces = {
+            let mut inner = self.inner.lock().unwrap();
+            let pieces = inner.cover(url, etag, start, end)?;
+            inner.clock += 1;
+            let clock = inner.clock;
+            for (id, _, _) in &pieces {
+                if let Some(entry) = inner.entries.get_mut(id) {
+                    entry.last_used = clock;
+                }
+            }
+            inner.dirty = true;
+            pieces
+        };
+        let mut data = Vec::with_capacity((end - start) as usize);
+        for (id, offset, len) in pieces {
+            match tokio::fs::read(dir.join(id.to_string())).await {
+                Ok(bytes) if bytes.len() as u64 >= offset + len => {
+                    data.extend_from_slice(&bytes[offset as usize..(offset + len) as usize]);
+                }
+                _ => {
+                    warn!(%url, id, "Cached range is unreadable, dropping it");
+                    self.remove(&[id]);
+                    return None;
+                }
+            }
+        }
+        Some(data)
+    }
+
+    /// Keeps a range fetched from the internet, if its headers allow it.
+    pub async fn put(&self, url: &str, etag: Option<&str>, start: u64, data: &[u8], storable: Storable) {
+        let Some(dir) = &self.dir else { return };
+        let end = start + data.len() as u64;
+        let expires = match storable {
+            Storable::No => return,
+            Storable::WhileUnchanged => None,
+            Storable::Until(time) => Some(time),
+        };
+        let id = {
+            let mut inner = self.inner.lock().unwrap();
+            if data.is_empty() || data.len() as u64 > self.max_size || inner.cover(url, etag, start, end).is_some() {
+                return;
+            }
+            inner.next_id += 1;
+            inner.next_id
+        };
+        if let Err(e) = tokio::fs::write(dir.join(id.to_string()), data).await {
+            warn!(error = %e, "Failed to cache range");
+            return;
+        }
+
+        let victims = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.clock += 1;
+            let entry = Entry { url: url.to_string(), etag: etag.map(str::to_string), start, end, expires, last_used: inner.clock };
+            inner.entries.insert(id, entry);
+            inner.size += end - start;
+            inner.dirty = true;
+            // Stale ranges go first, then the least recently used ones.
+            let now = unix_now();
+            let mut order: Vec<(bool, u64, u64)> =
+                inner.entries.iter().map(|(id, e)| (e.expires.is_none_or(|t| t > now), e.last_used, *id)).collect();
+            order.sort();
+            let mut size = inner.size;
+            let mut victims = Vec::new();
+            for (fresh, _, victim) in order {
+                if fresh && size <= self.max_size {
+                    break;
+                }
+                if let Some(entry) = inner.entries.get(&victim) {
+                    size -= entry.end - entry.start;
+                    victims.push(victim);
+                }
+            }
+            victims
+        };
+        self.remove(&victims);
+    }
+
+    fn remove(&self, ids: &[u64]) {
+        let Some(dir) = &self.dir else { return };
+        let mut inner = self.inner.lock().unwrap();
+        for id in ids {
+            if let Some(entry) = inner.entries.remove(id) {
+                inner.size -= entry.end - entry.start;
+                inner.dirty = true;
+                let _ = fs::remove_file(dir.join(id.to_string()));
+            }
+        }
+    }
+
+    /// Which chunks of a URL download laid out in `chunk_size` pieces the
+    /// cache could serve whole. Layouts with absurdly many chunks get none.
+    pub fn cached_chunks(&self, url: &str, etag: Option<&str>, total_size: u64, chunk_size: u64) -> Bitfield {
+        let mut chunks = Bitfield::default();
+        if self.dir.is_none() || chunk_size == 0 || (chunk_size < MIN_CHUNK_SIZE && chunk_size < total_size) {
+            return chunks;
+        }
+        let inner = self.inner.lock().unwrap();
+        let now = unix_now();
+        let mut ranges: Vec<(u64, u64)> =
+            inner.entries.values().filter(|e| e.matches(url, etag, now)).map(|e| (e.start, e.end)).collect();
+        ranges.sort();
+        // Merge touching ranges, then mark the chunks inside each run.
+        let mut runs: Vec<(u64, u64)> = Vec::new();
+        for (start, end) in ranges {
+            match runs.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => runs.push((start, end)),
+            }
+        }
+        for (start, end) in runs {
+            let mut index = start.div_ceil(chunk_size);
+            while index * chunk_size < total_size && ((index + 1) * chunk_size).min(total_size) <= end {
+                chunks.set(index);
+                index += 1;
+            }
+        }
+        chunks
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let Some(dir) = &self.dir else { return Ok(()) };
+        let json = {
+            let mut inner = self.inner.lock().unwrap();
+            if !inner.dirty {
+                return Ok(());
+            }
+            inner.dirty = false;
+            to_json(&*inner)?
+        };
+        write_file(&dir.join("index.json"), &json)
+    }
+}
+
+/// Saves the cache index every 30 seconds while the node runs, and once more on shutdown.
+pub async fn run_cache_flush(state: Arc<AppState>) {
+    let mut stopping = false;
+    while !stopping {
+        tokio::select! {
+            _ = tokio::time::sleep(FLUSH_INTERVAL) => {}
+            _ = state.shutdown.cancelled() => stopping = true,
+        }
+        if let Err(e) = state.cache.save() {
+            warn!(error = %e, "Failed to save cache index");
+        }
+    }
+}
@@ -1,11 +1,40 @@
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
-use std::sync::Mutex;
-use tokio::fs::{self, File};
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
+use std::io::{self, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
 use uuid::Uuid;
+use crate::collection::validate_manifest;
+use crate::error::{Error, Result};
+use crate::metrics::Metrics;
+use crate::output::{part_path, OutputFiles};
+use crate::protocol::{Collection, Manifest};
+use crate::sandbox::relative_path;
 
-pub const CHUNK_SIZE: u64 = 1024 * 1024; // 1MB
+pub const MIN_CHUNK_SIZE: u64 = 256 * 1024; // 256KB
+pub const DEFAULT_CHUNK_SIZE: u64 = 1024 * 1024; // 1MB
+pub const MAX_CHUNK_SIZE: u64 = 4 * 1024 * 1024; // 4MB
+/// Most chunks one transfer may have, which bounds what a manifest or a
+/// server's size can make us allocate.
+pub const MAX_CHUNKS: u64 = 1 << 20;
+/// Largest transfer that fits in `MAX_CHUNKS` chunks of the largest size (4TB).
+pub const MAX_TRANSFER_SIZE: u64 = MAX_CHUNKS * MAX_CHUNK_SIZE;
+
+/// Files are split into about this many chunks so even small ones spread over several peers.
+const TARGET_CHUNK_COUNT: u64 = 64;
+/// On fast links a chunk should take at least this long to transfer, so per-request overhead stays small.
+const MIN_CHUNK_SECS: f64 = 0.25;
+
+/// Picks a chunk size for a file of `total_size` bytes. `throughput` is the
+/// expected transfer rate in bytes/s, if known.
+pub fn choose_chunk_size(total_size: u64, throughput: Option<f64>) -> u64 {
+    let by_size = (total_size / TARGET_CHUNK_COUNT).max(1).next_power_of_two();
+    let by_speed = throughput.map(|t| ((t * MIN_CHUNK_SECS) as u64).max(1).next_power_of_two()).unwrap_or(0);
+    by_size.max(by_speed).clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE)
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ChunkStatus {
@@ -21,7 +50,8 @@ pub struct Chunk {
     pub start: u64,
     pub end: u64,
     pub status: ChunkStatus,
-    pub hash: Option<String>, 
+    /// Hex SHA-256 from the manifest, or `None` when there is nothing to check the chunk against.
+    pub hash: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -29,55 +59,376 @@ pub struct FileTransfer {
     pub file_id: String,
     pub file_name: String,
     pub total_size: u64,
+    pub chunk_size: u64,
     pub chunks: Vec<Chunk>,
+    /// For a collection, the directory its tree is saved in.
     pub output_path: String,
     pub source_path: Option<String>, 
+    /// Set for transfers fetched from the internet rather than from a peer's file.
+    pub url: Option<String>,
+    /// Strong ETag `url` had when the transfer started. Every range must come
+    /// from that version of it.
+    pub etag: Option<String>,
+    /// A paused download keeps its progress but requests no more chunks.
+    pub paused: bool,
+    pub collection: Option<Collection>,
+    /// The local files the transfer's bytes are read from or written to, in stream order.
+    segments: Vec<Segment>,
+    /// Open `.part` files of a download.
+    outputs: Arc<OutputFiles>,
+    /// Every chunk of a download is in and its files carry their final names.
+    finished: bool,
+}
+
+/// A local file holding bytes `[offset, offset + size)` of a transfer.
+#[derive(Debug, Clone)]
+struct Segment {
+    path: PathBuf,
+    offset: u64,
+    size: u64,
+}
+
+impl FileTransfer {
+    /// `root` is the file itself, or for a collection the directory it lives in.
+    fn new(file_id: String, file_name: String, total_size: u64, chunk_size: u64, root: &str, collection: Option<Collection>) -> Self {
+        let segments = match &collection {
+            Some(c) => {
+                let mut offset = 0;
+                c.files
+                    .iter()
+                    .map(|f| {
+                        // Paths from peers are checked before a transfer is created for them.
+                        let path = relative_path(&f.path).map(|r| Path::new(root).join(r)).unwrap_or_default();
+                        let segment = Segment { path, offset, size: f.size };
+                        offset += f.size;
+                        segment
+                    })
+                    .collect()
+            }
+            None => vec![Segment { path: PathBuf::from(root), offset: 0, size: total_size }],
+        };
+        Self {
+            file_id,
+            file_name,
+            total_size,
+            chunk_size,
+            chunks: layout_chunks(total_size, chunk_size, collection.as_ref()),
+            output_path: String::new(),
+            source_path: None,
+            url: None,
+            etag: None,
+            paused: false,
+            collection,
+            segments,
+            outputs: Arc::default(),
+            finished: false,
+        }
+    }
+
+    /// Where bytes `[start, start + len)` of the stream are on disk.
+    fn pieces(&self, start: u64, len: u64) -> Vec<Piece> {
+        let end = start + len;
+        let first = self.segments.partition_point(|s| s.offset + s.size <= start);
+        self.segments[first..]
+            .iter()
+            .take_while(|s| s.offset < end)
+            .filter(|s| s.size > 0)
+            .map(|s| {
+                let from = start.max(s.offset);
+                let to = end.min(s.offset + s.size);
+                Piece { path: s.path.clone(), file_size: s.size, offset: from - s.offset, len: to - from }
+            })
+            .collect()
+    }
+
+    /// The file a download's bytes for `path` can be read from right now.
+    fn current_path(&self, path: PathBuf) -> PathBuf {
+        if self.source_path.is_none() && !self.finished { part_path(&path) } else { path }
+    }
+
+    /// The files a download ends up as, leaving out empty ones, which no chunk writes.
+    fn output_files(&self) -> Vec<PathBuf> {
+        self.segments.iter().filter(|s| s.size > 0).map(|s| s.path.clone()).collect()
+    }
+
+    /// The empty files a download ends up as.
+    fn empty_files(&self) -> Vec<PathBuf> {
+        self.segments.iter().filter(|s| s.size == 0).map(|s| s.path.clone()).collect()
+    }
+}
+
+/// Bytes `[offset, offset + len)` of the file at `path`, which holds `file_size` bytes in all.
+struct Piece {
+    path: PathBuf,
+    file_size: u64,
+    offset: u64,
+    len: u64,
+}
+
+/// How many chunks `layout_chunks` makes of a transfer, without making them.
+pub fn chunk_count(total_size: u64, chunk_size: u64, collection: Option<&Collection>) -> u64 {
+    match collection {
+        Some(c) if c.aligned => c.files.iter().fold(0u64, |n, f| n.saturating_add(f.size.div_ceil(chunk_size))),
+        _ => total_size.div_ceil(chunk_size),
+    }
+}
+
+/// Splits a transfer into chunks of `chunk_size`. An aligned collection
+/// starts a new chunk at every file.
+fn layout_chunks(total_size: u64, chunk_size: u64, collection: Option<&Collection>) -> Vec<Chunk> {
+    let spans: Vec<(u64, u64)> = match collection {
+        Some(c) if c.aligned => {
+            let mut offset = 0;
+            c.files
+                .iter()
+                .map(|f| {
+                    offset += f.size;
+                    (offset - f.size, offset)
+                })
+                .collect()
+        }
+        _ => vec![(0, total_size)],
+    };
+    let mut chunks = Vec::new();
+    for (from, to) in spans {
+        let mut start = from;
+        while start < to {
+            let end = start + chunk_size.min(to - start);
+            chunks.push(Chunk { index: chunks.len() as u64, start, end, status: ChunkStatus::Pending, hash: None });
+            start = end;
+        }
+    }
+    chunks
+}
+
+/// Opens a file chunks are read from. A download renames its `.part` files
+/// as it completes, so a path located a moment ago may have just moved.
+pub async fn open_piece(path: &Path) -> io::Result<File> {
+    match File::open(path).await {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let moved = match path.to_str().and_then(|p| p.strip_suffix(".part")) {
+                Some(path) => PathBuf::from(path),
+                None => part_path(path),
+            };
+            // It may also move between the two attempts, and only ever one way.
+            match File::open(moved).await {
+                Err(_) => File::open(path).await,
+                opened => opened,
+            }
+        }
+        opened => opened,
+    }
+}
+
+/// Hex SHA-256 of a chunk's bytes, as listed in a manifest.
+pub fn hash_chunk(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// `hash_chunk` of the bytes in (file, offset in the file, length) pieces,
+/// read without buffering them all. Blocks.
+fn hash_pieces(pieces: &[(PathBuf, u64, u64)]) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    for (path, start, len) in pieces {
+        let mut file = std::fs::File::open(path)?;
+        file.seek(SeekFrom::Start(*start))?;
+        let mut left = *len;
+        while left > 0 {
+            let n = left.min(buf.len() as u64) as usize;
+            file.read_exact(&mut buf[..n])?;
+            hasher.update(&buf[..n]);
+            left -= n as u64;
+        }
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Reads (file, offset in the file, length) pieces into one buffer.
+pub async fn read_pieces(pieces: &[(PathBuf, u64, u64)]) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    for (path, start, len) in pieces {
+        let mut file = open_piece(path).await?;
+        file.seek(SeekFrom::Start(*start)).await?;
+        let filled = buf.len();
+        buf.resize(filled + *len as usize, 0);
+        file.read_exact(&mut buf[filled..]).await?;
+    }
+    Ok(buf)
+}
+
+/// Progress of a transfer, as reported by the control API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferInfo {
+    pub file_id: String,
+    pub file_name: String,
+    pub total_size: u64,
+    pub chunk_size: u64,
+    pub chunks: u64,
+    pub completed_chunks: u64,
+    pub completed_bytes: u64,
+    pub output_path: String,
+    /// Set when we are sharing a local file rather than downloading.
+    pub source_path: Option<String>,
+    pub url: Option<String>,
+    pub paused: bool,
+    /// Files in the transfer, more than one for a collection.
+    pub files: usize,
+}
+
+impl From<&FileTransfer> for TransferInfo {
+    fn from(t: &FileTransfer) -> Self {
+        let done = t.chunks.iter().filter(|c| matches!(c.status, ChunkStatus::Completed));
+        let (completed_chunks, completed_bytes) = done.fold((0, 0), |(n, bytes), c| (n + 1, bytes + c.end - c.start));
+        Self {
+            file_id: t.file_id.clone(),
+            file_name: t.file_name.clone(),
+            total_size: t.total_size,
+            chunk_size: t.chunk_size,
+            chunks: t.chunks.len() as u64,
+            completed_chunks,
+            completed_bytes,
+            output_path: t.output_path.clone(),
+            source_path: t.source_path.clone(),
+            url: t.url.clone(),
+            paused: t.paused,
+            files: t.collection.as_ref().map_or(1, |c| c.files.len()),
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct ChunkManager {
     transfers: Mutex<HashMap<String, FileTransfer>>,
+    /// Counts stored and rejected chunks.
+    metrics: Arc<Metrics>,
 }
 
 impl ChunkManager {
-    pub fn new() -> Self {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
         Self {
             transfers: Mutex::new(HashMap::new()),
+            metrics,
         }
     }
 
-    pub fn start_transfer(&self, file_name: String, total_size: u64, output_path: String) -> String {
+    pub fn start_transfer(&self, file_name: String, total_size: u64, chunk_size: u64, output_path: String) -> Result<String> {
         let file_id = Uuid::new_v4().to_string();
-        let chunks = self.calculate_chunks(total_size);
+        let mut transfer = FileTransfer::new(file_id.clone(), file_name, total_size, chunk_size, &output_path, None);
+        transfer.output_path = output_path;
+        self.add_download(transfer)?;
+        Ok(file_id)
+    }
 
-        let transfer = FileTransfer {
-            file_id: file_id.clone(),
-            file_name,
-            total_size,
-            chunks,
-            output_path,
-            source_path: None,
-        };
+    /// Creates a transfer whose chunks are byte ranges of `url`, as of `etag` if the server gave one.
+    pub fn start_url_transfer(&self, url: String, etag: Option<String>, total_size: u64, chunk_size: u64, output_path: String) -> Result<String> {
+        let file_id = Uuid::new_v4().to_string();
+        let file_name = url
+            .split(['?', '#'])
+            .next()
+            .and_then(|path| path.rsplit('/').next())
+            .filter(|name| !name.is_empty())
+            .unwrap_or("download")
+            .to_string();
+        let mut transfer = FileTransfer::new(file_id.clone(), file_name, total_size, chunk_size, &output_path, None);
+        transfer.output_path = output_path;
+        transfer.url = Some(url);
+        transfer.etag = etag;
+        self.add_download(transfer)?;
+        Ok(file_id)
+    }
 
+    /// Starts tracking a download, creating its empty files right away since
+    /// no chunk will. Refuses an id that is already shared or downloading.
+    fn add_download(&self, transfer: FileTransfer) -> Result<()> {
         let mut map = self.transfers.lock().unwrap();
-        map.insert(file_id.clone(), transfer);
-        file_id
+        if map.contains_key(&transfer.file_id) {
+            return Err(Error::Invalid(format!("already sharing or downloading {}", transfer.file_id)));
+        }
+        transfer.outputs.create_empty(&transfer.empty_files())?;
+        map.insert(transfer.file_id.clone(), transfer);
+        Ok(())
+    }
+
+    /// Forgets a transfer. A download in progress stops at its next step.
+    pub fn remove_transfer(&self, file_id: &str) -> bool {
+        self.transfers.lock().unwrap().remove(file_id).is_some()
+    }
+
+    /// Pauses or resumes a download. Returns false for an unknown transfer.
+    pub fn set_paused(&self, file_id: &str, paused: bool) -> bool {
+        let mut map = self.transfers.lock().unwrap();
+        map.get_mut(file_id).map(|t| t.paused = paused).is_some()
+    }
+
+    /// Creates the receiving side of a transfer from the sender's manifest,
+    /// refusing one that doesn't pass `validate_manifest`, whose hashes
+    /// don't match its chunks or whose file id is already shared or
+    /// downloaded here. For a collection, `output_path` is the
+    /// directory to recreate it in.
+    pub fn join_transfer(&self, manifest: Manifest, output_path: String) -> Result<()> {
+        validate_manifest(&manifest).map_err(Error::Invalid)?;
+        let file_id = manifest.file_id;
+        let mut transfer = FileTransfer::new(
+            file_id.clone(),
+            manifest.file_name,
+            manifest.total_size,
+            manifest.chunk_size,
+            &output_path,
+            manifest.collection,
+        );
+        if !manifest.chunk_hashes.is_empty() {
+            if manifest.chunk_hashes.len() != transfer.chunks.len() {
+                return Err(Error::Invalid(format!("{} hashes for {} chunks", manifest.chunk_hashes.len(), transfer.chunks.len())));
+            }
+            for (chunk, hash) in transfer.chunks.iter_mut().zip(manifest.chunk_hashes) {
+                chunk.hash = Some(hash);
+            }
+        }
+        transfer.output_path = output_path;
+        self.add_download(transfer)
+    }
+
+    pub async fn register_source_file(&self, path: String, total_size: u64, chunk_size: u64) -> Result<String> {
+        self.register_source(path, total_size, chunk_size, None).await
+    }
+
+    /// Shares the files of `collection`, found under the directory `root`.
+    pub async fn register_source_collection(&self, root: String, collection: Collection, chunk_size: u64) -> Result<String> {
+        let total_size = collection.files.iter().map(|f| f.size).sum();
+        self.register_source(root, total_size, chunk_size, Some(collection)).await
     }
 
-    pub fn register_source_file(&self, path: String, total_size: u64) -> String {
+    /// Hashes every chunk for the manifest, then starts serving the file.
+    async fn register_source(&self, path: String, total_size: u64, chunk_size: u64, collection: Option<Collection>) -> Result<String> {
+        if chunk_count(total_size, chunk_size, collection.as_ref()) > MAX_CHUNKS {
+            return Err(Error::Invalid(format!("{} is too large to share", path)));
+        }
         let file_id = Uuid::new_v4().to_string();
-        let chunks = self.calculate_chunks(total_size);
-        let transfer = FileTransfer {
-            file_id: file_id.clone(),
-            file_name: "source".into(),
-            total_size,
-            chunks,
-            output_path: "".into(),
-            source_path: Some(path),
-        };
+        let file_name = Path::new(&path)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "source".into());
+        let mut transfer = FileTransfer::new(file_id.clone(), file_name, total_size, chunk_size, &path, collection);
+        // We hold the whole file, so every chunk is complete from the start.
+        for chunk in &mut transfer.chunks {
+            chunk.status = ChunkStatus::Completed;
+        }
+        transfer.source_path = Some(path);
+        let chunks: Vec<Vec<(PathBuf, u64, u64)>> = transfer
+            .chunks
+            .iter()
+            .map(|c| transfer.pieces(c.start, c.end - c.start).into_iter().map(|p| (p.path, p.offset, p.len)).collect())
+            .collect();
+        let hashes = tokio::task::spawn_blocking(move || chunks.iter().map(|pieces| hash_pieces(pieces)).collect::<io::Result<Vec<_>>>())
+            .await
+            .map_err(io::Error::other)??;
+        for (chunk, hash) in transfer.chunks.iter_mut().zip(hashes) {
+            chunk.hash = Some(hash);
+        }
         let mut map = self.transfers.lock().unwrap();
         map.insert(file_id.clone(), transfer);
-        file_id
+        Ok(file_id)
     }
 
     pub fn get_active_transfers(&self) -> usize {
@@ -85,62 +436,309 @@ impl ChunkManager {
         map.len()
     }
 
-    fn calculate_chunks(&self, total_size: u64) -> Vec<Chunk> {
-        let mut chunks = Vec::new();
-        let mut start = 0;
-        let mut index = 0;
-        while start < total_size {
-            let mut end = start + CHUNK_SIZE;
-            if end > total_size { end = total_size; }
-            chunks.push(Chunk { index, start, end, status: ChunkStatus::Pending, hash: None });
-            start = end;
-            index += 1;
-        }
-        chunks
+    pub fn transfers(&self) -> Vec<TransferInfo> {
+        let map = self.transfers.lock().unwrap();
+        map.values().map(TransferInfo::from).collect()
+    }
+
+    pub fn transfer(&self, file_id: &str) -> Option<TransferInfo> {
+        let map = self.transfers.lock().unwrap();
+        map.get(file_id).map(TransferInfo::from)
+    }
+
+    /// What a receiver needs to lay out the same chunks as we do.
+    pub fn manifest(&self, file_id: &str) -> Option<Manifest> {
+        let map = self.transfers.lock().unwrap();
+        let t = map.get(file_id)?;
+        Some(Manifest {
+            file_id: t.file_id.clone(),
+            file_name: t.file_name.clone(),
+            total_size: t.total_size,
+            chunk_size: t.chunk_size,
+            collection: t.collection.clone(),
+            chunk_hashes: t.chunks.iter().map(|c| c.hash.clone()).collect::<Option<_>>().unwrap_or_default(),
+        })
+    }
+
+    pub fn chunk_count(&self, file_id: &str) -> Option<u64> {
+        let map = self.transfers.lock().unwrap();
+        map.get(file_id).map(|t| t.chunks.len() as u64)
+    }
+
+    pub fn chunk_size(&self, file_id: &str) -> Option<u64> {
+        let map = self.transfers.lock().unwrap();
+        map.get(file_id).map(|t| t.chunk_size)
+    }
+
+    pub fn url(&self, file_id: &str) -> Option<String> {
+        let map = self.transfers.lock().unwrap();
+        map.get(file_id).and_then(|t| t.url.clone())
+    }
+
+    pub fn etag(&self, file_id: &str) -> Option<String> {
+        let map = self.transfers.lock().unwrap();
+        map.get(file_id).and_then(|t| t.etag.clone())
+    }
+
+    /// Byte range `[start, end)` of a chunk within the file.
+    pub fn chunk_range(&self, file_id: &str, index: u64) -> Option<(u64, u64)> {
+        let map = self.transfers.lock().unwrap();
+        let c = map.get(file_id)?.chunks.get(index as usize)?;
+        Some((c.start, c.end))
+    }
+
+    pub fn is_chunk_completed(&self, file_id: &str, index: u64) -> bool {
+        let map = self.transfers.lock().unwrap();
+        map.get(file_id)
+            .and_then(|t| t.chunks.get(index as usize))
+            .is_some_and(|c| matches!(c.status, ChunkStatus::Completed))
+    }
+
+    /// Indices of the chunks not yet completed, or `None` for an unknown transfer.
+    pub fn pending_chunks(&self, file_id: &str) -> Option<Vec<u64>> {
+        let map = self.transfers.lock().unwrap();
+        let t = map.get(file_id)?;
+        Some(
+            t.chunks
+                .iter()
+                .filter(|c| !matches!(c.status, ChunkStatus::Completed))
+                .map(|c| c.index)
+                .collect(),
+        )
+    }
+
+    /// Indices of the chunks already completed, or `None` for an unknown transfer.
+    pub fn completed_chunks(&self, file_id: &str) -> Option<Vec<u64>> {
+        let map = self.transfers.lock().unwrap();
+        let t = map.get(file_id)?;
+        Some(
+            t.chunks
+                .iter()
+                .filter(|c| matches!(c.status, ChunkStatus::Completed))
+                .map(|c| c.index)
+                .collect(),
+        )
     }
 
     // READ (Serve)
-    pub async fn read_chunk(&self, file_id: &str, index: u64) -> Option<Vec<u8>> {
-        let (path, start, len) = {
+    /// Only completed chunks can be read.
+    pub async fn read_chunk(&self, file_id: &str, index: u64) -> Result<Vec<u8>> {
+        let pieces = self.locate_chunk(file_id, index)?;
+        Ok(read_pieces(&pieces).await?)
+    }
+
+    /// Where the bytes `read_chunk` would return are on disk, as (file,
+    /// offset in the file, length) pieces, without reading them.
+    pub fn locate_chunk(&self, file_id: &str, index: u64) -> Result<Vec<(PathBuf, u64, u64)>> {
+        let map = self.transfers.lock().unwrap();
+        let t = map.get(file_id).ok_or_else(|| Error::NotFound(format!("no file {}", file_id)))?;
+        let out_of_range = || Error::OutOfRange { file_id: file_id.to_string(), index };
+        let chunk = t.chunks.get(index as usize).ok_or_else(out_of_range)?;
+        if !matches!(chunk.status, ChunkStatus::Completed) {
+            return Err(Error::Unavailable(format!("chunk {} of {} is not downloaded yet", index, file_id)));
+        }
+        let pieces = t.pieces(chunk.start, chunk.end - chunk.start);
+        Ok(pieces.into_iter().map(|p| (t.current_path(p.path), p.offset, p.len)).collect())
+    }
+
+    /// Flushes every unfinished download's `.part` files to disk, e.g. before exiting.
+    pub async fn sync_outputs(&self) {
+        let downloads: Vec<(Arc<OutputFiles>, Vec<PathBuf>)> = {
             let map = self.transfers.lock().unwrap();
-            let t = map.get(file_id)?;
-            let chunk = t.chunks.get(index as usize)?;
-            let path = t.source_path.clone().or_else(|| Some(t.output_path.clone()))?;
-            (path, chunk.start, chunk.end - chunk.start)
+            map.values()
+                .filter(|t| t.source_path.is_none() && !t.finished)
+                .map(|t| (t.outputs.clone(), t.output_files()))
+                .collect()
         };
+        let _ = tokio::task::spawn_blocking(move || {
+            for (outputs, paths) in downloads {
+                outputs.sync(&paths);
+            }
+        })
+        .await;
+    }
 
-        if let Ok(mut file) = File::open(path).await {
-            if let Ok(_) = file.seek(SeekFrom::Start(start)).await {
-                let mut buf = vec![0u8; len as usize];
-                if let Ok(_) = file.read_exact(&mut buf).await {
-                    return Some(buf);
+    /// Whether the sender's manifest listed chunk hashes, so chunks are checked as they arrive.
+    pub fn is_verified(&self, file_id: &str) -> bool {
+        let map = self.transfers.lock().unwrap();
+        map.get(file_id).is_some_and(|t| t.chunks.iter().all(|c| c.hash.is_some()))
+    }
+
+    /// Hands `data` back if it matches the chunk's hash, or the chunk has none.
+    async fn check_hash(&self, file_id: &str, index: u64, data: Vec<u8>) -> Result<Vec<u8>> {
+        let expected = {
+            let map = self.transfers.lock().unwrap();
+            map.get(file_id).and_then(|t| t.chunks.get(index as usize)).and_then(|c| c.hash.clone())
+        };
+        let Some(expected) = expected else { return Ok(data) };
+        let (data, actual) = tokio::task::spawn_blocking(move || {
+            let actual = hash_chunk(&data);
+            (data, actual)
+        })
+        .await
+        .map_err(io::Error::other)?;
+        if actual != expected {
+            return Err(Error::Invalid(format!("chunk {} of {} doesn't match its hash", index, file_id)));
+        }
+        Ok(data)
+    }
+
+    // WRITE (Receive)
+    /// Writes a whole chunk and marks it completed. Data that isn't exactly
+    /// the chunk's length, or doesn't match the chunk's hash, is rejected.
+    /// The last chunk of a download also flushes its `.part` files and
+    /// gives them their final names.
+    pub async fn write_chunk(&self, file_id: &str, index: u64, data: Vec<u8>) -> Result<()> {
+        let written = match self.check_hash(file_id, index, data).await {
+            Ok(data) => self.write_at(file_id, index, data).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            self.metrics.chunks_failed.inc();
+            return Err(e);
+        }
+
+        // The files are renamed before the last chunk counts as completed, so
+        // a download is never seen as done while its files are still `.part`.
+        let finish = {
+            let mut map = self.transfers.lock().unwrap();
+            let Some(t) = map.get_mut(file_id) else { return Ok(()) };
+            if t.finished {
+                return Ok(());
+            }
+            let last = t.chunks.iter().all(|c| c.index == index || matches!(c.status, ChunkStatus::Completed));
+            if last {
+                t.finished = true;
+                Some((t.outputs.clone(), t.output_files()))
+            } else {
+                if let Some(c) = t.chunks.get_mut(index as usize) {
+                    c.status = ChunkStatus::Completed;
                 }
+                None
+            }
+        };
+        if let Some((outputs, paths)) = finish {
+            let finished = tokio::task::spawn_blocking(move || outputs.finish(&paths)).await.map_err(io::Error::other)?;
+            let mut map = self.transfers.lock().unwrap();
+            let Some(t) = map.get_mut(file_id) else { return Ok(()) };
+            if let Err(e) = finished {
+                t.finished = false;
+                self.metrics.chunks_failed.inc();
+                return Err(e.into());
+            }
+            if let Some(c) = t.chunks.get_mut(index as usize) {
+                c.status = ChunkStatus::Completed;
             }
         }
-        None
+        self.metrics.chunks_stored.inc();
+        Ok(())
     }
 
-    // WRITE (Receive)
-    pub async fn write_chunk(&self, file_id: &str, index: u64, data: Vec<u8>) {
-        let (path, start) = {
+    /// Writes a chunk's bytes to its files. Leaves the chunk status alone.
+    async fn write_at(&self, file_id: &str, index: u64, data: Vec<u8>) -> Result<()> {
+        let (outputs, pieces) = {
             let map = self.transfers.lock().unwrap();
-            if let Some(t) = map.get(file_id) {
-                if let Some(c) = t.chunks.get(index as usize) {
-                    (t.output_path.clone(), c.start)
-                } else { return; }
-            } else { return; }
+            let t = map.get(file_id).ok_or_else(|| Error::NotFound(format!("no transfer {}", file_id)))?;
+            let out_of_range = || Error::OutOfRange { file_id: file_id.to_string(), index };
+            let c = t.chunks.get(index as usize).ok_or_else(out_of_range)?;
+            if c.end - c.start != data.len() as u64 {
+                return Err(Error::Invalid(format!("{} bytes for a chunk of {}", data.len(), c.end - c.start)));
+            }
+            if t.source_path.is_some() {
+                return Err(Error::Forbidden(format!("{} is a shared file", file_id)));
+            }
+            // A late duplicate of a chunk that is already in.
+            if t.finished || matches!(c.status, ChunkStatus::Completed) {
+                return Ok(());
+            }
+            (t.outputs.clone(), t.pieces(c.start, c.end - c.start))
         };
 
-        if let Ok(mut file) = fs::OpenOptions::new().write(true).create(true).open(path).await {
-            if let Ok(_) = file.seek(SeekFrom::Start(start)).await {
-                let _ = file.write_all(&data).await;
-                let mut map = self.transfers.lock().unwrap();
-                if let Some(t) = map.get_mut(file_id) {
-                    if let Some(c) = t.chunks.get_mut(index as usize) {
-                        c.status = ChunkStatus::Completed;
-                    }
-                }
+        tokio::task::spawn_blocking(move || {
+            let mut data = &data[..];
+            for piece in pieces {
+                let (bytes, rest) = data.split_at(piece.len as usize);
+                outputs.write_at(&piece.path, piece.file_size, piece.offset, bytes)?;
+                data = rest;
             }
+            Ok::<_, io::Error>(())
+        })
+        .await
+        .map_err(io::Error::other)??;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory for one test's output.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("peapod_chunk_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn manifest(file_id: &str, data: &[u8]) -> Manifest {
+        Manifest {
+            file_id: file_id.into(),
+            file_name: "file.bin".into(),
+            total_size: data.len() as u64,
+            chunk_size: MIN_CHUNK_SIZE,
+            collection: None,
+            chunk_hashes: data.chunks(MIN_CHUNK_SIZE as usize).map(hash_chunk).collect(),
         }
     }
+
+    #[tokio::test]
+    async fn rejects_chunks_that_dont_match() {
+        let data: Vec<u8> = (0..MIN_CHUNK_SIZE + 10).map(|i| i as u8).collect();
+        let (first, second) = data.split_at(MIN_CHUNK_SIZE as usize);
+        let path = temp_dir("hash").join("file.bin");
+        let manager = ChunkManager::default();
+        manager.join_transfer(manifest("f", &data), path.to_string_lossy().into_owned()).unwrap();
+        assert!(manager.is_verified("f"));
+
+        let mut tampered = first.to_vec();
+        tampered[0] ^= 1;
+        assert!(manager.write_chunk("f", 0, tampered).await.is_err());
+        assert!(manager.write_chunk("f", 1, second[1..].to_vec()).await.is_err(), "short chunk");
+        assert_eq!(manager.transfer("f").unwrap().completed_chunks, 0);
+        assert_eq!(manager.metrics.chunks_failed.get(), 2);
+
+        manager.write_chunk("f", 0, first.to_vec()).await.unwrap();
+        manager.write_chunk("f", 1, second.to_vec()).await.unwrap();
+        assert_eq!(manager.transfer("f").unwrap().completed_chunks, 2);
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        assert!(!part_path(&path).exists());
+    }
+
+    #[test]
+    fn refuses_hashes_for_other_chunks_and_taken_ids() {
+        let path = temp_dir("join").join("file.bin").to_string_lossy().into_owned();
+        let manager = ChunkManager::default();
+        let mut wrong = manifest("f", &[1; 10]);
+        wrong.chunk_hashes.push(hash_chunk(b"extra"));
+        assert!(manager.join_transfer(wrong, path.clone()).is_err());
+        manager.join_transfer(manifest("f", &[1; 10]), path.clone()).unwrap();
+        assert!(manager.join_transfer(manifest("f", &[1; 10]), path).is_err());
+    }
+
+    #[test]
+    fn creates_empty_downloads() {
+        let path = temp_dir("empty").join("empty.bin");
+        let manager = ChunkManager::default();
+        manager.join_transfer(manifest("f", &[]), path.to_string_lossy().into_owned()).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn lays_out_chunks_without_overflow() {
+        let chunks = layout_chunks(u64::MAX, u64::MAX / 2, None);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks.last().unwrap().end, u64::MAX);
+        assert_eq!(chunk_count(u64::MAX, u64::MAX / 2, None), 3);
+    }
 }
@@ -0,0 +1,483 @@
+use clap::Subcommand;
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE, HOST};
+use reqwest::{Client, Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use peapod::chunk::TransferInfo;
+use peapod::config::ApiConfig;
+use peapod::offer::{OfferDirection, OfferInfo, OfferStatus};
+use peapod::protocol::Manifest;
+use peapod::webserver::{
+    AcceptOfferRequest, DownloadRequest, OfferRequest, ShareRequest, StatusResponse, TransferRequest, TransferResponse,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Download a URL through the swarm, or a file a peer is sharing (by file id)
+    Get {
+        /// http(s) URL or shared file id
+        source: String,
+        /// Where to save the file; relative paths are inside the daemon's download directory
+        #[arg(long, short)]
+        output: PathBuf,
+        /// Replace the file if it already exists
+        #[arg(long, short)]
+        force: bool,
+        /// Return as soon as the download has started
+        #[arg(long)]
+        no_wait: bool,
+    },
+    /// Offer a local file or directory to peers and print its file id
+    Share {
+        path: PathBuf,
+        /// For a directory, start a new chunk at every file instead of packing small files together
+        #[arg(long)]
+        align: bool,
+    },
+    /// Offer files or directories to a nearby device, sent once it accepts
+    Send {
+        /// Device id or name of a connected peer
+        peer: String,
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// Return as soon as the offer is sent
+        #[arg(long)]
+        no_wait: bool,
+    },
+    /// List files offered to and by peers
+    Offers,
+    /// Accept a file offer and download it
+    Accept {
+        id: String,
+        /// Directory to save into; relative paths are inside the daemon's download directory
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// Replace files that already exist
+        #[arg(long, short)]
+        force: bool,
+        /// Return as soon as the download has started
+        #[arg(long)]
+        no_wait: bool,
+    },
+    /// Decline a file offer
+    Decline { id: String },
+    /// List discovered peers
+    Peers,
+    /// List transfers and their progress
+    Transfers,
+    /// Stop a transfer
+    Cancel { id: String },
+    /// Show peers, transfers and data usage
+    Status {
+        /// Refresh every second until interrupted
+        #[arg(long, short)]
+        watch: bool,
+    },
+}
+
+/// Where the daemon's control API listens.
+enum Endpoint {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Talks to the control API of the daemon running on this machine.
+pub struct ApiClient {
+    http: Client,
+    endpoint: Endpoint,
+    token: Option<String>,
+}
+
+impl ApiClient {
+    pub fn new(config: &ApiConfig) -> Self {
+        #[cfg(unix)]
+        if let Some(path) = &config.socket {
+            return Self { http: Client::new(), endpoint: Endpoint::Unix(path.clone()), token: config.token.clone() };
+        }
+        // A daemon listening on every interface is reached through loopback.
+        let host = match config.bind {
+            IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ip => ip,
+        };
+        Self {
+            http: Client::new(),
+            endpoint: Endpoint::Tcp(format!("http://{}", SocketAddr::new(host, config.port))),
+            token: config.token.clone(),
+        }
+    }
+
+    /// Sends a request and returns the status and body of the reply.
+    async fn call(&self, method: Method, path: &str, body: Option<Vec<u8>>) -> Result<(StatusCode, Vec<u8>), String> {
+        match &self.endpoint {
+            Endpoint::Tcp(base) => {
+                let mut request = self.http.request(method, format!("{}{}", base, path));
+                if let Some(token) = &self.token {
+                    request = request.bearer_auth(token);
+                }
+                if let Some(body) = body {
+                    request = request.header(CONTENT_TYPE, "application/json").body(body);
+                }
+                let response = request.send().await.map_err(|e| self.unreachable(e))?;
+                let status = response.status();
+                let bytes = response.bytes().await.map_err(|e| self.unreachable(e))?;
+                Ok((status, bytes.to_vec()))
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(socket) => {
+                use axum::body::Body;
+                use hyper_util::rt::TokioIo;
+
+                let stream = tokio::net::UnixStream::connect(socket).await.map_err(|e| self.unreachable(e))?;
+                let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+                    .await
+                    .map_err(|e| self.unreachable(e))?;
+                tokio::spawn(conn);
+                let mut request = hyper::Request::builder().method(method).uri(path).header(HOST, "localhost");
+                if let Some(token) = &self.token {
+                    request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+                }
+                let request = match body {
+                    Some(body) => request.header(CONTENT_TYPE, "application/json").body(Body::from(body)),
+                    None => request.body(Body::empty()),
+                }
+                .map_err(|e| e.to_string())?;
+                let response = sender.send_request(request).await.map_err(|e| self.unreachable(e))?;
+                let status = response.status();
+                let bytes = axum::body::to_bytes(Body::new(response.into_body()), usize::MAX)
+                    .await
+                    .map_err(|e| self.unreachable(e))?;
+                Ok((status, bytes.to_vec()))
+            }
+        }
+    }
+
+    fn unreachable(&self, e: impl std::fmt::Display) -> String {
+        let location = match &self.endpoint {
+            Endpoint::Tcp(base) => base.clone(),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => format!("unix:{}", path.display()),
+        };
+        format!("Cannot reach the daemon at {} ({}). Is `peapod --daemon` running?", location, e)
+    }
+
+    /// Fails with the daemon's message unless the reply is a success.
+    async fn send<T: DeserializeOwned>(&self, method: Method, path: &str, body: Option<Vec<u8>>) -> Result<T, String> {
+        let (status, bytes) = self.call(method, path, body).await?;
+        if !status.is_success() {
+            return Err(failure(status, &bytes));
+        }
+        serde_json::from_slice(&bytes).map_err(|e| format!("Unexpected reply from the daemon: {}", e))
+    }
+
+    pub async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, String> {
+        self.send(Method::GET, path, None).await
+    }
+
+    pub async fn post<B: Serialize, T: DeserializeOwned>(&self, path: &str, body: &B) -> Result<T, String> {
+        let body = serde_json::to_vec(body).map_err(|e| e.to_string())?;
+        self.send(Method::POST, path, Some(body)).await
+    }
+
+    /// `None` if the transfer is gone (finished and removed, failed or cancelled).
+    pub async fn transfer(&self, id: &str) -> Result<Option<TransferInfo>, String> {
+        match self.get(&format!("/transfers/{}", id)).await {
+            Ok(info) => Ok(Some(info)),
+            Err(e) if e.starts_with(StatusCode::NOT_FOUND.as_str()) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn cancel(&self, id: &str) -> Result<(), String> {
+        self.no_content(Method::DELETE, &format!("/transfers/{}", id)).await
+    }
+
+    pub async fn decline(&self, id: &str) -> Result<(), String> {
+        self.no_content(Method::POST, &format!("/offers/{}/decline", id)).await
+    }
+
+    /// For endpoints that answer with an empty body.
+    async fn no_content(&self, method: Method, path: &str) -> Result<(), String> {
+        let (status, bytes) = self.call(method, path, None).await?;
+        if status.is_success() { Ok(()) } else { Err(failure(status, &bytes)) }
+    }
+}
+
+pub async fn run(command: Command, config: &ApiConfig, json: bool) -> Result<(), String> {
+    let api = ApiClient::new(config);
+    match command {
+        Command::Get { source, output, force, no_wait } => {
+            let output_path = output.to_string_lossy().into_owned();
+            let started: TransferResponse = if source.starts_with("http://") || source.starts_with("https://") {
+                api.post("/download", &DownloadRequest { url: source, output_path, overwrite: force }).await?
+            } else {
+                let request = TransferRequest { file_id: source, output_path, peers: Vec::new(), overwrite: force };
+                api.post("/transfer", &request).await?
+            };
+            if no_wait {
+                print(json, &started, || println!("Started {}", started.transfer_id));
+                return Ok(());
+            }
+            let info = wait_for(&api, &started.transfer_id, json).await?;
+            print(json, &info, || println!("Saved {} to {}", format_bytes(info.total_size), info.output_path));
+        }
+        Command::Share { path, align } => {
+            let manifest: Manifest = api.post("/share", &ShareRequest { path: absolute(&path)?, align_files: align }).await?;
+            print(json, &manifest, || {
+                let size = format_bytes(manifest.total_size);
+                match &manifest.collection {
+                    Some(collection) => {
+                        println!("Sharing {} ({} files, {})", manifest.file_name, collection.files.len(), size);
+                        println!("Peers can fetch it with: peapod get {} -o <directory>", manifest.file_id);
+                    }
+                    None => {
+                        println!("Sharing {} ({})", manifest.file_name, size);
+                        println!("Peers can fetch it with: peapod get {} -o <file>", manifest.file_id);
+                    }
+                }
+            });
+        }
+        Command::Send { peer, paths, no_wait } => {
+            let status: StatusResponse = api.get("/status").await?;
+            let peer = status
+                .peers
+                .iter()
+                .find(|p| p.is_connected && (p.id == peer || p.name == peer))
+                .map(|p| p.id.clone())
+                .ok_or_else(|| format!("No connected peer called {}", peer))?;
+            let paths = paths.iter().map(|p| absolute(p)).collect::<Result<_, _>>()?;
+            let offer: OfferInfo = api.post("/offers", &OfferRequest { peer, paths }).await?;
+            if no_wait {
+                print(json, &offer, || println!("Offered {} ({})", describe_offer(&offer), offer.offer_id));
+                return Ok(());
+            }
+            if !json {
+                eprintln!("Waiting for the peer to accept...");
+            }
+            let offer = wait_for_offer(&api, &offer.offer_id, json).await?;
+            print(json, &offer, || println!("Sent {}", describe_offer(&offer)));
+        }
+        Command::Offers => {
+            let offers: Vec<OfferInfo> = api.get("/offers").await?;
+            print(json, &offers, || print_offers(&offers));
+        }
+        Command::Accept { id, output, force, no_wait } => {
+            let output_dir = output.map(|o| o.to_string_lossy().into_owned()).unwrap_or_default();
+            let request = AcceptOfferRequest { output_dir, overwrite: force };
+            let offer: OfferInfo = api.post(&format!("/offers/{}/accept", id), &request).await?;
+            if no_wait {
+                print(json, &offer, || println!("Accepted {}", describe_offer(&offer)));
+                return Ok(());
+            }
+            let offer = wait_for_offer(&api, &id, json).await?;
+            print(json, &offer, || println!("Received {}", describe_offer(&offer)));
+        }
+        Command::Decline { id } => {
+            api.decline(&id).await?;
+            if !json {
+                println!("Declined {}", id);
+            }
+        }
+        Command::Peers => {
+            let status: StatusResponse = api.get("/status").await?;
+            print(json, &status.peers, || print_peers(&status));
+        }
+        Command::Transfers => {
+            let transfers: Vec<TransferInfo> = api.get("/transfers").await?;
+            print(json, &transfers, || print_transfers(&transfers));
+        }
+        Command::Cancel { id } => {
+            api.cancel(&id).await?;
+            if !json {
+                println!("Cancelled {}", id);
+            }
+        }
+        Command::Status { watch } => loop {
+            let status: StatusResponse = api.get("/status").await?;
+            let transfers: Vec<TransferInfo> = api.get("/transfers").await?;
+            if json {
+                let combined = serde_json::json!({ "status": status, "transfers": transfers });
+                println!("{}", combined);
+            } else {
+                if watch {
+                    // Clear the screen and move the cursor home.
+                    print!("\x1b[2J\x1b[H");
+                }
+                print_peers(&status);
+                println!();
+                print_transfers(&transfers);
+                println!();
+                println!(
+                    "Today: {} up, {} down, {} proxied{}",
+                    format_bytes(status.usage.today.uploaded),
+                    format_bytes(status.usage.today.downloaded),
+                    format_bytes(status.usage.today.proxied),
+                    if status.usage.upload_quota_reached { " (upload quota reached)" } else { "" },
+                );
+            }
+            if !watch {
+                break;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        },
+    }
+    Ok(())
+}
+
+/// Polls a transfer until every chunk is in, showing progress unless `json` is set.
+async fn wait_for(api: &ApiClient, id: &str, json: bool) -> Result<TransferInfo, String> {
+    loop {
+        let Some(info) = api.transfer(id).await? else {
+            return Err(format!("Transfer {} failed or was cancelled", id));
+        };
+        if !json {
+            eprint!("\r{}  {}", progress_bar(info.completed_bytes, info.total_size), info.file_name);
+        }
+        if info.completed_chunks == info.chunks {
+            if !json {
+                eprintln!();
+            }
+            return Ok(info);
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Polls an offer until it is completed, showing progress unless `json` is set.
+async fn wait_for_offer(api: &ApiClient, id: &str, json: bool) -> Result<OfferInfo, String> {
+    loop {
+        let offer: OfferInfo = api.get(&format!("/offers/{}", id)).await?;
+        match offer.status {
+            OfferStatus::Pending => {}
+            OfferStatus::Declined => return Err("The offer was declined".to_string()),
+            OfferStatus::Failed => return Err(format!("Offer {} failed or was cancelled", id)),
+            OfferStatus::Accepted | OfferStatus::Completed => {
+                if !json {
+                    eprint!("\r{}  {}", progress_bar(offer.completed_bytes, offer.total_size), describe_offer(&offer));
+                }
+            }
+        }
+        if offer.status == OfferStatus::Completed {
+            if !json {
+                eprintln!();
+            }
+            return Ok(offer);
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// The daemon's error message, prefixed with the status (e.g. "404 Not Found").
+fn failure(status: StatusCode, body: &[u8]) -> String {
+    let message = String::from_utf8_lossy(body);
+    if message.is_empty() { status.to_string() } else { format!("{}: {}", status, message) }
+}
+
+/// The daemon has its own working directory, so paths are sent absolute.
+fn absolute(path: &Path) -> Result<String, String> {
+    let path = std::path::absolute(path).map_err(|e| format!("Invalid path {}: {}", path.display(), e))?;
+    Ok(path.to_string_lossy().into_owned())
+}
+
+fn print<T: Serialize>(json: bool, value: &T, human: impl FnOnce()) {
+    if json {
+        println!("{}", serde_json::to_string_pretty(value).unwrap_or_default());
+    } else {
+        human();
+    }
+}
+
+fn print_peers(status: &StatusResponse) {
+    if status.peers.is_empty() {
+        println!("No peers discovered yet");
+        return;
+    }
+    println!("{:<20} {:<38} {:<10} {:>12} {:>8}", "NAME", "ID", "STATE", "THROUGHPUT", "RTT");
+    for peer in &status.peers {
+        let state = match (peer.is_connected, peer.out_of_quota) {
+            (_, true) => "no quota",
+            (true, false) => "connected",
+            (false, false) => "seen",
+        };
+        println!(
+            "{:<20} {:<38} {:<10} {:>10}/s {:>6.0}ms",
+            peer.name,
+            peer.id,
+            state,
+            format_bytes(peer.throughput as u64),
+            peer.rtt * 1000.0
+        );
+    }
+}
+
+fn print_transfers(transfers: &[TransferInfo]) {
+    if transfers.is_empty() {
+        println!("No transfers");
+        return;
+    }
+    println!("{:<38} {:<8} {:<28} NAME", "ID", "KIND", "PROGRESS");
+    for t in transfers {
+        let kind = if t.source_path.is_some() { "share" } else if t.url.is_some() { "url" } else { "swarm" };
+        let progress = progress_bar(t.completed_bytes, t.total_size);
+        let name = if t.files > 1 { format!("{} ({} files)", t.file_name, t.files) } else { t.file_name.clone() };
+        println!("{:<38} {:<8} {:<28} {}", t.file_id, kind, progress, name);
+    }
+}
+
+fn print_offers(offers: &[OfferInfo]) {
+    if offers.is_empty() {
+        println!("No offers");
+        return;
+    }
+    println!("{:<38} {:<4} {:<38} {:<10} {:<28} FILES", "ID", "DIR", "PEER", "STATUS", "PROGRESS");
+    for offer in offers {
+        let direction = match offer.direction {
+            OfferDirection::Incoming => "in",
+            OfferDirection::Outgoing => "out",
+        };
+        let status = match offer.status {
+            OfferStatus::Pending => "pending",
+            OfferStatus::Accepted => "accepted",
+            OfferStatus::Declined => "declined",
+            OfferStatus::Completed => "completed",
+            OfferStatus::Failed => "failed",
+        };
+        let progress = progress_bar(offer.completed_bytes, offer.total_size);
+        println!("{:<38} {:<4} {:<38} {:<10} {:<28} {}", offer.offer_id, direction, offer.peer, status, progress, describe_offer(offer));
+    }
+}
+
+/// "photos (12 files, 48.0 MB)" or "report.pdf (1.2 MB)".
+fn describe_offer(offer: &OfferInfo) -> String {
+    let size = format_bytes(offer.total_size);
+    let name = match offer.files.as_slice() {
+        [file] => file.path.clone(),
+        files => format!("{} and {} more", files.first().map_or("", |f| f.path.as_str()), files.len() - 1),
+    };
+    let count: usize = offer.files.iter().map(|f| f.manifest.collection.as_ref().map_or(1, |c| c.files.len())).sum();
+    if count == 1 { format!("{} ({})", name, size) } else { format!("{} ({} files, {})", name, count, size) }
+}
+
+fn progress_bar(completed: u64, total: u64) -> String {
+    const WIDTH: u64 = 20;
+    let filled = (completed * WIDTH).checked_div(total).unwrap_or(WIDTH) as usize;
+    let percent = (completed * 100).checked_div(total).unwrap_or(100);
+    format!("[{}{}] {:>3}%", "#".repeat(filled), " ".repeat(WIDTH as usize - filled), percent)
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} B", bytes) } else { format!("{:.1} {}", value, UNITS[unit]) }
+}
@@ -0,0 +1,228 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+use crate::chunk::{chunk_count, MAX_CHUNKS, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+use crate::protocol::{Collection, CollectionFile, Manifest};
+use crate::sandbox::{relative_path, Sandbox};
+
+/// Most files and empty directories one collection may list, which keeps
+/// its manifest well inside a frame.
+pub const MAX_COLLECTION_ENTRIES: usize = 100_000;
+
+/// Lists the files and empty directories under `root`, sorted by path.
+/// Symlinks are skipped, and so are names a peer could not recreate.
+pub fn scan_dir(root: &Path, aligned: bool) -> io::Result<Collection> {
+    let mut collection = Collection { files: Vec::new(), empty_dirs: Vec::new(), aligned };
+    scan(root, "", &mut collection)?;
+    Ok(collection)
+}
+
+/// Returns whether `dir` had anything worth listing in it.
+fn scan(dir: &Path, prefix: &str, collection: &mut Collection) -> io::Result<bool> {
+    let mut children: Vec<_> = fs::read_dir(dir)?.collect::<io::Result<_>>()?;
+    children.sort_by_key(|c| c.file_name());
+    let mut listed = false;
+    for child in children {
+        let name = child.file_name();
+        let Some(name) = name.to_str().filter(|n| relative_path(n).is_some()) else {
+            warn!(path = %child.path().display(), "Skipping a file name peers can't use");
+            continue;
+        };
+        let relative = if prefix.is_empty() { name.to_string() } else { format!("{}/{}", prefix, name) };
+        let kind = child.file_type()?;
+        if kind.is_dir() {
+            if !scan(&child.path(), &relative, collection)? {
+                collection.empty_dirs.push(relative);
+            }
+            listed = true;
+        } else if kind.is_file() {
+            collection.files.push(CollectionFile { path: relative, size: child.metadata()?.len() });
+            listed = true;
+        }
+        if collection.files.len() + collection.empty_dirs.len() > MAX_COLLECTION_ENTRIES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} holds more than {} entries", dir.display(), MAX_COLLECTION_ENTRIES),
+            ));
+        }
+    }
+    Ok(listed)
+}
+
+/// Checks a manifest from a peer before anything is laid out for it: a sane
+/// chunk size, no more than `MAX_CHUNKS` chunks and, for a collection, a
+/// tree that can only be recreated inside its root, with sizes that add up.
+pub fn validate_manifest(manifest: &Manifest) -> Result<(), String> {
+    if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&manifest.chunk_size) {
+        return Err(format!("chunk size {} is out of range", manifest.chunk_size));
+    }
+    let Some(collection) = &manifest.collection else { return check_chunk_count(manifest) };
+    if collection.files.len() + collection.empty_dirs.len() > MAX_COLLECTION_ENTRIES {
+        return Err(format!("more than {} entries", MAX_COLLECTION_ENTRIES));
+    }
+    let mut files = HashSet::new();
+    let mut entries = HashSet::new();
+    for (index, path) in collection.files.iter().map(|f| &f.path).chain(&collection.empty_dirs).enumerate() {
+        let relative = relative_path(path).ok_or_else(|| format!("unsafe path {}", path))?;
+        if index < collection.files.len() {
+            files.insert(relative.clone());
+        }
+        if !entries.insert(relative) {
+            return Err(format!("{} is listed twice", path));
+        }
+    }
+    // A file can't also be a directory that something else is in.
+    for entry in &entries {
+        if let Some(file) = entry.ancestors().skip(1).find(|a| files.contains(*a)) {
+            return Err(format!("{} is inside the file {}", entry.display(), file.display()));
+        }
+    }
+    let total = collection.files.iter().try_fold(0u64, |total, f| total.checked_add(f.size));
+    if total != Some(manifest.total_size) {
+        return Err(format!("file sizes don't add up to {} bytes", manifest.total_size));
+    }
+    check_chunk_count(manifest)
+}
+
+fn check_chunk_count(manifest: &Manifest) -> Result<(), String> {
+    let chunks = chunk_count(manifest.total_size, manifest.chunk_size, manifest.collection.as_ref());
+    if chunks > MAX_CHUNKS {
+        return Err(format!("{} chunks is more than {}", chunks, MAX_CHUNKS));
+    }
+    Ok(())
+}
+
+/// Recreates a collection's directories under `root`, which the sandbox has
+/// already approved, and checks every file target the way a single download
+/// is checked.
+pub fn prepare_tree(sandbox: &Sandbox, root: &Path, collection: &Collection, overwrite: bool) -> Result<(), String> {
+    let root = root.canonicalize().map_err(|e| format!("{}: {}", root.display(), e))?;
+    let relative = |path: &str| relative_path(path).ok_or_else(|| format!("unsafe path {}", path));
+    for dir in &collection.empty_dirs {
+        create_dirs(&root, &relative(dir)?)?;
+    }
+    for file in &collection.files {
+        let relative = relative(&file.path)?;
+        let (Some(parent), Some(name)) = (relative.parent(), relative.file_name()) else {
+            return Err(format!("unsafe path {}", file.path));
+        };
+        let target = create_dirs(&root, parent)?.join(name);
+        sandbox.output_path(&target, overwrite)?;
+    }
+    Ok(())
+}
+
+/// Creates `relative` under `root` one directory at a time, refusing to go
+/// through a symlink or a file, and checks the result is still under `root`.
+pub fn create_dirs(root: &Path, relative: &Path) -> Result<PathBuf, String> {
+    let mut dir = root.to_path_buf();
+    for part in relative.components() {
+        dir.push(part);
+        match fs::symlink_metadata(&dir) {
+            Ok(meta) if meta.is_dir() => {}
+            Ok(_) => return Err(format!("{} is not a directory", dir.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                fs::create_dir(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+            }
+            Err(e) => return Err(format!("{}: {}", dir.display(), e)),
+        }
+    }
+    let real = dir.canonicalize().map_err(|e| format!("{}: {}", dir.display(), e))?;
+    if !real.starts_with(root) {
+        return Err(format!("{} is outside {}", dir.display(), root.display()));
+    }
+    Ok(real)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(files: &[(&str, u64)], empty_dirs: &[&str]) -> Manifest {
+        let files: Vec<_> = files.iter().map(|&(path, size)| CollectionFile { path: path.into(), size }).collect();
+        Manifest {
+            file_id: "id".into(),
+            file_name: "tree".into(),
+            total_size: files.iter().map(|f| f.size).sum(),
+            chunk_size: MIN_CHUNK_SIZE,
+            collection: Some(Collection { files, empty_dirs: empty_dirs.iter().map(|d| d.to_string()).collect(), aligned: false }),
+            chunk_hashes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn accepts_a_plain_tree() {
+        assert!(validate_manifest(&manifest(&[("a/b.txt", 3), ("c.txt", 0)], &["d/e"])).is_ok());
+    }
+
+    #[test]
+    fn rejects_unsafe_paths() {
+        for path in ["../x", "a/../b", "/etc/passwd", "a//b", "."] {
+            assert!(validate_manifest(&manifest(&[(path, 1)], &[])).is_err(), "{:?}", path);
+        }
+        assert!(validate_manifest(&manifest(&[], &["a/.."])).is_err());
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        assert!(validate_manifest(&manifest(&[("a", 1), ("a", 2)], &[])).is_err());
+        assert!(validate_manifest(&manifest(&[("a", 1)], &["a"])).is_err());
+    }
+
+    #[test]
+    fn rejects_a_file_as_parent() {
+        assert!(validate_manifest(&manifest(&[("a", 1), ("a/b", 1)], &[])).is_err());
+        assert!(validate_manifest(&manifest(&[("a", 1)], &["a/b/c"])).is_err());
+    }
+
+    #[test]
+    fn rejects_sizes_that_dont_add_up() {
+        let mut bad = manifest(&[("a", 1), ("b", 2)], &[]);
+        bad.total_size = 4;
+        assert!(validate_manifest(&bad).is_err());
+        let mut overflow = manifest(&[("a", 1), ("b", 1)], &[]);
+        overflow.collection.as_mut().unwrap().files[0].size = u64::MAX;
+        overflow.total_size = 0;
+        assert!(validate_manifest(&overflow).is_err());
+    }
+
+    #[test]
+    fn rejects_too_many_chunks() {
+        let mut huge = manifest(&[], &[]);
+        huge.collection = None;
+        huge.total_size = u64::MAX;
+        assert!(validate_manifest(&huge).is_err());
+        huge.total_size = MAX_CHUNKS * MIN_CHUNK_SIZE;
+        assert!(validate_manifest(&huge).is_ok());
+        huge.total_size += 1;
+        assert!(validate_manifest(&huge).is_err());
+    }
+
+    #[test]
+    fn rejects_chunk_sizes_out_of_range() {
+        for chunk_size in [0, MIN_CHUNK_SIZE - 1, MAX_CHUNK_SIZE + 1] {
+            let mut bad = manifest(&[("a", 1)], &[]);
+            bad.chunk_size = chunk_size;
+            assert!(validate_manifest(&bad).is_err(), "{}", chunk_size);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn prepare_tree_refuses_symlinked_dirs() {
+        let base = std::env::temp_dir().join(format!("peapod_tree_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let (root, outside) = (base.join("root"), base.join("outside"));
+        fs::create_dir_all(&root).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+        let sandbox = Sandbox::new(root.clone(), Vec::new(), true);
+        let tree = manifest(&[("link/new/a.txt", 0)], &[]).collection.unwrap();
+        assert!(prepare_tree(&sandbox, &root, &tree, false).is_err());
+        let tree = manifest(&[], &["link/new"]).collection.unwrap();
+        assert!(prepare_tree(&sandbox, &root, &tree, false).is_err());
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,231 @@
+//! Commands the GUI can invoke. They reuse the control API's handlers, so
+//! the GUI and headless clients get the same behaviour and sandbox checks.
+
+use peapod::chunk::TransferInfo;
+use peapod::config::Config;
+use peapod::devices::DeviceLists;
+use peapod::events::Event;
+use peapod::fetch::is_fetchable;
+use peapod::ledger::Balance;
+use peapod::offer::OfferInfo;
+use peapod::protocol::Manifest;
+use peapod::ratelimit::Limits;
+use peapod::state::AppState;
+use peapod::webserver::{
+    self, AcceptOfferRequest, DownloadRequest, OfferRequest, PeerInfo, ShareRequest, TransferRequest, TransferResponse,
+};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+type AppStateArg<'a> = tauri::State<'a, Arc<AppState>>;
+
+/// Location of the config file the GUI reads and writes.
+pub struct ConfigPath(pub Option<PathBuf>);
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    NotFound,
+    /// Outside the sandbox, or an existing file without `overwrite`.
+    Forbidden,
+    Invalid,
+    /// No peers to ask, or the URL's server failed.
+    Unavailable,
+    Io,
+}
+
+/// What a failed command returns to the GUI, as `{ kind, message }`.
+#[derive(Debug, Serialize)]
+pub struct CommandError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl CommandError {
+    fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self { kind, message: message.into() }
+    }
+}
+
+impl From<peapod::Error> for CommandError {
+    fn from(e: peapod::Error) -> Self {
+        let kind = match e {
+            peapod::Error::NotFound(_) => ErrorKind::NotFound,
+            peapod::Error::Forbidden(_) => ErrorKind::Forbidden,
+            peapod::Error::Invalid(_) | peapod::Error::OutOfRange { .. } => ErrorKind::Invalid,
+            peapod::Error::Unavailable(_) | peapod::Error::Upstream(_) | peapod::Error::Remote { .. } => ErrorKind::Unavailable,
+            peapod::Error::Io(_) | peapod::Error::Bind { .. } => ErrorKind::Io,
+        };
+        Self::new(kind, e.to_string())
+    }
+}
+
+impl From<std::io::Error> for CommandError {
+    fn from(e: std::io::Error) -> Self {
+        Self::new(ErrorKind::Io, e.to_string())
+    }
+}
+
+type CommandResult<T> = Result<T, CommandError>;
+
+#[tauri::command]
+pub fn list_peers(state: AppStateArg<'_>) -> Vec<PeerInfo> {
+    webserver::peer_list(&state)
+}
+
+#[tauri::command]
+pub fn list_transfers(state: AppStateArg<'_>) -> Vec<TransferInfo> {
+    state.chunk_manager.transfers()
+}
+
+/// Downloads `source`, an http(s) URL or a file id shared by a peer, to
+/// `output_path`. Relative paths are placed in the download directory.
+#[tauri::command]
+pub async fn start_download(
+    source: String,
+    output_path: String,
+    overwrite: bool,
+    state: AppStateArg<'_>,
+) -> CommandResult<TransferResponse> {
+    let state = state.inner().clone();
+    let started = if is_fetchable(&source) {
+        webserver::download(state, DownloadRequest { url: source, output_path, overwrite }).await
+    } else {
+        let request = TransferRequest { file_id: source, output_path, peers: Vec::new(), overwrite };
+        webserver::transfer(state, request).await
+    };
+    Ok(started?)
+}
+
+#[tauri::command]
+pub async fn share_file(path: String, state: AppStateArg<'_>) -> CommandResult<Manifest> {
+    Ok(webserver::share(&state, ShareRequest { path, ..Default::default() }).await?)
+}
+
+/// Offers local files and directories to a connected peer.
+#[tauri::command]
+pub async fn send_offer(peer: String, paths: Vec<String>, state: AppStateArg<'_>) -> CommandResult<OfferInfo> {
+    Ok(webserver::send_offer(&state, OfferRequest { peer, paths }).await?)
+}
+
+#[tauri::command]
+pub fn list_offers(state: AppStateArg<'_>) -> Vec<OfferInfo> {
+    state.offers.list()
+}
+
+/// Accepts an incoming offer. An empty `output_dir` saves it in the download directory.
+#[tauri::command]
+pub async fn accept_offer(
+    offer_id: String,
+    output_dir: String,
+    overwrite: bool,
+    state: AppStateArg<'_>,
+) -> CommandResult<OfferInfo> {
+    let request = AcceptOfferRequest { output_dir, overwrite };
+    Ok(webserver::accept_offer(state.inner().clone(), &offer_id, request).await?)
+}
+
+#[tauri::command]
+pub fn decline_offer(offer_id: String, state: AppStateArg<'_>) -> CommandResult<()> {
+    Ok(webserver::decline_offer(&state, &offer_id)?)
+}
+
+#[tauri::command]
+pub fn pause_transfer(file_id: String, state: AppStateArg<'_>) -> CommandResult<()> {
+    set_paused(&state, file_id, true)
+}
+
+#[tauri::command]
+pub fn resume_transfer(file_id: String, state: AppStateArg<'_>) -> CommandResult<()> {
+    set_paused(&state, file_id, false)
+}
+
+fn set_paused(state: &AppState, file_id: String, paused: bool) -> CommandResult<()> {
+    let transfer = state
+        .chunk_manager
+        .transfer(&file_id)
+        .ok_or_else(|| CommandError::new(ErrorKind::NotFound, "No such transfer"))?;
+    if transfer.source_path.is_some() {
+        return Err(CommandError::new(ErrorKind::Invalid, "Shared files can't be paused"));
+    }
+    state.chunk_manager.set_paused(&file_id, paused);
+    let event = if paused { Event::TransferPaused { file_id } } else { Event::TransferResumed { file_id } };
+    state.events.publish(event);
+    Ok(())
+}
+
+/// Stops a download or a share. A partial download is left on disk.
+#[tauri::command]
+pub fn cancel_transfer(file_id: String, state: AppStateArg<'_>) -> CommandResult<()> {
+    if state.chunk_manager.remove_transfer(&file_id) {
+        Ok(())
+    } else {
+        Err(CommandError::new(ErrorKind::NotFound, "No such transfer"))
+    }
+}
+
+#[tauri::command]
+pub fn list_devices(state: AppStateArg<'_>) -> DeviceLists {
+    state.devices.lists()
+}
+
+/// Pairs with a discovered device, remembering its current name.
+#[tauri::command]
+pub fn pair_device(device_id: String, state: AppStateArg<'_>) -> CommandResult<()> {
+    let name = state.peers.lock().unwrap().get(&device_id).map(|b| b.name.clone());
+    let name = name.ok_or_else(|| CommandError::new(ErrorKind::NotFound, "Device not discovered"))?;
+    Ok(state.devices.pair(&device_id, &name)?)
+}
+
+#[tauri::command]
+pub fn unpair_device(device_id: String, state: AppStateArg<'_>) -> CommandResult<()> {
+    Ok(state.devices.unpair(&device_id)?)
+}
+
+/// Blocks a device. A live connection to it is dropped within a few seconds.
+#[tauri::command]
+pub fn block_device(device_id: String, state: AppStateArg<'_>) -> CommandResult<()> {
+    Ok(state.devices.block(&device_id)?)
+}
+
+#[tauri::command]
+pub fn unblock_device(device_id: String, state: AppStateArg<'_>) -> CommandResult<()> {
+    Ok(state.devices.unblock(&device_id)?)
+}
+
+/// Bytes given to and received from each peer, keyed by device id.
+#[tauri::command]
+pub fn get_balances(state: AppStateArg<'_>) -> HashMap<String, Balance> {
+    state.ledger.balances()
+}
+
+#[tauri::command]
+pub fn get_limits(state: AppStateArg<'_>) -> Limits {
+    state.rate_limiter.limits()
+}
+
+/// Applies new bandwidth caps immediately (not persisted; use `save_config` for that).
+#[tauri::command]
+pub fn set_limits(limits: Limits, state: AppStateArg<'_>) -> Limits {
+    state.rate_limiter.set_limits(limits);
+    state.rate_limiter.limits()
+}
+
+#[tauri::command]
+pub fn get_config(path: tauri::State<'_, ConfigPath>) -> CommandResult<Config> {
+    match &path.0 {
+        Some(p) => Ok(Config::load(p)?),
+        None => Ok(Config::default()),
+    }
+}
+
+/// Writes the config file. Changes take effect on the next start.
+#[tauri::command]
+pub fn save_config(config: Config, path: tauri::State<'_, ConfigPath>) -> CommandResult<()> {
+    match &path.0 {
+        Some(p) => Ok(config.save(p)?),
+        None => Err(CommandError::new(ErrorKind::Unavailable, "No config directory available")),
+    }
+}
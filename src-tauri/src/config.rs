@@ -0,0 +1,201 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use crate::cache::CacheConfig;
+use crate::devices::DevicesConfig;
+use crate::ledger::FairnessConfig;
+use crate::logging::LogConfig;
+use crate::proxy::ProxyConfig;
+use crate::ratelimit::Limits;
+use crate::sandbox::{FilesConfig, Sandbox};
+use crate::usage::Quota;
+use tracing::warn;
+use uuid::Uuid;
+
+pub const DEFAULT_DISCOVERY_PORT: u16 = 45678;
+pub const DEFAULT_TRANSPORT_PORT: u16 = 45679;
+pub const DEFAULT_API_PORT: u16 = 8080;
+pub const DEFAULT_BEACON_INTERVAL_SECS: u64 = 3;
+
+/// Node configuration, loaded from `config.toml` and then overridden by
+/// environment variables and CLI flags (see `main.rs`).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub node: NodeConfig,
+    pub discovery: DiscoveryConfig,
+    pub transport: TransportConfig,
+    pub api: ApiConfig,
+    /// Initial bandwidth caps; can be changed at runtime through the API.
+    pub limits: Limits,
+    pub quota: Quota,
+    pub fairness: FairnessConfig,
+    pub proxy: ProxyConfig,
+    pub cache: CacheConfig,
+    pub files: FilesConfig,
+    pub devices: DevicesConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NodeConfig {
+    /// Name advertised in beacons. Falls back to a per-mode default when unset.
+    pub name: Option<String>,
+    /// Where usage and other state is kept. Defaults to `<data dir>/peapod`.
+    pub data_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    pub port: u16,
+    /// Local interface used to join the multicast group (0.0.0.0 = any).
+    pub interface: Ipv4Addr,
+    /// Seconds between beacons, at least 1.
+    pub beacon_interval_secs: u64,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_DISCOVERY_PORT,
+            interface: Ipv4Addr::UNSPECIFIED,
+            beacon_interval_secs: DEFAULT_BEACON_INTERVAL_SECS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransportConfig {
+    pub port: u16,
+    pub bind: IpAddr,
+    /// Send chunks to peers that support it as raw bytes straight from the
+    /// file (with `sendfile` on Linux) instead of inside a JSON frame.
+    pub raw_payloads: bool,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_TRANSPORT_PORT,
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            raw_payloads: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApiConfig {
+    pub port: u16,
+    /// Loopback by default. Any other address requires `token`.
+    pub bind: IpAddr,
+    /// Serve on this Unix socket (mode 0600) instead of TCP.
+    pub socket: Option<PathBuf>,
+    /// Bearer token required on every request when set.
+    pub token: Option<String>,
+    /// Browser origins allowed to call the API.
+    pub cors_origins: Vec<String>,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_API_PORT,
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            socket: None,
+            token: None,
+            cors_origins: default_cors_origins(),
+        }
+    }
+}
+
+/// The GUI's origins. Debug builds also allow the Vite dev server.
+fn default_cors_origins() -> Vec<String> {
+    let mut origins = vec!["tauri://localhost".to_string(), "http://tauri.localhost".to_string()];
+    if cfg!(debug_assertions) {
+        origins.push("http://localhost:1420".to_string());
+    }
+    origins
+}
+
+impl Config {
+    /// `<config dir>/peapod/config.toml`, e.g. `~/.config/peapod/config.toml` on Linux.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("peapod").join("config.toml"))
+    }
+
+    /// Reads the config at `path`. A missing file yields the defaults.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let config: Self = match fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        config.validate().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(config)
+    }
+
+    /// Checks settings the node can't run with. Done on load and save, and
+    /// again when a node starts, since flags and code can change a config.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.discovery.beacon_interval_secs == 0 {
+            return Err("discovery.beacon_interval_secs must be at least 1".into());
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        self.validate().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let text = toml::to_string_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, text)
+    }
+
+    pub fn data_dir(&self) -> Option<PathBuf> {
+        self.node.data_dir.clone().or_else(|| dirs::data_dir().map(|dir| dir.join("peapod")))
+    }
+
+    pub fn download_dir(&self) -> PathBuf {
+        self.files
+            .download_dir
+            .clone()
+            .or_else(|| dirs::download_dir().map(|dir| dir.join("PeaPod")))
+            .or_else(|| self.data_dir().map(|dir| dir.join("downloads")))
+            .unwrap_or_else(|| std::env::temp_dir().join("peapod"))
+    }
+
+    pub fn sandbox(&self) -> Sandbox {
+        Sandbox::new(self.download_dir(), self.files.share_roots.clone(), self.files.allow_any_path)
+    }
+
+    /// This node's identity. Generated on first start and kept in the data
+    /// directory, so peers' ledgers recognise us across restarts.
+    pub fn device_id(&self) -> String {
+        let Some(path) = self.data_dir().map(|dir| dir.join("device_id")) else {
+            return Uuid::new_v4().to_string();
+        };
+        if let Ok(id) = fs::read_to_string(&path) {
+            let id = id.trim();
+            if !id.is_empty() {
+                return id.to_string();
+            }
+        }
+        let id = Uuid::new_v4().to_string();
+        let saved = path.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| fs::write(&path, &id));
+        if let Err(e) = saved {
+            warn!(path = %path.display(), error = %e, "Failed to save device id");
+        }
+        id
+    }
+
+    pub fn device_name(&self) -> &str {
+        self.node.name.as_deref().unwrap_or("PeaPod")
+    }
+}
@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use crate::persist::{load_json, to_json, write_file};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DevicesConfig {
+    /// Only connect to devices whose id has been paired. Ids are whatever a
+    /// device says in its handshake, so this keeps strangers' devices out of
+    /// the way; it is not access control.
+    pub paired_ids_only: bool,
+}
+
+/// Devices the user has paired with or blocked, keyed by device id.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceLists {
+    /// Device id to the name it had when paired.
+    pub paired: BTreeMap<String, String>,
+    pub blocked: BTreeSet<String>,
+}
+
+/// Which peers we talk to, persisted as JSON. Blocked devices are never
+/// connected to; with `paired_ids_only`, neither is anyone who isn't paired.
+/// Devices are known only by the id they announce, which anyone can copy,
+/// so none of this keeps out a device that means harm.
+#[derive(Default)]
+pub struct Devices {
+    lists: Mutex<DeviceLists>,
+    path: Option<PathBuf>,
+    paired_ids_only: bool,
+}
+
+impl Devices {
+    pub fn load(path: Option<PathBuf>, config: &DevicesConfig) -> io::Result<Self> {
+        let lists = load_json(path.as_deref())?;
+        Ok(Self {
+            lists: Mutex::new(lists),
+            path,
+            paired_ids_only: config.paired_ids_only,
+        })
+    }
+
+    pub fn lists(&self) -> DeviceLists {
+        self.lists.lock().unwrap().clone()
+    }
+
+    /// Whether we may connect to, and accept connections from, `device_id`.
+    pub fn allows(&self, device_id: &str) -> bool {
+        let lists = self.lists.lock().unwrap();
+        !lists.blocked.contains(device_id) && (!self.paired_ids_only || lists.paired.contains_key(device_id))
+    }
+
+    pub fn is_paired(&self, device_id: &str) -> bool {
+        self.lists.lock().unwrap().paired.contains_key(device_id)
+    }
+
+    pub fn is_blocked(&self, device_id: &str) -> bool {
+        self.lists.lock().unwrap().blocked.contains(device_id)
+    }
+
+    /// Pairing a device also unblocks it.
+    pub fn pair(&self, device_id: &str, name: &str) -> io::Result<()> {
+        self.update(|lists| {
+            lists.blocked.remove(device_id);
+            lists.paired.insert(device_id.to_string(), name.to_string());
+        })
+    }
+
+    pub fn unpair(&self, device_id: &str) -> io::Result<()> {
+        self.update(|lists| {
+            lists.paired.remove(device_id);
+        })
+    }
+
+    /// Blocking a device also unpairs it.
+    pub fn block(&self, device_id: &str) -> io::Result<()> {
+        self.update(|lists| {
+            lists.paired.remove(device_id);
+            lists.blocked.insert(device_id.to_string());
+        })
+    }
+
+    pub fn unblock(&self, device_id: &str) -> io::Result<()> {
+        self.update(|lists| {
+            lists.blocked.remove(device_id);
+        })
+    }
+
+    /// Changes are rare, so they are written out straight away.
+    fn update(&self, change: impl FnOnce(&mut DeviceLists)) -> io::Result<()> {
+        let json = {
+            let mut lists = self.lists.lock().unwrap();
+            change(&mut lists);
+            to_json(&*lists)?
+        };
+        let Some(path) = &self.path else { return Ok(()) };
+        write_file(path, &json)
+    }
+}
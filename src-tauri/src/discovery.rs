@@ -1,80 +1,152 @@
+use std::collections::HashMap;
 use std::net::{Ipv4Addr, SocketAddr};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use socket2::{Domain, Protocol, Socket, Type};
 use tokio::net::UdpSocket;
+use tracing::{debug, info, warn, Instrument};
+use crate::config::{Config, DiscoveryConfig};
+use crate::error::{Error, Result};
+use crate::events::Event;
+use crate::fetch::LOCAL_PEER;
 use crate::protocol::Beacon;
-use crate::state::{AppState, PeerEmitter};
+use crate::state::AppState;
 use crate::transport::connect_to_peer;
 
 pub const MULTICAST_ADDR: &str = "239.255.60.60";
+/// A peer is considered gone after this many of our beacon intervals without hearing from it.
+const MISSED_BEACONS: u32 = 3;
+const MIN_PEER_TIMEOUT: Duration = Duration::from_secs(10);
 
-pub async fn run_discovery<E: PeerEmitter>(
-    my_id: String,
-    my_name: String,
-    my_port: u16,
-    discovery_port: u16,
-    state: Arc<AppState>,
-    emitter: E,
-) {
-    println!("[{}] Starting discovery on multicast:{}", my_id, discovery_port);
-    let listener = match create_multicast_socket(discovery_port) {
-        Ok(s) => s,
-        Err(_) => return,
+/// The multicast listener and the socket beacons are sent from.
+pub struct DiscoverySockets {
+    listener: UdpSocket,
+    sender: UdpSocket,
+}
+
+pub async fn bind_discovery(config: &DiscoveryConfig) -> Result<DiscoverySockets> {
+    let bind = async {
+        let listener = UdpSocket::from_std(create_multicast_socket(config.port, config.interface)?.into())?;
+        let sender = UdpSocket::from_std(create_sender_socket(config.interface)?.into())?;
+        Ok::<_, std::io::Error>(DiscoverySockets { listener, sender })
     };
-    let listener = UdpSocket::from_std(listener.into()).unwrap();
-    let sender = UdpSocket::bind("0.0.0.0:0").await.unwrap();
-    sender.set_broadcast(true).unwrap();
+    bind.await.map_err(|source| Error::Bind {
+        service: "discovery",
+        addr: format!("{}:{} via {}", MULTICAST_ADDR, config.port, config.interface),
+        source,
+    })
+}
 
-    let beacon = Beacon { device_id: my_id.clone(), name: my_name.clone(), port: my_port };
-    let beacon_json = serde_json::to_string(&beacon).unwrap();
+pub async fn run_discovery(sockets: DiscoverySockets, my_id: String, config: Arc<Config>, state: Arc<AppState>) {
+    let DiscoverySockets { listener, sender } = sockets;
+    let discovery_port = config.discovery.port;
+    info!(group = MULTICAST_ADDR, port = discovery_port, "Discovery running");
+
+    let mut beacon = Beacon {
+        device_id: my_id.clone(),
+        name: config.device_name().to_string(),
+        port: config.transport.port,
+        out_of_quota: false,
+        leaving: false,
+    };
     let target_addr: SocketAddr = format!("{}:{}", MULTICAST_ADDR, discovery_port).parse().unwrap();
 
-    let sender_msg = beacon_json.clone();
     let sender_clone = Arc::new(sender);
-    tokio::spawn(async move {
+    let sender_state = state.clone();
+    let beacon_interval = Duration::from_secs(config.discovery.beacon_interval_secs);
+    state.tasks.spawn(async move {
         loop {
-            let _ = sender_clone.send_to(sender_msg.as_bytes(), target_addr).await;
-            tokio::time::sleep(Duration::from_secs(3)).await;
+            beacon.out_of_quota = sender_state.usage.upload_quota_reached();
+            let beacon_json = serde_json::to_string(&beacon).unwrap();
+            match sender_clone.send_to(beacon_json.as_bytes(), target_addr).await {
+                Ok(_) => sender_state.metrics.beacons_sent.inc(),
+                Err(e) => warn!(error = %e, "Failed to send beacon"),
+            }
+            if beacon.leaving { break; }
+            tokio::select! {
+                _ = tokio::time::sleep(beacon_interval) => {}
+                // One last beacon so peers drop us now rather than after the timeout.
+                _ = sender_state.shutdown.cancelled() => beacon.leaving = true,
+            }
         }
-    });
+    }.in_current_span());
 
+    let peer_timeout = (beacon_interval * MISSED_BEACONS).max(MIN_PEER_TIMEOUT);
+    let mut last_seen: HashMap<String, Instant> = HashMap::new();
+    let mut expiry = tokio::time::interval(beacon_interval);
     let mut buf = [0; 1024];
     loop {
-        if let Ok((len, addr)) = listener.recv_from(&mut buf).await {
-            if let Ok(peer_beacon) = serde_json::from_slice::<Beacon>(&buf[..len]) {
-                if peer_beacon.device_id != my_id {
-                    let mut peers = state.peers.lock().unwrap();
-                    let mut should_connect = false;
+        let received = tokio::select! {
+            received = listener.recv_from(&mut buf) => received,
+            _ = state.shutdown.cancelled() => break,
+            _ = expiry.tick() => {
+                last_seen.retain(|device_id, seen| {
+                    if seen.elapsed() < peer_timeout { return true; }
+                    info!(device = %device_id, "Peer lost");
+                    state.peers.lock().unwrap().remove(device_id);
+                    state.events.publish(Event::PeerLost { device_id: device_id.clone() });
+                    false
+                });
+                continue;
+            }
+        };
+        let (len, addr) = match received {
+            Ok(received) => received,
+            Err(e) => {
+                warn!(error = %e, "Failed to receive beacon");
+                continue;
+            }
+        };
+        let peer_beacon = match serde_json::from_slice::<Beacon>(&buf[..len]) {
+            Ok(beacon) => beacon,
+            Err(e) => {
+                debug!(%addr, error = %e, "Ignoring malformed beacon");
+                continue;
+            }
+        };
+        // Our own beacons come back to us over multicast; `LOCAL_PEER` is
+        // reserved for our own internet fetches.
+        if peer_beacon.device_id == my_id || peer_beacon.device_id == LOCAL_PEER {
+            continue;
+        }
+        state.metrics.beacons_received.inc();
+        if peer_beacon.leaving {
+            last_seen.remove(&peer_beacon.device_id);
+            if state.peers.lock().unwrap().remove(&peer_beacon.device_id).is_some() {
+                info!(device = %peer_beacon.device_id, name = %peer_beacon.name, "Peer left");
+                state.events.publish(Event::PeerLost { device_id: peer_beacon.device_id });
+            }
+        } else {
+            last_seen.insert(peer_beacon.device_id.clone(), Instant::now());
+            let mut peers = state.peers.lock().unwrap();
 
-                    if !peers.contains_key(&peer_beacon.device_id) {
-                        println!("[{}] Discovered: {} ({:?})", my_id, peer_beacon.name, addr);
-                        should_connect = true;
-                        emitter.emit(peer_beacon.clone());
-                    }
-                    peers.insert(peer_beacon.device_id.clone(), peer_beacon.clone());
-                    
-                    if should_connect {
-                        let mut conns = state.active_connections.lock().unwrap();
-                        if !conns.contains_key(&peer_beacon.device_id) {
-                            conns.insert(peer_beacon.device_id.clone(), true);
-                            let peer_id = peer_beacon.device_id.clone();
-                            let peer_ip = addr.ip().to_string(); 
-                            let peer_port = peer_beacon.port;
-                            let my_id_clone = my_id.clone();
-                            let state_clone = state.clone();
-                            tokio::spawn(async move {
-                                connect_to_peer(peer_id, peer_ip, peer_port, my_id_clone, state_clone).await;
-                            });
-                        }
-                    }
+            if !peers.contains_key(&peer_beacon.device_id) {
+                info!(device = %peer_beacon.device_id, name = %peer_beacon.name, %addr, "Peer discovered");
+                state.events.publish(Event::PeerDiscovered { peer: peer_beacon.clone() });
+            }
+            peers.insert(peer_beacon.device_id.clone(), peer_beacon.clone());
+
+            // Blocked (or, with paired_ids_only, unpaired) devices stay listed so they
+            // can be paired, but we don't connect. Anyone else is (re)connected.
+            if state.devices.allows(&peer_beacon.device_id) {
+                let mut conns = state.active_connections.lock().unwrap();
+                if !conns.contains_key(&peer_beacon.device_id) {
+                    conns.insert(peer_beacon.device_id.clone(), true);
+                    let peer_id = peer_beacon.device_id.clone();
+                    let peer_ip = addr.ip().to_string(); 
+                    let peer_port = peer_beacon.port;
+                    let my_id_clone = my_id.clone();
+                    let state_clone = state.clone();
+                    state.tasks.spawn(async move {
+                        connect_to_peer(peer_id, peer_ip, peer_port, my_id_clone, state_clone).await;
+                    }.in_current_span());
                 }
             }
         }
     }
 }
 
-fn create_multicast_socket(port: u16) -> std::io::Result<Socket> {
+fn create_multicast_socket(port: u16, interface: Ipv4Addr) -> std::io::Result<Socket> {
     let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
     socket.set_reuse_address(true)?;
     #[cfg(not(target_os = "windows"))]
@@ -82,8 +154,19 @@ fn create_multicast_socket(port: u16) -> std::io::Result<Socket> {
     let addr: SocketAddr = format!("0.0.0.0:{}", port).parse().unwrap();
     socket.bind(&addr.into())?;
     let multi_addr: Ipv4Addr = MULTICAST_ADDR.parse().unwrap();
-    let interface = Ipv4Addr::new(0, 0, 0, 0); 
     socket.join_multicast_v4(&multi_addr, &interface)?;
     socket.set_nonblocking(true)?;
     Ok(socket)
 }
+
+/// Sends beacons out of `interface`, so they reach the network it joined
+/// the group on rather than whichever one the routing table picks.
+fn create_sender_socket(interface: Ipv4Addr) -> std::io::Result<Socket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_broadcast(true)?;
+    socket.set_multicast_if_v4(&interface)?;
+    let addr: SocketAddr = "0.0.0.0:0".parse().unwrap();
+    socket.bind(&addr.into())?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
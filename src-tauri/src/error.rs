@@ -0,0 +1,72 @@
+use std::fmt;
+use std::io;
+use crate::protocol::ErrorCode;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Everything that can go wrong in the library, roughly by who is at fault,
+/// so the API, the GUI and peers can each report it their own way.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// A listening socket could not be set up, e.g. because the port is taken.
+    Bind { service: &'static str, addr: String, source: io::Error },
+    /// No transfer, shared file or device with this id, or no peer has it.
+    NotFound(String),
+    /// A chunk index or byte range outside the file.
+    OutOfRange { file_id: String, index: u64 },
+    /// Outside the sandbox, an existing file without `overwrite`, or a peer we won't serve.
+    Forbidden(String),
+    Invalid(String),
+    /// No peer to ask, a chunk we don't have yet, or the node is shutting down.
+    Unavailable(String),
+    /// The server behind a URL failed.
+    Upstream(String),
+    /// A peer answered with an `Error` message.
+    Remote { code: ErrorCode, message: String },
+}
+
+impl Error {
+    /// How the error is reported to a peer that asked for something.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::NotFound(_) => ErrorCode::UnknownFile,
+            Error::OutOfRange { .. } => ErrorCode::OutOfRange,
+            Error::Forbidden(_) => ErrorCode::NotPermitted,
+            Error::Unavailable(_) | Error::Upstream(_) => ErrorCode::Unavailable,
+            Error::Remote { code, .. } => *code,
+            Error::Io(_) | Error::Bind { .. } | Error::Invalid(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => e.fmt(f),
+            Error::Bind { service, addr, source } => write!(f, "could not listen for {} on {}: {}", service, addr, source),
+            Error::OutOfRange { file_id, index } => write!(f, "chunk {} is outside file {}", index, file_id),
+            Error::NotFound(message)
+            | Error::Forbidden(message)
+            | Error::Invalid(message)
+            | Error::Unavailable(message)
+            | Error::Upstream(message) => f.write_str(message),
+            Error::Remote { code, message } => write!(f, "peer refused ({:?}): {}", code, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) | Error::Bind { source: e, .. } => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,79 @@
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+use crate::chunk::TransferInfo;
+use crate::offer::OfferInfo;
+use crate::protocol::Beacon;
+use crate::state::AppState;
+
+/// Events buffered per subscriber before slow ones start missing some.
+const CAPACITY: usize = 1024;
+
+/// Something that happened in the node, for UIs and dashboards.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    PeerDiscovered { peer: Beacon },
+    /// No beacon from the peer for a while.
+    PeerLost { device_id: String },
+    PeerConnected { device_id: String },
+    PeerDisconnected { device_id: String },
+    TransferCreated { transfer: TransferInfo },
+    TransferProgress { file_id: String, completed_bytes: u64, total_size: u64 },
+    TransferPaused { file_id: String },
+    TransferResumed { file_id: String },
+    TransferCompleted { file_id: String },
+    TransferFailed { file_id: String, reason: String },
+    /// A chunk arrived whole and was written to disk. `verified` says it
+    /// matched the hash in the sender's manifest; URL downloads have none.
+    ChunkStored { file_id: String, index: u64, peer: String, verified: bool },
+    ChunkFailed { file_id: String, index: u64, peer: String, reason: String },
+    /// A peer wants to send us files and is waiting for an answer.
+    OfferReceived { offer: OfferInfo },
+    OfferAccepted { offer_id: String },
+    OfferDeclined { offer_id: String },
+    /// Bytes of an accepted offer sent or received so far.
+    OfferProgress { offer_id: String, completed_bytes: u64, total_size: u64 },
+    OfferCompleted { offer_id: String },
+    OfferFailed { offer_id: String, reason: String },
+}
+
+/// Fan-out of `Event`s to any number of subscribers. Publishing never blocks.
+pub struct EventBus {
+    tx: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self { tx: broadcast::channel(CAPACITY).0 }
+    }
+}
+
+impl EventBus {
+    pub fn publish(&self, event: Event) {
+        // Nobody listening is fine.
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+}
+
+/// Receives every event, e.g. to forward it to the GUI or print it.
+pub trait EventSink: Send + Sync + 'static {
+    fn emit(&self, event: &Event);
+}
+
+/// Feeds all events from the bus into `sink` while the node runs.
+pub async fn run_event_sink<S: EventSink>(state: Arc<AppState>, sink: S) {
+    let mut events = state.events.subscribe();
+    loop {
+        match events.recv().await {
+            Ok(event) => sink.emit(&event),
+            Err(RecvError::Lagged(missed)) => warn!(missed, "Event sink fell behind, events dropped"),
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
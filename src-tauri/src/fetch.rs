@@ -0,0 +1,335 @@
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::header::{HeaderMap, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, RANGE};
+use reqwest::redirect::Policy;
+use reqwest::{Client, StatusCode, Url};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, instrument, warn, Instrument};
+use crate::cache::{strong_etag, Storable};
+use crate::chunk::{choose_chunk_size, MAX_CHUNK_SIZE, MAX_TRANSFER_SIZE};
+use crate::events::Event;
+use crate::scheduler::{complete_request, finish_download, run_download};
+use crate::state::AppState;
+
+/// Pseudo peer id the scheduler uses for fetches made by this node itself.
+/// Peers announcing it are ignored, so it never names a real connection.
+pub const LOCAL_PEER: &str = "local";
+
+/// How long a download from a server without range support may go without receiving anything.
+const STREAM_STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What we need to know about a URL before splitting it into chunks.
+#[derive(Debug, Clone)]
+pub struct UrlInfo {
+    pub total_size: u64,
+    pub accepts_ranges: bool,
+    /// Strong ETag, which pins the version of the file every range is taken from.
+    pub etag: Option<String>,
+}
+
+impl UrlInfo {
+    /// Reads size and range support from a response's headers.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let total_size = headers.get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()?;
+        let accepts_ranges = headers
+            .get(ACCEPT_RANGES)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.eq_ignore_ascii_case("bytes"));
+        Some(Self { total_size, accepts_ranges, etag: strong_etag(headers) })
+    }
+}
+
+/// Only plain web URLs may be fetched, so peers can't point us at `file://` and the like.
+pub fn is_fetchable(url: &str) -> bool {
+    reqwest::Url::parse(url).is_ok_and(|u| matches!(u.scheme(), "http" | "https"))
+}
+
+/// Whether `ip` is a public internet address. Peers must not be able to
+/// make us reach loopback, private, link-local or unique local addresses,
+/// where our own control API, routers and other private services live.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            !(v4.is_loopback()
+                || v4.is_private()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_multicast()
+                // "This network", 0.0.0.0/8
+                || a == 0
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(v4));
+            }
+            let segments = v6.segments();
+            let first = segments[0];
+            !(v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                // Unique local fc00::/7 and link-local fe80::/10
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                // Addresses that carry an IPv4 one, which could be anything:
+                // IPv4-compatible ::/96, NAT64 64:ff9b::/96 and 6to4 2002::/16
+                || segments[..6] == [0; 6]
+                || segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0]
+                || first == 0x2002)
+        }
+    }
+}
+
+/// Resolves host names to their public addresses only, so a name pointing
+/// into the LAN (or rebinding to it) can't be fetched for a peer.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?.filter(|a| is_public(a.ip())).collect();
+            if addrs.is_empty() {
+                let reason = format!("{} has no public address", host);
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, reason).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// HTTP client for fetches made on a peer's behalf. It doesn't follow
+/// redirects, which could lead anywhere, and unless `private` is set only
+/// connects to public addresses.
+#[derive(Clone)]
+pub struct PeerClient {
+    client: Client,
+    private: bool,
+}
+
+impl Default for PeerClient {
+    fn default() -> Self {
+        Self::new(false)
+    }
+}
+
+impl PeerClient {
+    pub fn new(private: bool) -> Self {
+        // An environment proxy would make the connections instead, past `PublicResolver`.
+        let builder = Client::builder().redirect(Policy::none()).no_proxy();
+        let builder = if private { builder } else { builder.dns_resolver(Arc::new(PublicResolver)) };
+        Self { client: builder.build().expect("HTTP client for peer fetches"), private }
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Checks a URL a peer asked us to fetch: http(s), and not an IP literal
+    /// outside the public internet. Host names are checked as they resolve.
+    pub fn check(&self, url: &str) -> Result<(), String> {
+        let parsed = Url::parse(url).map_err(|e| format!("Bad URL {}: {}", url, e))?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            return Err(format!("Not an http(s) URL: {}", url));
+        }
+        let host = parsed.host_str().ok_or_else(|| format!("No host in {}", url))?;
+        let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() else {
+            return Ok(());
+        };
+        if !self.private && !is_public(ip) {
+            return Err(format!("{} is not a public address", ip));
+        }
+        Ok(())
+    }
+}
+
+/// Asks the server for the size of `url` and whether it serves byte ranges.
+pub async fn probe(client: &Client, url: &str) -> Result<UrlInfo, String> {
+    if !is_fetchable(url) {
+        return Err(format!("Not an http(s) URL: {}", url));
+    }
+    let response = client.head(url).send().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("{} answered {}", url, response.status()));
+    }
+    let info = UrlInfo::from_headers(response.headers()).ok_or_else(|| format!("{} did not report its size", url))?;
+    if info.total_size > MAX_TRANSFER_SIZE {
+        return Err(format!("{} is {} bytes, more than the {} a download may be", url, info.total_size, MAX_TRANSFER_SIZE));
+    }
+    Ok(info)
+}
+
+/// Downloads bytes `[start, end)` of `url`, failing if the server no longer
+/// has the version with `etag`. Also says whether the bytes may be cached.
+pub async fn fetch_range(client: &Client, url: &str, etag: Option<&str>, start: u64, end: u64) -> Result<(Vec<u8>, Storable), String> {
+    if !is_fetchable(url) {
+        return Err(format!("Not an http(s) URL: {}", url));
+    }
+    let mut response = client
+        .get(url)
+        .header(RANGE, format!("bytes={}-{}", start, end.saturating_sub(1)))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    match response.status() {
+        StatusCode::PARTIAL_CONTENT => {
+            let expected = format!("bytes {}-{}/", start, end.saturating_sub(1));
+            let content_range = response.headers().get(CONTENT_RANGE).and_then(|v| v.to_str().ok()).unwrap_or("");
+            if !content_range.starts_with(&expected) {
+                return Err(format!("Server sent range '{}' instead of {}-{}", content_range, start, end));
+            }
+        }
+        // Servers without range support send the whole body, which is only what we asked for if it starts at 0.
+        StatusCode::OK if start == 0 => {}
+        status => return Err(format!("{} answered {}", url, status)),
+    }
+    let current = strong_etag(response.headers());
+    if etag.is_some() && current.is_some() && current.as_deref() != etag {
+        return Err(format!("{} changed since the download started", url));
+    }
+    let storable = Storable::from_headers(response.headers(), etag);
+
+    // Read no further than the range, whatever the server sends.
+    let len = (end - start) as usize;
+    let mut body = Vec::with_capacity(len.min(MAX_CHUNK_SIZE as usize));
+    while body.len() < len {
+        match response.chunk().await.map_err(|e| e.to_string())? {
+            Some(bytes) => body.extend_from_slice(&bytes[..bytes.len().min(len - body.len())]),
+            None => return Err(format!("Short read: got {} of {} bytes", body.len(), len)),
+        }
+    }
+    Ok((body, storable))
+}
+
+/// Bytes `[start, end)` of `url` from this node's pod cache, or else from the
+/// internet through `client`, keeping them in the cache if the server allows it.
+pub async fn fetch_cached(state: &AppState, client: &Client, url: &str, etag: Option<&str>, start: u64, end: u64) -> Result<Vec<u8>, String> {
+    if let Some(data) = state.cache.get(url, etag, start, end).await {
+        debug!(%url, start, end, "Range served from the pod cache");
+        state.metrics.cache_hits.inc();
+        return Ok(data);
+    }
+    if state.cache.enabled() {
+        state.metrics.cache_misses.inc();
+    }
+    let (data, storable) = fetch_range(client, url, etag, start, end).await?;
+    state.cache.put(url, etag, start, &data, storable).await;
+    Ok(data)
+}
+
+/// Registers a transfer for `url` and starts pulling it through the swarm:
+/// connected peers fetch ranges from the internet on our behalf, alongside
+/// this node. Returns the transfer id and the download task, which yields
+/// whether every chunk arrived.
+pub fn start_url_download(state: Arc<AppState>, url: String, info: &UrlInfo, output_path: String) -> crate::Result<(String, JoinHandle<bool>)> {
+    // With the pod cache, chunks are sized by the file alone so every node
+    // lays the URL out the same way and cached chunks line up.
+    let throughput = if state.cache.enabled() || !info.accepts_ranges { None } else { state.scheduler.total_throughput() };
+    let chunk_size = choose_chunk_size(info.total_size, throughput);
+    let file_id = state.chunk_manager.start_url_transfer(url.clone(), info.etag.clone(), info.total_size, chunk_size, output_path)?;
+
+    // Without range support the only option is one request for the whole body.
+    if !info.accepts_ranges {
+        let download = state.tasks.spawn(stream_download(state.clone(), file_id.clone(), url).in_current_span());
+        return Ok((file_id, download));
+    }
+    let mut peers: Vec<String> = state.peer_links.lock().unwrap().keys().cloned().collect();
+    peers.push(LOCAL_PEER.to_string());
+
+    let download = state.tasks.spawn(run_download(state.clone(), file_id.clone(), peers).in_current_span());
+    Ok((file_id, download))
+}
+
+/// Downloads `url` from a server without range support with one request,
+/// storing each chunk as soon as its bytes are in, so no more than about a
+/// chunk is held in memory. Returns whether the transfer completed.
+#[instrument(name = "transfer", skip_all, fields(%file_id))]
+async fn stream_download(state: Arc<AppState>, file_id: String, url: String) -> bool {
+    info!("Download started without range support");
+    if let Some(transfer) = state.chunk_manager.transfer(&file_id) {
+        state.events.publish(Event::TransferCreated { transfer });
+    }
+    let started = Instant::now();
+    let result = tokio::select! {
+        result = stream_body(&state, &file_id, &url) => result,
+        _ = state.shutdown.cancelled() => Err("node shutting down".to_string()),
+    };
+    finish_download(&state, file_id, result, started)
+}
+
+async fn stream_body(state: &AppState, file_id: &str, url: &str) -> Result<(), String> {
+    let mut response = state.http.get(url).send().await.map_err(|e| e.to_string())?;
+    if response.status() != StatusCode::OK {
+        return Err(format!("{} answered {}", url, response.status()));
+    }
+    let etag = state.chunk_manager.etag(file_id);
+    let current = strong_etag(response.headers());
+    if etag.is_some() && current.is_some() && current != etag {
+        return Err(format!("{} changed since the download started", url));
+    }
+    let cancelled = || "cancelled".to_string();
+    let count = state.chunk_manager.chunk_count(file_id).ok_or_else(cancelled)?;
+    let mut buf = Vec::new();
+    for index in 0..count {
+        let (start, end) = state.chunk_manager.chunk_range(file_id, index).ok_or_else(cancelled)?;
+        let len = (end - start) as usize;
+        while buf.len() < len {
+            let next = tokio::time::timeout(STREAM_STALL_TIMEOUT, response.chunk()).await.map_err(|_| "stalled".to_string())?;
+            match next.map_err(|e| e.to_string())? {
+                Some(bytes) => buf.extend_from_slice(&bytes),
+                None => return Err(format!("Short read: got {} of {} bytes", start as usize + buf.len(), end)),
+            }
+        }
+        // A paused download holds the connection and reads on when resumed.
+        while state.chunk_manager.transfer(file_id).ok_or_else(cancelled)?.paused {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let rest = buf.split_off(len);
+        let chunk = std::mem::replace(&mut buf, rest);
+        state.chunk_manager.write_chunk(file_id, index, chunk).await.map_err(|e| e.to_string())?;
+        let transfer = state.chunk_manager.transfer(file_id).ok_or_else(cancelled)?;
+        state.events.publish(Event::TransferProgress {
+            file_id: file_id.to_string(),
+            completed_bytes: transfer.completed_bytes,
+            total_size: transfer.total_size,
+        });
+    }
+    Ok(())
+}
+
+/// Fetches one chunk of a URL transfer from this node's own connection.
+pub async fn fetch_chunk_locally(state: Arc<AppState>, file_id: String, index: u64, url: String, start: u64, end: u64) {
+    let etag = state.chunk_manager.etag(&file_id);
+    let result = match fetch_cached(&state, &state.http, &url, etag.as_deref(), start, end).await {
+        // Another peer won the endgame race.
+        Ok(data) if state.chunk_manager.is_chunk_completed(&file_id, index) => {
+            complete_request(&state, LOCAL_PEER, &file_id, index, data.len());
+            return;
+        }
+        Ok(data) => {
+            let len = data.len();
+            state.chunk_manager.write_chunk(&file_id, index, data).await.map(|()| len).map_err(|e| e.to_string())
+        }
+        Err(e) => Err(e),
+    };
+    let peer = LOCAL_PEER.to_string();
+    match result {
+        Ok(len) => {
+            complete_request(&state, LOCAL_PEER, &file_id, index, len);
+            let verified = state.chunk_manager.is_verified(&file_id);
+            state.events.publish(Event::ChunkStored { file_id, index, peer, verified });
+        }
+        Err(reason) => {
+            warn!(index, %reason, "Local fetch of chunk failed");
+            state.scheduler.record_failed(LOCAL_PEER, &file_id, index);
+            state.events.publish(Event::ChunkFailed { file_id, index, peer, reason });
+        }
+    }
+}
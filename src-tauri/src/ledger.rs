@@ -0,0 +1,235 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
+use crate::persist::{load_json, to_json, write_file};
+use crate::state::AppState;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a deprioritised peer waits before each chunk is served.
+const DEFER_DELAY: Duration = Duration::from_millis(250);
+
+/// Bytes exchanged with one peer over its lifetime.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Balance {
+    /// Bytes we uploaded to the peer.
+    pub given: u64,
+    /// Bytes the peer uploaded to us.
+    pub received: u64,
+}
+
+impl Balance {
+    /// How much more the peer has taken than given back.
+    pub fn deficit(&self) -> u64 {
+        self.given.saturating_sub(self.received)
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    balances: HashMap<String, Balance>,
+    dirty: bool,
+}
+
+/// Per-peer `Balance`s keyed by device id, persisted as JSON.
+#[derive(Default)]
+pub struct Ledger {
+    inner: Mutex<Inner>,
+    path: Option<PathBuf>,
+}
+
+impl Ledger {
+    pub fn load(path: Option<PathBuf>) -> io::Result<Self> {
+        let balances = load_json(path.as_deref())?;
+        Ok(Self {
+            inner: Mutex::new(Inner { balances, dirty: false }),
+            path,
+        })
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else { return Ok(()) };
+        let json = {
+            let mut inner = self.inner.lock().unwrap();
+            if !inner.dirty {
+                return Ok(());
+            }
+            inner.dirty = false;
+            to_json(&inner.balances)?
+        };
+        write_file(path, &json)
+    }
+
+    pub fn balance(&self, peer_id: &str) -> Balance {
+        self.inner.lock().unwrap().balances.get(peer_id).copied().unwrap_or_default()
+    }
+
+    pub fn balances(&self) -> HashMap<String, Balance> {
+        self.inner.lock().unwrap().balances.clone()
+    }
+
+    pub fn record_given(&self, peer_id: &str, bytes: u64) {
+        self.record(peer_id, |b| b.given += bytes);
+    }
+
+    pub fn record_received(&self, peer_id: &str, bytes: u64) {
+        self.record(peer_id, |b| b.received += bytes);
+    }
+
+    fn record(&self, peer_id: &str, update: impl FnOnce(&mut Balance)) {
+        let mut inner = self.inner.lock().unwrap();
+        update(inner.balances.entry(peer_id.to_string()).or_default());
+        inner.dirty = true;
+    }
+}
+
+/// Saves the ledger to disk every 30 seconds while the node runs, and once more on shutdown.
+pub async fn run_ledger_flush(state: Arc<AppState>) {
+    let mut stopping = false;
+    while !stopping {
+        tokio::select! {
+            _ = tokio::time::sleep(FLUSH_INTERVAL) => {}
+            _ = state.shutdown.cancelled() => stopping = true,
+        }
+        if let Err(e) = state.ledger.save() {
+            warn!(error = %e, "Failed to save ledger");
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Serve,
+    /// Serve, but only after the delay, so contributors get the upload first.
+    Defer(Duration),
+    /// Refuse; the requester is told it is choked.
+    Choke,
+}
+
+/// Decides whether chunk requests from a peer are served.
+pub trait ServePolicy: Send + Sync + 'static {
+    fn verdict(&self, peer_id: &str, balance: Balance) -> Verdict;
+}
+
+impl Default for Box<dyn ServePolicy> {
+    fn default() -> Self {
+        Box::new(ServeAll)
+    }
+}
+
+/// Serves everyone. For trusted home setups.
+pub struct ServeAll;
+
+impl ServePolicy for ServeAll {
+    fn verdict(&self, _peer_id: &str, _balance: Balance) -> Verdict {
+        Verdict::Serve
+    }
+}
+
+/// Tit-for-tat: peers may take `grace` bytes more than they gave at full
+/// priority, are deprioritised after that, and choked past `choke_deficit`.
+pub struct Reciprocal {
+    pub grace: u64,
+    pub choke_deficit: u64,
+}
+
+impl ServePolicy for Reciprocal {
+    fn verdict(&self, _peer_id: &str, balance: Balance) -> Verdict {
+        let deficit = balance.deficit();
+        if deficit > self.choke_deficit {
+            Verdict::Choke
+        } else if deficit > self.grace {
+            Verdict::Defer(DEFER_DELAY)
+        } else {
+            Verdict::Serve
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyKind {
+    #[default]
+    Off,
+    Reciprocal,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FairnessConfig {
+    pub policy: PolicyKind,
+    /// Bytes a peer may take beyond what it gave before being deprioritised.
+    pub grace_bytes: u64,
+    /// Bytes a peer may take beyond what it gave before being choked.
+    pub choke_deficit_bytes: u64,
+}
+
+impl Default for FairnessConfig {
+    fn default() -> Self {
+        Self {
+            policy: PolicyKind::Off,
+            grace_bytes: 64 * 1024 * 1024,
+            choke_deficit_bytes: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+impl FairnessConfig {
+    pub fn build(&self) -> Box<dyn ServePolicy> {
+        match self.policy {
+            PolicyKind::Off => Box::new(ServeAll),
+            PolicyKind::Reciprocal => Box::new(Reciprocal {
+                grace: self.grace_bytes,
+                choke_deficit: self.choke_deficit_bytes,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balance(given: u64, received: u64) -> Balance {
+        Balance { given, received }
+    }
+
+    #[test]
+    fn deficit_never_goes_negative() {
+        assert_eq!(balance(10, 3).deficit(), 7);
+        assert_eq!(balance(3, 10).deficit(), 0);
+    }
+
+    #[test]
+    fn reciprocal_verdicts_at_the_boundaries() {
+        let policy = Reciprocal { grace: 100, choke_deficit: 1000 };
+        assert_eq!(policy.verdict("a", balance(0, 0)), Verdict::Serve);
+        assert_eq!(policy.verdict("a", balance(100, 0)), Verdict::Serve);
+        assert_eq!(policy.verdict("a", balance(101, 0)), Verdict::Defer(DEFER_DELAY));
+        assert_eq!(policy.verdict("a", balance(1000, 0)), Verdict::Defer(DEFER_DELAY));
+        assert_eq!(policy.verdict("a", balance(1001, 0)), Verdict::Choke);
+        // What the peer gave back counts against what it took.
+        assert_eq!(policy.verdict("a", balance(1001, 901)), Verdict::Serve);
+        assert_eq!(policy.verdict("a", balance(u64::MAX, 0)), Verdict::Choke);
+    }
+
+    #[test]
+    fn fairness_off_serves_everyone() {
+        let policy = FairnessConfig::default().build();
+        assert_eq!(policy.verdict("a", balance(u64::MAX, 0)), Verdict::Serve);
+    }
+
+    #[test]
+    fn ledger_records_both_directions() {
+        let ledger = Ledger::default();
+        ledger.record_given("a", 5);
+        ledger.record_given("a", 5);
+        ledger.record_received("a", 3);
+        assert_eq!(ledger.balance("a").deficit(), 7);
+        assert_eq!(ledger.balance("b").deficit(), 0);
+    }
+}
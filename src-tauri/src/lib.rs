@@ -1,5 +1,30 @@
+pub mod bitfield;
+pub mod cache;
 pub mod chunk;
+pub mod collection;
+pub mod config;
+pub mod devices;
+pub mod error;
+pub mod events;
+pub mod fetch;
+pub mod ledger;
+pub mod logging;
+pub mod metrics;
+pub mod node;
+pub mod offer;
+pub mod output;
+pub mod persist;
 pub mod protocol;
+pub mod proxy;
+pub mod ratelimit;
+pub mod sandbox;
+pub mod scheduler;
 pub mod state;
+pub mod swarm;
 pub mod transport;
+pub mod usage;
+pub mod webserver;
 pub mod discovery;
+
+pub use error::{Error, Result};
+pub use node::{Node, NodeBuilder};
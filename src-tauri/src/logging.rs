@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tracing::Subscriber;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{self, Rotation};
+use tracing_subscriber::fmt::{self, MakeWriter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of every enclosing span.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown log format '{}', expected text or json", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// A level, or filter directives such as `info,peapod::transport=debug`.
+    pub level: String,
+    pub format: LogFormat,
+    /// Also write logs to `peapod.<date>.log` files in this directory.
+    pub dir: Option<PathBuf>,
+    pub rotation: LogRotation,
+    /// Rotated files to keep; 0 keeps them all.
+    pub max_files: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".into(),
+            format: LogFormat::Text,
+            dir: None,
+            rotation: LogRotation::Daily,
+            max_files: 7,
+        }
+    }
+}
+
+/// Installs the global subscriber: stdout, plus the rotating files when
+/// `dir` is set. Keep the returned guard until exit, since dropping it
+/// flushes what is still buffered for the files.
+pub fn init(config: &LogConfig) -> io::Result<Option<WorkerGuard>> {
+    let filter = EnvFilter::try_new(&config.level).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let (file, guard) = match &config.dir {
+        Some(dir) => {
+            let rotation = match config.rotation {
+                LogRotation::Hourly => Rotation::HOURLY,
+                LogRotation::Daily => Rotation::DAILY,
+                LogRotation::Never => Rotation::NEVER,
+            };
+            let mut builder = rolling::Builder::new().rotation(rotation).filename_prefix("peapod").filename_suffix("log");
+            if config.max_files > 0 {
+                builder = builder.max_log_files(config.max_files);
+            }
+            let appender = builder.build(dir).map_err(io::Error::other)?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (Some(layer(config.format, writer, false)), Some(guard))
+        }
+        None => (None, None),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layer(config.format, io::stdout, true))
+        .with(file)
+        .try_init()
+        .map_err(io::Error::other)?;
+    Ok(guard)
+}
+
+fn layer<S, W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = fmt::layer().with_writer(writer).with_ansi(ansi);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    }
+}
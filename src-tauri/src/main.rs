@@ -1,27 +1,144 @@
-mod chunk;
-mod discovery;
-mod protocol;
-mod state;
-mod transport;
-mod webserver;
+mod client;
+mod commands;
 
 use clap::Parser;
-use peapod::chunk::ChunkManager;
-use peapod::discovery::run_discovery;
-use peapod::protocol::Beacon;
-use peapod::state::{AppState, PeerEmitter};
-use peapod::transport::run_tcp_listener;
-use std::sync::Arc;
-use tauri::{AppHandle, Emitter, Manager};
+use client::Command;
+use commands::ConfigPath;
+use peapod::config::Config;
+use peapod::events::{run_event_sink, Event, EventSink};
+use peapod::logging::{self, LogFormat};
+use peapod::Node;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use tauri::{AppHandle, Emitter, Manager, RunEvent};
+use tokio::runtime::Handle;
 use tokio::time::Duration;
-use uuid::Uuid;
+use tracing::{error, info, warn};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    /// Control a running daemon instead of starting a node
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Print machine-readable JSON (for subcommands)
+    #[arg(long, global = true)]
+    json: bool,
+
     /// Run in headless daemon mode (no GUI)
     #[arg(long, short)]
     daemon: bool,
+
+    /// Config file (defaults to <config dir>/peapod/config.toml)
+    #[arg(long, short, env = "PEAPOD_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Device name advertised to other peers
+    #[arg(long, env = "PEAPOD_NAME")]
+    name: Option<String>,
+
+    /// Directory for the device id, usage records, peer ledger and other node state
+    #[arg(long, env = "PEAPOD_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// Where downloads are saved
+    #[arg(long, env = "PEAPOD_DOWNLOAD_DIR")]
+    download_dir: Option<PathBuf>,
+
+    /// UDP port for multicast discovery
+    #[arg(long, env = "PEAPOD_DISCOVERY_PORT")]
+    discovery_port: Option<u16>,
+
+    /// Local interface used to join the multicast group
+    #[arg(long, env = "PEAPOD_INTERFACE")]
+    interface: Option<Ipv4Addr>,
+
+    /// Seconds between discovery beacons
+    #[arg(long, env = "PEAPOD_BEACON_INTERVAL")]
+    beacon_interval: Option<u64>,
+
+    /// TCP port for peer connections
+    #[arg(long, env = "PEAPOD_TCP_PORT")]
+    tcp_port: Option<u16>,
+
+    /// Address the peer TCP listener binds to
+    #[arg(long, env = "PEAPOD_TCP_BIND")]
+    tcp_bind: Option<IpAddr>,
+
+    /// Port for the HTTP control API
+    #[arg(long, env = "PEAPOD_API_PORT")]
+    api_port: Option<u16>,
+
+    /// Address the HTTP control API binds to (anything but loopback needs --api-token)
+    #[arg(long, env = "PEAPOD_API_BIND")]
+    api_bind: Option<IpAddr>,
+
+    /// Serve the control API on this Unix socket instead of TCP
+    #[arg(long, env = "PEAPOD_API_SOCKET")]
+    api_socket: Option<PathBuf>,
+
+    /// Bearer token required by the control API
+    #[arg(long, env = "PEAPOD_API_TOKEN", hide_env_values = true)]
+    api_token: Option<String>,
+
+    /// Run the local HTTP proxy that speeds up large downloads through the swarm
+    #[arg(long, env = "PEAPOD_PROXY")]
+    proxy: bool,
+
+    /// Port for the local HTTP proxy
+    #[arg(long, env = "PEAPOD_PROXY_PORT")]
+    proxy_port: Option<u16>,
+
+    /// Loopback address the local HTTP proxy binds to
+    #[arg(long, env = "PEAPOD_PROXY_BIND")]
+    proxy_bind: Option<IpAddr>,
+
+    /// Fetch internet ranges for peers that ask
+    #[arg(long, env = "PEAPOD_SERVE_PEERS")]
+    serve_peers: bool,
+
+    /// Keep internet downloads in a cache the rest of the pod can fetch from
+    #[arg(long, env = "PEAPOD_CACHE")]
+    cache: bool,
+
+    /// Log level or filter, e.g. "debug" or "info,peapod::transport=debug"
+    #[arg(long, env = "PEAPOD_LOG")]
+    log_level: Option<String>,
+
+    /// Log output format: text or json
+    #[arg(long, env = "PEAPOD_LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+
+    /// Also write logs to daily rotated files in this directory
+    #[arg(long, env = "PEAPOD_LOG_DIR")]
+    log_dir: Option<PathBuf>,
+}
+
+impl Args {
+    /// Flags (and their env vars) take precedence over the config file.
+    fn apply(&self, config: &mut Config) {
+        if let Some(name) = &self.name { config.node.name = Some(name.clone()); }
+        if let Some(dir) = &self.data_dir { config.node.data_dir = Some(dir.clone()); }
+        if let Some(dir) = &self.download_dir { config.files.download_dir = Some(dir.clone()); }
+        if let Some(port) = self.discovery_port { config.discovery.port = port; }
+        if let Some(interface) = self.interface { config.discovery.interface = interface; }
+        if let Some(secs) = self.beacon_interval { config.discovery.beacon_interval_secs = secs; }
+        if let Some(port) = self.tcp_port { config.transport.port = port; }
+        if let Some(bind) = self.tcp_bind { config.transport.bind = bind; }
+        if let Some(port) = self.api_port { config.api.port = port; }
+        if let Some(bind) = self.api_bind { config.api.bind = bind; }
+        if let Some(socket) = &self.api_socket { config.api.socket = Some(socket.clone()); }
+        if let Some(token) = &self.api_token { config.api.token = Some(token.clone()); }
+        if self.proxy { config.proxy.enabled = true; }
+        if let Some(port) = self.proxy_port { config.proxy.port = port; }
+        if let Some(bind) = self.proxy_bind { config.proxy.bind = bind; }
+        if self.serve_peers { config.proxy.serve_peers = true; }
+        if self.cache { config.cache.enabled = true; }
+        if let Some(level) = &self.log_level { config.log.level = level.clone(); }
+        if let Some(format) = self.log_format { config.log.format = format; }
+        if let Some(dir) = &self.log_dir { config.log.dir = Some(dir.clone()); }
+    }
 }
 
 // GUI Emitter
@@ -29,112 +146,159 @@ struct TauriEmitter {
     handle: AppHandle,
 }
 
-impl PeerEmitter for TauriEmitter {
-    fn emit(&self, peer: Beacon) {
-        #[derive(Clone, serde::Serialize)]
-        struct PeerFound {
-            id: String,
-            name: String,
-            port: u16,
+impl EventSink for TauriEmitter {
+    fn emit(&self, event: &Event) {
+        if let Err(e) = self.handle.emit("peapod-event", event) {
+            warn!(error = %e, "Failed to forward event to the GUI");
+        }
+        if let Event::PeerDiscovered { peer } = event {
+            #[derive(Clone, serde::Serialize)]
+            struct PeerFound<'a> {
+                id: &'a str,
+                name: &'a str,
+                port: u16,
+            }
+            let found = PeerFound {
+                id: &peer.device_id,
+                name: &peer.name,
+                port: peer.port,
+            };
+            if let Err(e) = self.handle.emit("peer-update", &found) {
+                warn!(error = %e, "Failed to forward peer to the GUI");
+            }
         }
-        let event = PeerFound {
-            id: peer.device_id,
-            name: peer.name,
-            port: peer.port,
-        };
-        let _ = self.handle.emit("peer-update", &event);
-    }
-}
-
-// CLI Emitter
-struct CliEmitter;
-impl PeerEmitter for CliEmitter {
-    fn emit(&self, peer: Beacon) {
-        println!(">>> Discovered Peer: {} ({}) on port {}", peer.name, peer.device_id, peer.port);
     }
 }
 
-// Test Command (GUI only)
-#[tauri::command]
-async fn start_test_transfer(state: tauri::State<'_, Arc<AppState>>) -> Result<String, String> {
-    let file_id = state.chunk_manager.start_transfer(
-        "test.txt".into(),
-        1024 * 1024 * 5,
-        "/tmp/test_out.txt".into(),
-    );
-    Ok(format!("Started transfer: {}", file_id))
-}
-
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
-    let chunk_manager = Arc::new(ChunkManager::new());
-    let app_state = Arc::new(AppState {
-        chunk_manager,
-        ..Default::default()
-    });
-
-    let my_id = Uuid::new_v4().to_string();
-    let my_name = if args.daemon { "CashlyPod-CLI".to_string() } else { "CashlyPod-GUI".to_string() };
+    let config_path = args.config.clone().or_else(Config::default_path);
+    let mut config = match &config_path {
+        // Running on the defaults instead would drop the token, share roots and binds.
+        Some(path) => Config::load(path).unwrap_or_else(|e| {
+            eprintln!("Failed to load config {}: {}", path.display(), e);
+            std::process::exit(1);
+        }),
+        None => Config::default(),
+    };
+    args.apply(&mut config);
 
-    if args.daemon {
-        println!("🚀 Starting PeaPod in HEADLESS DAEMON MODE");
-        println!("ID: {}", my_id);
+    if let Some(command) = args.command {
+        if let Err(e) = client::run(command, &config.api, args.json).await {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-        let id_clone = my_id.clone();
-        let name_clone = my_name.clone();
-        let state_clone = app_state.clone();
-        let tcp_state = app_state.clone();
-        let tcp_id = my_id.clone();
+    // Dropping this flushes the log files, so it is dropped before exiting.
+    let mut log_guard = match logging::init(&config.log) {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("Invalid logging setup: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-        // Spawn TCP
-        tokio::spawn(async move {
-            run_tcp_listener(tcp_id, 45679, tcp_state).await;
-        });
+    if config.node.name.is_none() {
+        let default_name = if args.daemon { "CashlyPod-CLI" } else { "CashlyPod-GUI" };
+        config.node.name = Some(default_name.to_string());
+    }
+    let node = Node::builder(config).build();
+    let app_state = node.state().clone();
 
-        // Spawn Webserver (for remote control/debugging)
-        let webserver_state = app_state.clone();
-        tokio::spawn(async move {
-            webserver::start_webserver(webserver_state).await;
-        });
+    if args.daemon {
+        info!(id = %node.id(), name = %node.config().device_name(), "Starting PeaPod in headless daemon mode");
 
-        // Run Discovery (Blocking main thread or await)
-        run_discovery(
-            id_clone,
-            name_clone,
-            45679,
-            45678,
-            state_clone,
-            CliEmitter
-        ).await;
+        // Discoveries and transfer outcomes are logged where they happen.
+        if let Err(e) = node.start().await {
+            error!(error = %e, "Failed to start");
+            drop(log_guard);
+            std::process::exit(1);
+        }
+        shutdown_signal().await;
 
+        info!("Shutting down");
+        if !node.shutdown().await {
+            error!("Some tasks did not stop in time");
+            drop(log_guard);
+            std::process::exit(1);
+        }
     } else {
-        // GUI MODE
-        tauri::Builder::default()
+        // GUI MODE: Tauri runs its tasks on our runtime, alongside the node's.
+        tauri::async_runtime::set(tokio::runtime::Handle::current());
+        if let Err(e) = node.start().await {
+            error!(error = %e, "Failed to start");
+            drop(log_guard);
+            std::process::exit(1);
+        }
+
+        let app = tauri::Builder::default()
             .manage(app_state.clone())
-            .invoke_handler(tauri::generate_handler![start_test_transfer])
+            .manage(ConfigPath(config_path))
+            .invoke_handler(tauri::generate_handler![
+                commands::list_peers,
+                commands::list_transfers,
+                commands::start_download,
+                commands::share_file,
+                commands::send_offer,
+                commands::list_offers,
+                commands::accept_offer,
+                commands::decline_offer,
+                commands::pause_transfer,
+                commands::resume_transfer,
+                commands::cancel_transfer,
+                commands::list_devices,
+                commands::pair_device,
+                commands::unpair_device,
+                commands::block_device,
+                commands::unblock_device,
+                commands::get_config,
+                commands::save_config,
+                commands::get_limits,
+                commands::set_limits,
+                commands::get_balances
+            ])
             .setup(move |app| {
                 let handle = app.handle().clone();
-                let state_clone = app_state.clone();
-                let id_clone = my_id.clone();
-                let name_clone = my_name.clone();
-                let tcp_id = my_id.clone();
-                let tcp_state = app_state.clone();
-
-                let emitter = TauriEmitter { handle };
+                tauri::async_runtime::spawn(run_event_sink(app_state.clone(), TauriEmitter { handle }));
+                Ok(())
+            })
+            .build(tauri::generate_context!())
+            .expect("error while building tauri application");
 
-                tauri::async_runtime::spawn(async move {
-                    run_discovery(id_clone, name_clone, 45679, 45678, state_clone, emitter).await;
-                });
+        // Ctrl-C in the terminal closes the app the same way Quit does.
+        let handle = app.handle().clone();
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            handle.exit(0);
+        });
 
-                tauri::async_runtime::spawn(async move {
-                    run_tcp_listener(tcp_id, 45679, tcp_state).await;
-                });
+        app.run(move |_, event| {
+            if let RunEvent::Exit = event {
+                let stopped = tokio::task::block_in_place(|| Handle::current().block_on(node.shutdown()));
+                if !stopped {
+                    error!("Some tasks did not stop in time");
+                }
+                log_guard.take();
+            }
+        });
+    }
+}
 
-                Ok(())
-            })
-            .run(tauri::generate_context!())
-            .expect("error while running tauri application");
+/// Resolves on Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
     }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use crate::state::AppState;
+
+/// Upper bounds, in seconds, of the transfer duration histogram buckets.
+const DURATION_BUCKETS: [f64; 8] = [1.0, 5.0, 15.0, 30.0, 60.0, 300.0, 900.0, 3600.0];
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct PeerBytes {
+    sent: u64,
+    received: u64,
+}
+
+#[derive(Default, Clone)]
+struct Histogram {
+    /// Observations at or below each of `DURATION_BUCKETS`.
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// Counters since the node started, served at `GET /metrics` in the
+/// Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    pub beacons_sent: Counter,
+    pub beacons_received: Counter,
+    /// Failed dials, handshakes and connections dropped by an I/O error.
+    pub connection_errors: Counter,
+    /// Chunk requests from peers, including ranges they asked us to fetch.
+    pub chunks_requested: Counter,
+    pub chunks_served: Counter,
+    /// Chunks received whole and stored, whether or not there was a hash to check them against.
+    pub chunks_stored: Counter,
+    pub chunks_failed: Counter,
+    /// Internet ranges served from the pod cache, for us or for peers.
+    pub cache_hits: Counter,
+    /// Internet ranges the enabled cache didn't have.
+    pub cache_misses: Counter,
+    peer_bytes: Mutex<BTreeMap<String, PeerBytes>>,
+    /// Keyed by outcome, `completed` or `failed`.
+    transfer_durations: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+impl Metrics {
+    pub fn record_sent(&self, peer_id: &str, bytes: u64) {
+        self.peer_bytes.lock().unwrap().entry(peer_id.to_string()).or_default().sent += bytes;
+    }
+
+    pub fn record_received(&self, peer_id: &str, bytes: u64) {
+        self.peer_bytes.lock().unwrap().entry(peer_id.to_string()).or_default().received += bytes;
+    }
+
+    /// Drops a peer's byte counts once it disconnects, so the series don't
+    /// pile up for every device ever seen.
+    pub fn peer_disconnected(&self, peer_id: &str) {
+        self.peer_bytes.lock().unwrap().remove(peer_id);
+    }
+
+    pub fn record_transfer(&self, completed: bool, duration: Duration) {
+        let outcome = if completed { "completed" } else { "failed" };
+        let secs = duration.as_secs_f64();
+        let mut durations = self.transfer_durations.lock().unwrap();
+        let histogram = durations.entry(outcome).or_default();
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if secs <= bound { *bucket += 1; }
+        }
+        histogram.count += 1;
+        histogram.sum += secs;
+    }
+}
+
+/// Everything in `state.metrics`, plus the current peer counts.
+pub fn render(state: &AppState) -> String {
+    let metrics = &state.metrics;
+    let mut out = String::new();
+    let discovered = state.peers.lock().unwrap().len();
+    let connected = state.peer_links.lock().unwrap().len();
+    gauge(&mut out, "peapod_peers_discovered", "Peers currently heard from on the LAN.", discovered as u64);
+    gauge(&mut out, "peapod_peers_connected", "Peers with a live connection.", connected as u64);
+
+    counter(&mut out, "peapod_beacons_sent_total", "Discovery beacons sent.", metrics.beacons_sent.get());
+    counter(&mut out, "peapod_beacons_received_total", "Discovery beacons received from other nodes.", metrics.beacons_received.get());
+    counter(&mut out, "peapod_connection_errors_total", "Failed or broken peer connections.", metrics.connection_errors.get());
+    counter(&mut out, "peapod_chunks_requested_total", "Chunk requests received from peers.", metrics.chunks_requested.get());
+    counter(&mut out, "peapod_chunks_served_total", "Chunks sent to peers.", metrics.chunks_served.get());
+    counter(&mut out, "peapod_chunks_stored_total", "Chunks received and stored.", metrics.chunks_stored.get());
+    counter(&mut out, "peapod_chunks_failed_total", "Chunks that could not be fetched or stored.", metrics.chunks_failed.get());
+    counter(&mut out, "peapod_cache_hits_total", "Internet ranges served from the pod cache.", metrics.cache_hits.get());
+    counter(&mut out, "peapod_cache_misses_total", "Internet ranges the pod cache did not have.", metrics.cache_misses.get());
+
+    let peer_bytes = metrics.peer_bytes.lock().unwrap().clone();
+    header(&mut out, "peapod_peer_bytes_sent_total", "Bytes sent to each connected peer.", "counter");
+    for (peer, bytes) in &peer_bytes {
+        let _ = writeln!(out, "peapod_peer_bytes_sent_total{{peer=\"{}\"}} {}", escape(peer), bytes.sent);
+    }
+    header(&mut out, "peapod_peer_bytes_received_total", "Bytes received from each connected peer.", "counter");
+    for (peer, bytes) in &peer_bytes {
+        let _ = writeln!(out, "peapod_peer_bytes_received_total{{peer=\"{}\"}} {}", escape(peer), bytes.received);
+    }
+
+    let name = "peapod_transfer_duration_seconds";
+    header(&mut out, name, "Time from starting a download to completing or failing it.", "histogram");
+    for (outcome, histogram) in metrics.transfer_durations.lock().unwrap().iter() {
+        for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets) {
+            let _ = writeln!(out, "{}_bucket{{outcome=\"{}\",le=\"{}\"}} {}", name, outcome, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{outcome=\"{}\",le=\"+Inf\"}} {}", name, outcome, histogram.count);
+        let _ = writeln!(out, "{}_sum{{outcome=\"{}\"}} {}", name, outcome, histogram.sum);
+        let _ = writeln!(out, "{}_count{{outcome=\"{}\"}} {}", name, outcome, histogram.count);
+    }
+    out
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Label values are quoted, so backslashes, quotes and newlines are escaped.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,253 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::{info_span, warn, Instrument, Span};
+use crate::cache::{run_cache_flush, PodCache};
+use crate::chunk::{ChunkManager, TransferInfo};
+use crate::config::Config;
+use crate::devices::Devices;
+use crate::discovery::{bind_discovery, run_discovery};
+use crate::error::{Error, Result};
+use crate::events::Event;
+use crate::fetch::{is_fetchable, PeerClient};
+use crate::ledger::{run_ledger_flush, Ledger};
+use crate::metrics::Metrics;
+use crate::offer::OfferInfo;
+use crate::protocol::Manifest;
+use crate::proxy::{bind_proxy, run_proxy};
+use crate::ratelimit::RateLimiter;
+use crate::state::AppState;
+use crate::transport::{bind_tcp_listener, run_tcp_listener};
+use crate::usage::{run_usage_flush, UsageTracker};
+use crate::webserver::{
+    self, bind_webserver, start_webserver, AcceptOfferRequest, DownloadRequest, OfferRequest, PeerInfo, ShareRequest,
+    TransferRequest,
+};
+
+/// How long `shutdown` waits for connections to close and tasks to finish.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sets up a `Node` from a config. Usage records, the peer ledger, the
+/// device lists and the pod cache are loaded from the data directory when
+/// there is one.
+pub struct NodeBuilder {
+    config: Config,
+    device_id: Option<String>,
+    api: bool,
+}
+
+impl NodeBuilder {
+    /// Uses this id instead of the one kept in the data directory.
+    pub fn device_id(mut self, id: impl Into<String>) -> Self {
+        self.device_id = Some(id.into());
+        self
+    }
+
+    /// Whether `start` serves the control API. On by default.
+    pub fn api(mut self, enabled: bool) -> Self {
+        self.api = enabled;
+        self
+    }
+
+    pub fn build(self) -> Node {
+        let config = self.config;
+        let data_dir = config.data_dir();
+        let usage = UsageTracker::load(data_dir.as_ref().map(|dir| dir.join("usage.json")), config.quota.clone())
+            .unwrap_or_else(|e| {
+                warn!(error = %e, "Failed to load usage records");
+                let fallback = UsageTracker::default();
+                fallback.set_quota(config.quota.clone());
+                fallback
+            });
+        let ledger = Ledger::load(data_dir.as_ref().map(|dir| dir.join("ledger.json"))).unwrap_or_else(|e| {
+            warn!(error = %e, "Failed to load peer ledger");
+            Ledger::default()
+        });
+        let devices = Devices::load(data_dir.as_ref().map(|dir| dir.join("devices.json")), &config.devices)
+            .unwrap_or_else(|e| {
+                warn!(error = %e, "Failed to load paired and blocked devices");
+                Devices::default()
+            });
+        let cache = PodCache::open(&config.cache, data_dir.as_deref()).unwrap_or_else(|e| {
+            warn!(error = %e, "Failed to open the pod cache, running without it");
+            PodCache::default()
+        });
+        let metrics = Arc::new(Metrics::default());
+        let state = AppState {
+            chunk_manager: Arc::new(ChunkManager::new(metrics.clone())),
+            metrics,
+            rate_limiter: RateLimiter::new(config.limits.clone()),
+            usage,
+            ledger,
+            devices,
+            cache,
+            raw_payloads: config.transport.raw_payloads,
+            serve_fetches: config.proxy.serve_peers,
+            // Not through the environment's proxy, which may be our own.
+            http: reqwest::Client::builder().no_proxy().build().unwrap_or_default(),
+            peer_http: PeerClient::new(config.proxy.serve_private),
+            serve_policy: config.fairness.build(),
+            sandbox: config.sandbox(),
+            ..Default::default()
+        };
+        let id = self.device_id.unwrap_or_else(|| config.device_id());
+        Node {
+            span: info_span!("node", %id),
+            id,
+            config: Arc::new(config),
+            state: Arc::new(state),
+            api: self.api,
+        }
+    }
+}
+
+/// A PeaPod node: discovery, peer transport, the control API and the
+/// optional proxy, all sharing one `AppState`. The GUI, the daemon and the
+/// examples all run through here, and other Rust programs can embed it.
+pub struct Node {
+    id: String,
+    config: Arc<Config>,
+    state: Arc<AppState>,
+    api: bool,
+    /// Parent of everything the node logs, so two nodes in one process can be told apart.
+    span: Span,
+}
+
+impl Node {
+    pub fn builder(config: Config) -> NodeBuilder {
+        NodeBuilder { config, device_id: None, api: true }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn config(&self) -> &Arc<Config> {
+        &self.config
+    }
+
+    pub fn state(&self) -> &Arc<AppState> {
+        &self.state
+    }
+
+    /// Binds every socket, then spawns every subsystem on the current Tokio
+    /// runtime. Nothing is spawned if a port is taken or an address is refused.
+    pub async fn start(&self) -> Result<()> {
+        let config = &self.config;
+        config.validate().map_err(Error::Invalid)?;
+        let tcp = bind_tcp_listener(&config.transport).await?;
+        let discovery = bind_discovery(&config.discovery).await?;
+        let api = if self.api { Some(bind_webserver(&config.api).await?) } else { None };
+        let proxy = if config.proxy.enabled { Some(bind_proxy(&config.proxy).await?) } else { None };
+
+        let state = &self.state;
+        let tasks = &state.tasks;
+        let span = &self.span;
+        tasks.spawn(run_tcp_listener(tcp, self.id.clone(), state.clone()).instrument(span.clone()));
+        tasks.spawn(run_discovery(discovery, self.id.clone(), config.clone(), state.clone()).instrument(span.clone()));
+
+        // Persist data usage, peer balances and the cache index
+        tasks.spawn(run_usage_flush(state.clone()).instrument(span.clone()));
+        tasks.spawn(run_ledger_flush(state.clone()).instrument(span.clone()));
+        tasks.spawn(run_cache_flush(state.clone()).instrument(span.clone()));
+
+        if let Some(listener) = api {
+            tasks.spawn(start_webserver(listener, state.clone(), config.api.clone()).instrument(span.clone()));
+        }
+        if let Some(listener) = proxy {
+            tasks.spawn(run_proxy(listener, config.proxy.clone(), state.clone()).instrument(span.clone()));
+        }
+        Ok(())
+    }
+
+    /// Stops the node: a leaving beacon and a `Goodbye` to each peer, then
+    /// connections close, downloads stop where they are and their files are
+    /// synced, and usage records, the ledger and the cache index are saved. Returns false if
+    /// something was still running after `SHUTDOWN_TIMEOUT`.
+    pub async fn shutdown(&self) -> bool {
+        self.state.shutdown.cancel();
+        self.state.tasks.close();
+        let stopped = tokio::time::timeout(SHUTDOWN_TIMEOUT, self.state.tasks.wait()).await.is_ok();
+        self.state.chunk_manager.sync_outputs().await;
+        if let Err(e) = self.state.usage.save() {
+            warn!(error = %e, "Failed to save usage records");
+        }
+        if let Err(e) = self.state.ledger.save() {
+            warn!(error = %e, "Failed to save ledger");
+        }
+        if let Err(e) = self.state.cache.save() {
+            warn!(error = %e, "Failed to save cache index");
+        }
+        stopped
+    }
+
+    /// Every event the node publishes from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.state.events.subscribe()
+    }
+
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        webserver::peer_list(&self.state)
+    }
+
+    pub fn transfers(&self) -> Vec<TransferInfo> {
+        self.state.chunk_manager.transfers()
+    }
+
+    pub fn transfer(&self, id: &str) -> Option<TransferInfo> {
+        self.state.chunk_manager.transfer(id)
+    }
+
+    /// Starts downloading `source`, an http(s) URL or a file id shared by a
+    /// peer, and returns the transfer id. Relative output paths go in the
+    /// download directory. Progress and completion arrive as events.
+    pub async fn download(&self, source: &str, output_path: impl AsRef<Path>, overwrite: bool) -> Result<String> {
+        let output_path = output_path.as_ref().to_string_lossy().into_owned();
+        // So the transfer is logged under this node.
+        let started = if is_fetchable(source) {
+            let request = DownloadRequest { url: source.to_string(), output_path, overwrite };
+            webserver::download(self.state.clone(), request).instrument(self.span.clone()).await
+        } else {
+            let request = TransferRequest { file_id: source.to_string(), output_path, peers: Vec::new(), overwrite };
+            webserver::transfer(self.state.clone(), request).instrument(self.span.clone()).await
+        };
+        started.map(|r| r.transfer_id)
+    }
+
+    /// Shares a local file, or a directory as a collection, inside the share
+    /// roots. Peers download it by the manifest's file id.
+    pub async fn share(&self, path: impl AsRef<Path>) -> Result<Manifest> {
+        let request = ShareRequest { path: path.as_ref().to_string_lossy().into_owned(), ..Default::default() };
+        webserver::share(&self.state, request).await
+    }
+
+    /// Offers local files and directories to a connected peer. Its answer
+    /// and the upload progress arrive as events.
+    pub async fn offer(&self, peer: &str, paths: &[impl AsRef<Path>]) -> Result<OfferInfo> {
+        let paths = paths.iter().map(|p| p.as_ref().to_string_lossy().into_owned()).collect();
+        webserver::send_offer(&self.state, OfferRequest { peer: peer.to_string(), paths }).await
+    }
+
+    /// Offers sent and received, answered or not.
+    pub fn offers(&self) -> Vec<OfferInfo> {
+        self.state.offers.list()
+    }
+
+    /// Accepts an incoming offer and starts downloading it into `output_dir`,
+    /// relative to the download directory unless absolute.
+    pub async fn accept_offer(&self, offer_id: &str, output_dir: impl AsRef<Path>, overwrite: bool) -> Result<OfferInfo> {
+        let output_dir = output_dir.as_ref().to_string_lossy().into_owned();
+        let request = AcceptOfferRequest { output_dir, overwrite };
+        webserver::accept_offer(self.state.clone(), offer_id, request).instrument(self.span.clone()).await
+    }
+
+    pub fn decline_offer(&self, offer_id: &str) -> Result<()> {
+        webserver::decline_offer(&self.state, offer_id)
+    }
+
+    /// Stops a download or a share. Returns false if there was no such transfer.
+    pub fn cancel(&self, id: &str) -> bool {
+        self.state.chunk_manager.remove_transfer(id)
+    }
+}
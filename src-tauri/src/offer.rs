@@ -0,0 +1,169 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use uuid::Uuid;
+use crate::collection::validate_manifest;
+use crate::events::Event;
+use crate::protocol::OfferedFile;
+use crate::sandbox::relative_path;
+use crate::state::AppState;
+
+/// Most files and directories one offer may hold, in either direction.
+pub const MAX_OFFER_FILES: usize = 10_000;
+/// Incoming offers waiting for an answer before new ones are declined unseen.
+const MAX_PENDING_OFFERS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OfferDirection {
+    Incoming,
+    Outgoing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OfferStatus {
+    /// Waiting for the receiving user to accept or decline.
+    Pending,
+    Accepted,
+    Declined,
+    Completed,
+    /// A download of an accepted offer gave up (receiving side only).
+    Failed,
+}
+
+/// Files one device proposed to send to another, as seen by either side.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OfferInfo {
+    pub offer_id: String,
+    /// Who sent an incoming offer, or who an outgoing one is for.
+    pub peer: String,
+    pub direction: OfferDirection,
+    pub files: Vec<OfferedFile>,
+    pub total_size: u64,
+    /// Bytes sent (outgoing) or received (incoming) so far.
+    pub completed_bytes: u64,
+    pub status: OfferStatus,
+}
+
+struct Offer {
+    info: OfferInfo,
+    /// Chunks already counted towards `completed_bytes`, so endgame duplicates aren't.
+    counted: HashSet<(String, u64)>,
+}
+
+/// Offers sent and received since the node started.
+#[derive(Default)]
+pub struct Offers {
+    offers: Mutex<HashMap<String, Offer>>,
+}
+
+impl Offers {
+    /// Records an offer we are about to send to `peer`.
+    pub fn create(&self, peer: &str, files: Vec<OfferedFile>) -> OfferInfo {
+        let offer_id = Uuid::new_v4().to_string();
+        self.insert(offer_id, peer, OfferDirection::Outgoing, files)
+    }
+
+    /// Records an offer from `peer`. `None` if the id is already taken or too
+    /// many offers are waiting for an answer.
+    pub fn receive(&self, offer_id: String, peer: &str, files: Vec<OfferedFile>) -> Option<OfferInfo> {
+        {
+            let offers = self.offers.lock().unwrap();
+            let pending = offers
+                .values()
+                .filter(|o| o.info.direction == OfferDirection::Incoming && o.info.status == OfferStatus::Pending)
+                .count();
+            if offers.contains_key(&offer_id) || pending >= MAX_PENDING_OFFERS {
+                return None;
+            }
+        }
+        Some(self.insert(offer_id, peer, OfferDirection::Incoming, files))
+    }
+
+    fn insert(&self, offer_id: String, peer: &str, direction: OfferDirection, files: Vec<OfferedFile>) -> OfferInfo {
+        let info = OfferInfo {
+            offer_id: offer_id.clone(),
+            peer: peer.to_string(),
+            direction,
+            total_size: files.iter().map(|f| f.manifest.total_size).sum(),
+            files,
+            completed_bytes: 0,
+            status: OfferStatus::Pending,
+        };
+        let offer = Offer { info: info.clone(), counted: HashSet::new() };
+        self.offers.lock().unwrap().insert(offer_id, offer);
+        info
+    }
+
+    pub fn list(&self) -> Vec<OfferInfo> {
+        self.offers.lock().unwrap().values().map(|o| o.info.clone()).collect()
+    }
+
+    pub fn get(&self, offer_id: &str) -> Option<OfferInfo> {
+        self.offers.lock().unwrap().get(offer_id).map(|o| o.info.clone())
+    }
+
+    /// Moves an offer to `status`. An accepted offer with nothing to send is
+    /// completed straight away.
+    pub fn set_status(&self, offer_id: &str, status: OfferStatus) -> Option<OfferInfo> {
+        let mut offers = self.offers.lock().unwrap();
+        let info = &mut offers.get_mut(offer_id)?.info;
+        info.status = status;
+        if status == OfferStatus::Accepted && info.total_size == 0 {
+            info.status = OfferStatus::Completed;
+        }
+        Some(info.clone())
+    }
+
+    pub fn remove(&self, offer_id: &str) -> Option<OfferInfo> {
+        self.offers.lock().unwrap().remove(offer_id).map(|o| o.info)
+    }
+
+    /// Counts a chunk of `file_id` sent to or received from `peer` towards the
+    /// accepted offer it belongs to. Returns the offer if its progress changed.
+    pub fn record_chunk(&self, peer: &str, file_id: &str, index: u64, bytes: u64) -> Option<OfferInfo> {
+        let mut offers = self.offers.lock().unwrap();
+        let offer = offers.values_mut().find(|o| {
+            o.info.peer == peer
+                && o.info.status == OfferStatus::Accepted
+                && o.info.files.iter().any(|f| f.manifest.file_id == file_id)
+        })?;
+        if !offer.counted.insert((file_id.to_string(), index)) {
+            return None;
+        }
+        let info = &mut offer.info;
+        info.completed_bytes += bytes;
+        if info.completed_bytes >= info.total_size {
+            info.status = OfferStatus::Completed;
+        }
+        Some(info.clone())
+    }
+}
+
+/// Whether an offer from a peer can be saved as is: at most
+/// `MAX_OFFER_FILES` entries, each a valid manifest at a distinct path
+/// inside the target directory.
+pub fn valid_offer(files: &[OfferedFile]) -> bool {
+    let mut paths = HashSet::new();
+    !files.is_empty()
+        && files.len() <= MAX_OFFER_FILES
+        && files.iter().all(|f| {
+            validate_manifest(&f.manifest).is_ok() && relative_path(&f.path).is_some_and(|p| paths.insert(p))
+        })
+}
+
+/// Updates offer progress for a chunk that went to or came from `peer`, and
+/// publishes the progress and completion events.
+pub fn record_offer_chunk(state: &AppState, peer: &str, file_id: &str, index: u64, bytes: u64) {
+    let Some(offer) = state.offers.record_chunk(peer, file_id, index, bytes) else { return };
+    let offer_id = offer.offer_id.clone();
+    state.events.publish(Event::OfferProgress {
+        offer_id: offer_id.clone(),
+        completed_bytes: offer.completed_bytes,
+        total_size: offer.total_size,
+    });
+    if offer.status == OfferStatus::Completed {
+        state.events.publish(Event::OfferCompleted { offer_id });
+    }
+}
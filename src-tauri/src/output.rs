@@ -0,0 +1,213 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Most output files one download keeps open at once. A collection of many
+/// small files reopens the ones that fell out when it needs them again.
+const MAX_OPEN_FILES: usize = 64;
+
+/// Where a download is written until every chunk of it has arrived.
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+/// The `.part` files of a download, kept open between chunks and written
+/// at explicit offsets, so concurrent chunks never share a file position.
+/// Everything here blocks, so it runs on the blocking pool.
+#[derive(Debug, Default)]
+pub struct OutputFiles {
+    open: Mutex<HashMap<PathBuf, Arc<File>>>,
+    /// Files `finish` already gave their final names, on an attempt that
+    /// failed on a later file. Their data is written in place from then on.
+    renamed: Mutex<HashSet<PathBuf>>,
+}
+
+impl OutputFiles {
+    /// Writes `data` at `offset` into the `.part` file for `path`, which is
+    /// created and preallocated to `size` bytes the first time.
+    pub fn write_at(&self, path: &Path, size: u64, offset: u64, data: &[u8]) -> io::Result<()> {
+        let file = self.file(path, size)?;
+        write_all_at(&file, data, offset)
+    }
+
+    fn file(&self, path: &Path, size: u64) -> io::Result<Arc<File>> {
+        let mut open = self.open.lock().unwrap();
+        if let Some(file) = open.get(path) {
+            return Ok(file.clone());
+        }
+        let part = if self.renamed.lock().unwrap().contains(path) { path.to_path_buf() } else { part_path(path) };
+        if fs::symlink_metadata(&part).is_ok_and(|m| m.file_type().is_symlink()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is a symlink", part.display())));
+        }
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&part)?;
+        // A `.part` left by an earlier attempt is reused, cut or grown to size.
+        if file.metadata()?.len() != size {
+            preallocate(&file, size)?;
+        }
+        if open.len() >= MAX_OPEN_FILES {
+            open.clear();
+        }
+        let file = Arc::new(file);
+        open.insert(path.to_path_buf(), file.clone());
+        Ok(file)
+    }
+
+    /// Creates the empty files of a download under their final names, since
+    /// no chunk ever writes them. An existing file is emptied, but a
+    /// symlink is refused.
+    pub fn create_empty(&self, paths: &[PathBuf]) -> io::Result<()> {
+        for path in paths {
+            if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink()) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is a symlink", path.display())));
+            }
+            File::create(path)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the `.part` files of `paths` that exist to disk.
+    pub fn sync(&self, paths: &[PathBuf]) {
+        for path in paths {
+            let open = self.open.lock().unwrap().get(path).cloned();
+            let file = match open {
+                Some(file) => file,
+                None => match File::open(part_path(path)) {
+                    Ok(file) => Arc::new(file),
+                    Err(_) => continue,
+                },
+            };
+            let _ = file.sync_all();
+        }
+    }
+
+    /// Every chunk has arrived: flushes each `.part` file, closes it and
+    /// renames it to `path`, then makes the renames durable. Files renamed
+    /// by an earlier call that failed part way are only flushed, so calling
+    /// it again after an error is safe.
+    pub fn finish(&self, paths: &[PathBuf]) -> io::Result<()> {
+        let mut dirs = HashSet::new();
+        for path in paths {
+            dirs.extend(path.parent().map(Path::to_path_buf));
+            let part = part_path(path);
+            let open = self.open.lock().unwrap().remove(path);
+            if self.renamed.lock().unwrap().contains(path) {
+                if let Some(file) = open {
+                    file.sync_all()?;
+                }
+                continue;
+            }
+            match open {
+                Some(file) => file.sync_all()?,
+                None => File::open(&part)?.sync_all()?,
+            }
+            fs::rename(&part, path)?;
+            self.renamed.lock().unwrap().insert(path.clone());
+        }
+        for dir in dirs {
+            sync_dir(&dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reserves `size` bytes for the file up front, so a full disk shows up
+/// when the download starts rather than halfway through. Where that isn't
+/// supported the file is only extended, and stays sparse.
+fn preallocate(file: &File, size: u64) -> io::Result<()> {
+    file.set_len(size)?;
+    #[cfg(target_os = "linux")]
+    if size > 0 {
+        use std::os::unix::io::AsRawFd;
+        let len = libc::off_t::try_from(size).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        // SAFETY: the descriptor stays open while `file` is borrowed.
+        if unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len) } != 0 {
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                return Err(error);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, data, offset)
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut data: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !data.is_empty() {
+        match file.seek_write(data, offset) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                data = &data[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Flushes a directory's entries, so a rename in it survives a crash.
+/// Windows has no such thing and needs none.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finish_can_be_retried() {
+        let dir = std::env::temp_dir().join(format!("peapod_output_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        let outputs = OutputFiles::default();
+        outputs.write_at(&a, 3, 0, b"aaa").unwrap();
+
+        // `b` has no `.part` yet, so the first attempt fails after renaming `a`.
+        assert!(outputs.finish(&[a.clone(), b.clone()]).is_err());
+        assert_eq!(fs::read(&a).unwrap(), b"aaa");
+        assert!(!part_path(&a).exists());
+
+        // Writes to a renamed file land in it, not in a new `.part`.
+        outputs.write_at(&a, 3, 1, b"x").unwrap();
+        outputs.write_at(&b, 2, 0, b"bb").unwrap();
+        outputs.finish(&[a.clone(), b.clone()]).unwrap();
+        assert_eq!(fs::read(&a).unwrap(), b"axa");
+        assert_eq!(fs::read(&b).unwrap(), b"bb");
+        assert!(!part_path(&a).exists() && !part_path(&b).exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_symlinked_files() {
+        let dir = std::env::temp_dir().join(format!("peapod_output_link_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("target");
+        fs::write(&target, b"keep").unwrap();
+        std::os::unix::fs::symlink(&target, part_path(&dir.join("a"))).unwrap();
+        std::os::unix::fs::symlink(&target, dir.join("empty")).unwrap();
+        let outputs = OutputFiles::default();
+        assert!(outputs.write_at(&dir.join("a"), 4, 0, b"gone").is_err());
+        assert!(outputs.create_empty(&[dir.join("empty")]).is_err());
+        assert_eq!(fs::read(&target).unwrap(), b"keep");
+    }
+}
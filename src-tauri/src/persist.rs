@@ -0,0 +1,32 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Reads a JSON file from the data directory. A missing file, or no path
+/// at all, gives the default value.
+pub fn load_json<T: DeserializeOwned + Default>(path: Option<&Path>) -> io::Result<T> {
+    let Some(path) = path else { return Ok(T::default()) };
+    match fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Serializes `value` for `write_file`, typically while its lock is held.
+pub fn to_json<T: Serialize + ?Sized>(value: &T) -> io::Result<String> {
+    serde_json::to_string(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it over
+/// `path`, so a crash never leaves a half-written file behind.
+pub fn write_file(path: &Path, contents: &str) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(tmp, path)
+}
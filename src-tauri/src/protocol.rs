@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use crate::bitfield::Bitfield;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Message {
@@ -6,7 +7,117 @@ pub enum Message {
     Ping,
     Pong,
     RequestChunk { file_id: String, index: u64 },
+    /// Withdraws a `RequestChunk` that another peer already answered.
+    CancelChunk { file_id: String, index: u64 },
+    /// The request won't be served (quota used up, or the fairness policy
+    /// chokes the requester). Ask someone else.
+    Choked { file_id: String, index: u64 },
     ChunkData { file_id: String, index: u64, data: Vec<u8> },
+    /// A `ChunkData` whose `len` bytes of data follow the frame raw instead
+    /// of inside it. Only sent to peers whose handshake has version 2 or later.
+    RawData { file_id: String, index: u64, len: u64 },
+    RequestManifest { file_id: String },
+    Manifest(Manifest),
+    /// Asks the peer to download bytes `[start, end)` of `url` from the
+    /// internet for us. Answered with `ChunkData`, `FetchFailed` or `Choked`.
+    /// With `etag`, the bytes must come from that version of the URL, which
+    /// lets a peer serve them from its pod cache.
+    FetchRange {
+        file_id: String,
+        index: u64,
+        url: String,
+        start: u64,
+        end: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        etag: Option<String>,
+    },
+    FetchFailed { file_id: String, index: u64, reason: String },
+    /// Why a request failed. `file_id` and `index` name the manifest or
+    /// chunk it was about; with neither, the connection is being refused.
+    Error {
+        code: ErrorCode,
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        file_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        index: Option<u64>,
+    },
+    /// Proposes sending files to the peer. Its user accepts or declines,
+    /// and on accepting it pulls the files' chunks like any shared file.
+    Offer { offer_id: String, files: Vec<OfferedFile> },
+    OfferReply { offer_id: String, accepted: bool },
+    /// We are downloading `file_id`. A peer holding any of it answers with
+    /// `HaveAll` or a `Bitfield` of the chunks it has, then a `Have` for
+    /// every chunk it completes later. Peers without the file stay silent.
+    Interested { file_id: String },
+    Bitfield { file_id: String, chunks: Bitfield },
+    Have { file_id: String, index: u64 },
+    HaveAll { file_id: String },
+    /// We are downloading `url` in chunks of `chunk_size`. A peer whose pod
+    /// cache holds any of them answers with a `Bitfield`, and is asked for
+    /// those chunks first. Peers without them stay silent.
+    CacheQuery { file_id: String, url: String, etag: Option<String>, total_size: u64, chunk_size: u64 },
+    /// Sent before closing the connection on shutdown.
+    Goodbye,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// We don't have a file with this id.
+    UnknownFile,
+    /// The chunk index is outside the file.
+    OutOfRange,
+    /// The requester is blocked, or not paired while we only talk to paired devices.
+    NotPermitted,
+    /// We have the file but not this chunk yet, or the request can't be served right now.
+    Unavailable,
+    Internal,
+}
+
+/// Describes how a file is split, so both sides agree on chunk boundaries.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub file_id: String,
+    pub file_name: String,
+    pub total_size: u64,
+    pub chunk_size: u64,
+    /// Set when `file_id` is a directory tree rather than a single file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collection: Option<Collection>,
+    /// Hex SHA-256 of each chunk, in order. Empty for downloads from a URL,
+    /// whose chunks can't be checked.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunk_hashes: Vec<String>,
+}
+
+/// The files of a directory tree, sent as one byte stream: the files'
+/// contents back to back, in the order listed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Collection {
+    pub files: Vec<CollectionFile>,
+    /// Directories with nothing in them, which no file path would recreate.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub empty_dirs: Vec<String>,
+    /// Every file starts a new chunk, so no chunk spans two files. Otherwise
+    /// chunks run across file boundaries and small files share chunks.
+    #[serde(default)]
+    pub aligned: bool,
+}
+
+/// `path` is `/`-separated and relative to the collection's root.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollectionFile {
+    pub path: String,
+    pub size: u64,
+}
+
+/// One file of an `Offer`. `path` is `/`-separated and relative to wherever
+/// the receiver saves the offer, so directories keep their layout.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OfferedFile {
+    pub path: String,
+    pub manifest: Manifest,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -14,4 +125,10 @@ pub struct Beacon {
     pub device_id: String,
     pub name: String,
     pub port: u16,
+    /// Set when this node has used up its upload quota and won't serve chunks.
+    #[serde(default)]
+    pub out_of_quota: bool,
+    /// The last beacon of a node that is shutting down.
+    #[serde(default)]
+    pub leaving: bool,
 }
@@ -0,0 +1,255 @@
+use axum::body::{Body, Bytes};
+use hyper::body::Incoming;
+use hyper::header::{self, HeaderMap, HeaderName};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use reqwest::redirect::Policy;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, info, warn, Instrument};
+use uuid::Uuid;
+use crate::chunk::MAX_TRANSFER_SIZE;
+use crate::error::Error;
+use crate::fetch::{start_url_download, UrlInfo};
+use crate::output::part_path;
+use crate::state::AppState;
+
+pub const DEFAULT_PROXY_PORT: u16 = 8888;
+/// How often the response stream checks whether the next chunk has arrived.
+const CHUNK_POLL: Duration = Duration::from_millis(50);
+
+/// Headers that only apply to a single connection and must not be forwarded.
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProxyConfig {
+    pub enabled: bool,
+    pub bind: IpAddr,
+    pub port: u16,
+    /// Smaller responses are passed through instead of being split across the swarm.
+    pub min_size: u64,
+    /// Fetch internet ranges for peers that ask. Off by default: it spends
+    /// this node's bandwidth and makes requests from its network.
+    pub serve_peers: bool,
+    /// Let those fetches reach loopback and private addresses. Only for
+    /// trying things out on one machine.
+    pub serve_private: bool,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: DEFAULT_PROXY_PORT,
+            min_size: 8 * 1024 * 1024,
+            serve_peers: false,
+            serve_private: false,
+        }
+    }
+}
+
+/// Opens the proxy's socket. It has no authentication and can reach this
+/// machine's loopback services, so only loopback addresses are allowed.
+pub async fn bind_proxy(config: &ProxyConfig) -> crate::Result<TcpListener> {
+    if !config.bind.is_loopback() {
+        let message = format!("refusing to expose the HTTP proxy on {}; it can only bind to loopback", config.bind);
+        return Err(Error::Forbidden(message));
+    }
+    let addr = SocketAddr::new(config.bind, config.port);
+    TcpListener::bind(&addr).await.map_err(|source| Error::Bind { service: "the HTTP proxy", addr: addr.to_string(), source })
+}
+
+/// HTTP proxy for browsers and other tools. Large, cacheable downloads are
+/// split into ranges and fetched through the swarm; everything else,
+/// including HTTPS (`CONNECT`), is passed straight through.
+pub async fn run_proxy(listener: TcpListener, config: ProxyConfig, state: Arc<AppState>) {
+    if let Ok(addr) = listener.local_addr() {
+        info!(%addr, "HTTP proxy running");
+    }
+
+    // The client must see redirects itself, or relative URLs in the page break.
+    // Nor may it go through the environment's proxy, which may well be this one.
+    let client = Client::builder().redirect(Policy::none()).no_proxy().build().unwrap_or_default();
+    let config = Arc::new(config);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = state.shutdown.cancelled() => break,
+        };
+        let (socket, _) = match accepted {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!(error = %e, "Failed to accept proxy connection");
+                continue;
+            }
+        };
+        let (state, config, client) = (state.clone(), config.clone(), client.clone());
+        tokio::spawn(async move {
+            let service = service_fn(move |req| handle(req, state.clone(), config.clone(), client.clone()));
+            let conn = http1::Builder::new()
+                .preserve_header_case(true)
+                .title_case_headers(true)
+                .serve_connection(TokioIo::new(socket), service)
+                .with_upgrades();
+            if let Err(e) = conn.await {
+                debug!(error = %e, "Proxy connection error");
+            }
+        }.in_current_span());
+    }
+}
+
+async fn handle(req: Request<Incoming>, state: Arc<AppState>, config: Arc<ProxyConfig>, client: Client) -> Result<Response<Body>, Infallible> {
+    if req.method() == Method::CONNECT {
+        return Ok(tunnel(req).await);
+    }
+    if req.uri().scheme().is_none() || req.uri().host().is_none() {
+        return Ok(error(StatusCode::BAD_REQUEST, "PeaPod proxy expects absolute URLs".into()));
+    }
+
+    // Personalised or partial responses can't be fetched by other peers.
+    let splittable = req.method() == Method::GET
+        && ![header::AUTHORIZATION, header::COOKIE, header::RANGE].iter().any(|h| req.headers().contains_key(h));
+
+    let (parts, body) = req.into_parts();
+    let url = parts.uri.to_string();
+    let mut headers = parts.headers;
+    strip_hop_by_hop(&mut headers);
+    headers.remove(header::HOST);
+
+    let upstream = client
+        .request(parts.method, &url)
+        .headers(headers)
+        .body(reqwest::Body::wrap_stream(Body::new(body).into_data_stream()))
+        .send()
+        .await;
+    let upstream = match upstream {
+        Ok(r) => r,
+        Err(e) => return Ok(error(StatusCode::BAD_GATEWAY, e.to_string())),
+    };
+
+    if splittable && upstream.status() == StatusCode::OK && !upstream.headers().contains_key(header::CONTENT_ENCODING) {
+        let info = UrlInfo::from_headers(upstream.headers());
+        if let Some(info) = info.filter(|i| i.accepts_ranges && (config.min_size..=MAX_TRANSFER_SIZE).contains(&i.total_size)) {
+            info!(%url, bytes = info.total_size, "Fetching through the swarm");
+            let mut headers = upstream.headers().clone();
+            strip_hop_by_hop(&mut headers);
+            drop(upstream);
+            return Ok(swarm_response(state, url, &info, headers));
+        }
+    }
+
+    let mut response = Response::builder().status(upstream.status());
+    if let Some(out) = response.headers_mut() {
+        *out = upstream.headers().clone();
+        strip_hop_by_hop(out);
+    }
+    Ok(response.body(Body::from_stream(upstream.bytes_stream())).unwrap_or_else(|e| error(StatusCode::BAD_GATEWAY, e.to_string())))
+}
+
+/// Starts a swarm download of `url` into a temporary file and streams it to
+/// the client chunk by chunk, in order, as the chunks arrive.
+fn swarm_response(state: Arc<AppState>, url: String, info: &UrlInfo, headers: HeaderMap) -> Response<Body> {
+    let path = std::env::temp_dir().join(format!("peapod-proxy-{}", Uuid::new_v4()));
+    let (file_id, download) = match start_url_download(state.clone(), url, info, path.to_string_lossy().into_owned()) {
+        Ok(started) => started,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+    let chunk_size = state.chunk_manager.chunk_size(&file_id).unwrap_or(info.total_size).max(1);
+    let chunks = info.total_size.div_ceil(chunk_size);
+
+    let (tx, rx) = mpsc::channel::<io::Result<Bytes>>(2);
+    tokio::spawn(async move {
+        stream_chunks(&state, &file_id, chunks, &download, &tx).await;
+        state.chunk_manager.remove_transfer(&file_id);
+        let _ = tokio::fs::remove_file(&path).await;
+        let _ = tokio::fs::remove_file(part_path(&path)).await;
+    }.in_current_span());
+
+    let mut response = Response::new(Body::from_stream(ReceiverStream::new(rx)));
+    *response.headers_mut() = headers;
+    response
+}
+
+async fn stream_chunks(state: &AppState, file_id: &str, chunks: u64, download: &JoinHandle<bool>, tx: &mpsc::Sender<io::Result<Bytes>>) {
+    for index in 0..chunks {
+        while !state.chunk_manager.is_chunk_completed(file_id, index) {
+            if tx.is_closed() {
+                return;
+            }
+            if download.is_finished() {
+                let _ = tx.send(Err(io::Error::other("swarm download failed"))).await;
+                return;
+            }
+            tokio::time::sleep(CHUNK_POLL).await;
+        }
+        let data = match state.chunk_manager.read_chunk(file_id, index).await {
+            Ok(data) => data,
+            Err(e) => {
+                let _ = tx.send(Err(io::Error::other(e))).await;
+                return;
+            }
+        };
+        if tx.send(Ok(Bytes::from(data))).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Connects to the `CONNECT` target and relays bytes both ways once the client upgrades.
+async fn tunnel(req: Request<Incoming>) -> Response<Body> {
+    let Some(addr) = req.uri().authority().map(|a| a.to_string()) else {
+        return error(StatusCode::BAD_REQUEST, "CONNECT needs host:port".into());
+    };
+    let mut server = match TcpStream::connect(&addr).await {
+        Ok(s) => s,
+        Err(e) => return error(StatusCode::BAD_GATEWAY, e.to_string()),
+    };
+    tokio::spawn(async move {
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) => {
+                let mut client = TokioIo::new(upgraded);
+                if let Err(e) = tokio::io::copy_bidirectional(&mut client, &mut server).await {
+                    debug!(%addr, error = %e, "Proxy tunnel closed");
+                }
+            }
+            Err(e) => warn!(%addr, error = %e, "Proxy tunnel failed"),
+        }
+    }.in_current_span());
+    Response::new(Body::empty())
+}
+
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    for name in HOP_BY_HOP {
+        headers.remove(HeaderName::from_static(name));
+    }
+}
+
+fn error(status: StatusCode, message: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(message));
+    *response.status_mut() = status;
+    response
+}
@@ -0,0 +1,264 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Bandwidth caps in bytes per second. `0` means unlimited.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    /// Total upload to all peers.
+    pub upload: u64,
+    /// Total download from all peers.
+    pub download: u64,
+    /// Internet fetches made on behalf of other peers.
+    pub proxy: u64,
+    /// Default per-peer caps, unless overridden in `peers`.
+    pub peer_upload: u64,
+    pub peer_download: u64,
+    /// Per-peer overrides, keyed by device id.
+    pub peers: HashMap<String, PeerLimits>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PeerLimits {
+    pub upload: u64,
+    pub download: u64,
+}
+
+struct BucketState {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl BucketState {
+    /// Adds the tokens earned since the last call, up to one second's worth.
+    fn refill(&mut self, now: Instant) {
+        let rate = self.rate as f64;
+        self.tokens = (self.tokens + now.saturating_duration_since(self.last).as_secs_f64() * rate).min(rate);
+        self.last = now;
+    }
+
+    /// Takes `bytes` out, returning how long the caller has to wait for them.
+    fn take(&mut self, bytes: u64, now: Instant) -> Option<Duration> {
+        if self.rate == 0 {
+            return None;
+        }
+        self.refill(now);
+        self.tokens -= bytes as f64;
+        (self.tokens < 0.0).then(|| Duration::from_secs_f64(-self.tokens / self.rate as f64))
+    }
+}
+
+/// Token bucket holding up to one second of `rate`. A request larger than the
+/// bucket goes through at once and leaves a debt that later callers wait out.
+pub struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            state: Mutex::new(BucketState { rate, tokens: rate as f64, last: Instant::now() }),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.state.lock().unwrap().rate
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.rate != rate {
+            state.rate = rate;
+            state.tokens = rate as f64;
+            state.last = Instant::now();
+        }
+    }
+
+    /// Waits until `bytes` may pass.
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = self.state.lock().unwrap().take(bytes, Instant::now());
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Whether the bucket has refilled completely, so a new one would behave the same.
+    fn is_full(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.refill(Instant::now());
+        state.rate == 0 || state.tokens >= state.rate as f64
+    }
+}
+
+struct PeerBuckets {
+    upload: TokenBucket,
+    download: TokenBucket,
+    /// The peer disconnected since it last used the buckets.
+    left: AtomicBool,
+}
+
+/// Global, per-peer and proxy token buckets. Limits can be changed at runtime.
+pub struct RateLimiter {
+    limits: Mutex<Limits>,
+    upload: TokenBucket,
+    download: TokenBucket,
+    proxy: TokenBucket,
+    peers: Mutex<HashMap<String, Arc<PeerBuckets>>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(Limits::default())
+    }
+}
+
+impl RateLimiter {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            upload: TokenBucket::new(limits.upload),
+            download: TokenBucket::new(limits.download),
+            proxy: TokenBucket::new(limits.proxy),
+            limits: Mutex::new(limits),
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits.lock().unwrap().clone()
+    }
+
+    pub fn set_limits(&self, limits: Limits) {
+        self.upload.set_rate(limits.upload);
+        self.download.set_rate(limits.download);
+        self.proxy.set_rate(limits.proxy);
+        for (peer_id, buckets) in self.peers.lock().unwrap().iter() {
+            let peer = peer_limits(&limits, peer_id);
+            buckets.upload.set_rate(peer.upload);
+            buckets.download.set_rate(peer.download);
+        }
+        *self.limits.lock().unwrap() = limits;
+    }
+
+    fn peer(&self, peer_id: &str) -> Arc<PeerBuckets> {
+        let mut peers = self.peers.lock().unwrap();
+        let buckets = peers
+            .entry(peer_id.to_string())
+            .or_insert_with(|| {
+                let limits = peer_limits(&self.limits.lock().unwrap(), peer_id);
+                Arc::new(PeerBuckets {
+                    upload: TokenBucket::new(limits.upload),
+                    download: TokenBucket::new(limits.download),
+                    left: AtomicBool::new(false),
+                })
+            })
+            .clone();
+        buckets.left.store(false, Ordering::Relaxed);
+        buckets
+    }
+
+    pub async fn acquire_upload(&self, peer_id: &str, bytes: u64) {
+        self.upload.acquire(bytes).await;
+        self.peer(peer_id).upload.acquire(bytes).await;
+    }
+
+    pub async fn acquire_download(&self, peer_id: &str, bytes: u64) {
+        self.download.acquire(bytes).await;
+        self.peer(peer_id).download.acquire(bytes).await;
+    }
+
+    pub async fn acquire_proxy(&self, bytes: u64) {
+        self.proxy.acquire(bytes).await;
+    }
+
+    /// Forgets the buckets of peers that have left, once they have refilled.
+    /// Until then a peer that reconnects finds its debt still there, so it
+    /// can't get round its cap by reconnecting.
+    pub fn peer_disconnected(&self, peer_id: &str) {
+        let mut peers = self.peers.lock().unwrap();
+        if let Some(buckets) = peers.get(peer_id) {
+            buckets.left.store(true, Ordering::Relaxed);
+        }
+        peers.retain(|_, b| !(b.left.load(Ordering::Relaxed) && b.upload.is_full() && b.download.is_full()));
+    }
+}
+
+fn peer_limits(limits: &Limits, peer_id: &str) -> PeerLimits {
+    limits.peers.get(peer_id).cloned().unwrap_or(PeerLimits {
+        upload: limits.peer_upload,
+        download: limits.peer_download,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(rate: u64, start: Instant) -> BucketState {
+        BucketState { rate, tokens: rate as f64, last: start }
+    }
+
+    #[test]
+    fn unlimited_never_waits() {
+        let start = Instant::now();
+        assert_eq!(bucket(0, start).take(u64::MAX, start), None);
+    }
+
+    #[test]
+    fn large_request_leaves_a_debt() {
+        let start = Instant::now();
+        let mut state = bucket(1000, start);
+        assert_eq!(state.take(1000, start), None);
+        // Twice the bucket goes through at once; the next caller waits it out.
+        assert_eq!(state.take(2000, start), Some(Duration::from_secs(2)));
+        assert_eq!(state.take(500, start), Some(Duration::from_millis(2500)));
+    }
+
+    #[test]
+    fn refills_up_to_one_second() {
+        let start = Instant::now();
+        let mut state = bucket(1000, start);
+        assert_eq!(state.take(1000, start), None);
+        assert_eq!(state.take(500, start + Duration::from_millis(500)), None);
+        assert_eq!(state.take(1, start + Duration::from_millis(500)), Some(Duration::from_millis(1)));
+        // A long pause earns no more than a full bucket.
+        let later = start + Duration::from_secs(60);
+        assert_eq!(state.take(1000, later), None);
+        assert!(state.take(100, later).is_some());
+    }
+
+    #[test]
+    fn peer_overrides_default_limits() {
+        let mut limits = Limits { peer_upload: 10, peer_download: 20, ..Limits::default() };
+        limits.peers.insert("a".into(), PeerLimits { upload: 1, download: 0 });
+        let a = peer_limits(&limits, "a");
+        assert_eq!((a.upload, a.download), (1, 0));
+        let b = peer_limits(&limits, "b");
+        assert_eq!((b.upload, b.download), (10, 20));
+    }
+
+    #[test]
+    fn reconnecting_keeps_the_debt() {
+        let limits = Limits { peer_upload: 1000, ..Limits::default() };
+        let limiter = RateLimiter::new(limits);
+        limiter.peer("a").upload.state.lock().unwrap().take(5000, Instant::now());
+        limiter.peer("b");
+        limiter.peer_disconnected("a");
+        limiter.peer_disconnected("b");
+        let peers = limiter.peers.lock().unwrap();
+        assert!(peers.contains_key("a"));
+        assert!(!peers.contains_key("b"));
+    }
+
+    #[test]
+    fn set_limits_reaches_existing_peers() {
+        let limiter = RateLimiter::default();
+        limiter.peer("a");
+        limiter.set_limits(Limits { peer_download: 42, ..Limits::default() });
+        assert_eq!(limiter.peer("a").download.rate(), 42);
+    }
+}
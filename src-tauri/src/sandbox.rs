@@ -0,0 +1,186 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FilesConfig {
+    /// Where downloads are saved. Defaults to `<downloads>/PeaPod`.
+    pub download_dir: Option<PathBuf>,
+    /// Directories whose files may be shared, besides the download directory.
+    pub share_roots: Vec<PathBuf>,
+    /// Turns the sandbox off: any readable file may be shared and downloads
+    /// may be written anywhere. Existing files still need `overwrite`.
+    pub allow_any_path: bool,
+}
+
+/// Decides which local paths the API may read from and write to. Only
+/// local callers get here; peers never supply paths, just file ids.
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    download_dir: PathBuf,
+    share_roots: Vec<PathBuf>,
+    allow_any_path: bool,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Self::new(std::env::temp_dir().join("peapod"), Vec::new(), false)
+    }
+}
+
+impl Sandbox {
+    pub fn new(download_dir: PathBuf, share_roots: Vec<PathBuf>, allow_any_path: bool) -> Self {
+        Self { download_dir, share_roots, allow_any_path }
+    }
+
+    pub fn download_dir(&self) -> &Path {
+        &self.download_dir
+    }
+
+    /// Resolves a file to share. Symlinks are followed, and the real file must
+    /// be inside a share root or the download directory.
+    pub fn share_path(&self, path: &Path) -> Result<PathBuf, String> {
+        let real = path.canonicalize().map_err(|e| format!("{}: {}", path.display(), e))?;
+        if !real.is_file() {
+            return Err(format!("{} is not a regular file", path.display()));
+        }
+        self.shareable(path, real)
+    }
+
+    /// Resolves a directory to share as a collection, like `share_path`.
+    pub fn share_dir(&self, path: &Path) -> Result<PathBuf, String> {
+        let real = path.canonicalize().map_err(|e| format!("{}: {}", path.display(), e))?;
+        if !real.is_dir() {
+            return Err(format!("{} is not a directory", path.display()));
+        }
+        self.shareable(path, real)
+    }
+
+    fn shareable(&self, path: &Path, real: PathBuf) -> Result<PathBuf, String> {
+        if self.allow_any_path {
+            return Ok(real);
+        }
+        let inside = self
+            .share_roots
+            .iter()
+            .chain(std::iter::once(&self.download_dir))
+            .filter_map(|root| root.canonicalize().ok())
+            .any(|root| real.starts_with(root));
+        if !inside {
+            return Err(format!("{} is outside the share roots", path.display()));
+        }
+        Ok(real)
+    }
+
+    /// Resolves where a download is written. Relative paths are taken from
+    /// the download directory. The target must not be a symlink, and must not
+    /// exist unless `overwrite` is set. An existing file stays until the
+    /// finished download is renamed over it.
+    pub fn output_path(&self, path: &Path, overwrite: bool) -> Result<PathBuf, String> {
+        let joined = if path.is_absolute() { path.to_path_buf() } else { self.download_dir.join(path) };
+        let (Some(parent), Some(name)) = (joined.parent(), joined.file_name()) else {
+            return Err(format!("{} is not a file path", path.display()));
+        };
+        fs::create_dir_all(&self.download_dir).map_err(|e| format!("{}: {}", self.download_dir.display(), e))?;
+        let parent = parent.canonicalize().map_err(|e| format!("{}: {}", parent.display(), e))?;
+        if !self.allow_any_path {
+            let root = self.download_dir.canonicalize().map_err(|e| format!("{}: {}", self.download_dir.display(), e))?;
+            if !parent.starts_with(&root) {
+                return Err(format!("{} is outside the download directory {}", path.display(), root.display()));
+            }
+        }
+
+        let target = parent.join(name);
+        match fs::symlink_metadata(&target) {
+            Ok(meta) if meta.file_type().is_symlink() => Err(format!("{} is a symlink", target.display())),
+            Ok(meta) if !meta.is_file() => Err(format!("{} is not a regular file", target.display())),
+            Ok(_) if !overwrite => Err(format!("{} already exists", target.display())),
+            _ => Ok(target),
+        }
+    }
+
+    /// Resolves a directory that several downloads are saved into, creating
+    /// it. Relative paths are taken from the download directory.
+    pub fn output_dir(&self, path: &Path) -> Result<PathBuf, String> {
+        fs::create_dir_all(&self.download_dir).map_err(|e| format!("{}: {}", self.download_dir.display(), e))?;
+        let root = self.download_dir.canonicalize().map_err(|e| format!("{}: {}", self.download_dir.display(), e))?;
+        let joined = root.join(path);
+        let escapes = joined.components().any(|c| c == Component::ParentDir) || !joined.starts_with(&root);
+        if escapes && !self.allow_any_path {
+            return Err(format!("{} is outside the download directory {}", path.display(), root.display()));
+        }
+        fs::create_dir_all(&joined).map_err(|e| format!("{}: {}", joined.display(), e))?;
+        let real = joined.canonicalize().map_err(|e| format!("{}: {}", joined.display(), e))?;
+        if !self.allow_any_path && !real.starts_with(&root) {
+            return Err(format!("{} is outside the download directory {}", path.display(), root.display()));
+        }
+        Ok(real)
+    }
+}
+
+/// Turns a `/`-separated path from a peer into one that stays inside
+/// whatever it is joined to: no root, drive, `..`, `.` or empty parts.
+pub fn relative_path(path: &str) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+    for part in path.split('/') {
+        if matches!(part, "" | "." | "..") || part.contains(['\\', ':', '\0']) {
+            return None;
+        }
+        relative.push(part);
+    }
+    Some(relative)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A sandbox whose download directory is a fresh directory of its own.
+    fn sandbox(name: &str) -> Sandbox {
+        let dir = std::env::temp_dir().join(format!("peapod_sandbox_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Sandbox::new(dir, Vec::new(), false)
+    }
+
+    #[test]
+    fn relative_path_stays_inside() {
+        assert_eq!(relative_path("a/b.txt"), Some(PathBuf::from("a").join("b.txt")));
+        for bad in ["", "/etc/passwd", "a//b", "./a", "a/../b", "..", "a\\b", "C:x", "a\0b", "a/"] {
+            assert_eq!(relative_path(bad), None, "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn output_path_stays_in_download_dir() {
+        let sandbox = sandbox("inside");
+        let root = sandbox.download_dir().canonicalize().unwrap();
+        assert_eq!(sandbox.output_path(Path::new("file.bin"), false).unwrap(), root.join("file.bin"));
+        assert!(sandbox.output_path(Path::new("../file.bin"), false).is_err());
+        assert!(sandbox.output_path(&std::env::temp_dir().join("peapod_outside.bin"), false).is_err());
+        assert!(sandbox.output_path(Path::new("missing/file.bin"), false).is_err());
+        assert!(sandbox.output_path(Path::new(""), false).is_err());
+    }
+
+    #[test]
+    fn output_path_keeps_existing_file() {
+        let sandbox = sandbox("existing");
+        let existing = sandbox.download_dir().join("old.bin");
+        fs::write(&existing, b"old").unwrap();
+        assert!(sandbox.output_path(Path::new("old.bin"), false).is_err());
+        let target = sandbox.output_path(Path::new("old.bin"), true).unwrap();
+        assert_eq!(fs::read(target).unwrap(), b"old");
+        fs::create_dir(sandbox.download_dir().join("dir")).unwrap();
+        assert!(sandbox.output_path(Path::new("dir"), true).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn output_path_refuses_symlinks() {
+        let sandbox = sandbox("symlink");
+        let link = sandbox.download_dir().join("link.bin");
+        std::os::unix::fs::symlink("/etc/hostname", &link).unwrap();
+        assert!(sandbox.output_path(Path::new("link.bin"), true).is_err());
+    }
+}
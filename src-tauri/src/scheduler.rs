@@ -0,0 +1,472 @@
+use serde::Serialize;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::events::Event;
+use crate::fetch::{fetch_chunk_locally, LOCAL_PEER};
+use crate::protocol::Message;
+use crate::state::AppState;
+use crate::swarm::Holding;
+use tracing::{info, instrument, warn, Instrument};
+
+/// Weight of the newest sample in the moving averages.
+const EWMA_ALPHA: f64 = 0.25;
+/// Each peer gets enough requests in flight to keep it busy for this long.
+const TARGET_QUEUE_SECS: f64 = 1.0;
+const MAX_INFLIGHT_PER_PEER: usize = 16;
+/// In endgame mode every outstanding chunk is requested from up to this many peers.
+const ENDGAME_REDUNDANCY: usize = 2;
+const MIN_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const TICK: Duration = Duration::from_millis(100);
+/// A peer that choked us isn't asked again for this long.
+const CHOKE_BACKOFF: Duration = Duration::from_secs(30);
+/// A peer whose internet fetch failed is left alone for this long.
+const FETCH_FAILURE_BACKOFF: Duration = Duration::from_secs(2);
+/// A download gives up after this long without a single chunk arriving,
+/// unless it is paused.
+const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PeerStats {
+    /// Bytes per second, exponentially weighted.
+    pub throughput: f64,
+    /// Round-trip time in seconds, exponentially weighted.
+    pub rtt: f64,
+    pub chunks_received: u64,
+    pub timeouts: u64,
+    /// Internet fetches the peer reported as failed.
+    pub fetch_failures: u64,
+}
+
+impl PeerStats {
+    fn record_throughput(&mut self, sample: f64) {
+        self.throughput = ewma(self.throughput, sample, self.chunks_received == 0);
+        self.chunks_received += 1;
+    }
+
+    fn record_rtt(&mut self, sample: f64) {
+        self.rtt = ewma(self.rtt, sample, self.rtt == 0.0);
+    }
+
+    /// How many chunk requests this peer should have outstanding at once.
+    fn window(&self, chunk_size: u64) -> usize {
+        let chunks = (self.throughput * TARGET_QUEUE_SECS / chunk_size as f64).ceil() as usize;
+        chunks.clamp(1, MAX_INFLIGHT_PER_PEER)
+    }
+
+    fn request_timeout(&self, chunk_size: u64) -> Duration {
+        if self.throughput <= 0.0 {
+            return MIN_REQUEST_TIMEOUT;
+        }
+        let expected = chunk_size as f64 / self.throughput + self.rtt;
+        Duration::from_secs_f64(expected * 4.0).max(MIN_REQUEST_TIMEOUT)
+    }
+}
+
+fn ewma(current: f64, sample: f64, first: bool) -> f64 {
+    if first { sample } else { EWMA_ALPHA * sample + (1.0 - EWMA_ALPHA) * current }
+}
+
+struct Request {
+    peer: String,
+    sent_at: Instant,
+}
+
+#[derive(Default)]
+struct Inner {
+    stats: HashMap<String, PeerStats>,
+    pings: HashMap<String, Instant>,
+    last_delivery: HashMap<String, Instant>,
+    /// Peers that refused our requests, and until when we leave them alone.
+    choked_until: HashMap<String, Instant>,
+    /// Outstanding requests per (file_id, chunk index).
+    inflight: HashMap<(String, u64), Vec<Request>>,
+    /// Breaks ties between equally rare chunks in an order of our own, so
+    /// downloaders starting together don't all fetch the same chunks first.
+    tie_break: RandomState,
+}
+
+/// Which peers can serve which chunks of a download.
+enum Sources {
+    /// Peers serve the chunks they announced holding.
+    Holders(HashMap<String, Holding>),
+    /// Any peer can fetch any chunk from the internet. Chunks a peer has in
+    /// its pod cache are asked of it first, and other peers leave them alone.
+    Internet { cached: HashMap<String, Holding> },
+}
+
+fn holds(holdings: &HashMap<String, Holding>, peer: &str, index: u64) -> bool {
+    holdings.get(peer).is_some_and(|h| h.has(index))
+}
+
+/// Tracks per-peer throughput/RTT and the chunk requests currently in flight,
+/// and decides which peer each chunk of a download is requested from.
+#[derive(Default)]
+pub struct Scheduler {
+    inner: Mutex<Inner>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn peer_stats(&self) -> HashMap<String, PeerStats> {
+        self.inner.lock().unwrap().stats.clone()
+    }
+
+    /// Combined throughput of all measured peers, used as a hint when picking chunk sizes.
+    pub fn total_throughput(&self) -> Option<f64> {
+        let inner = self.inner.lock().unwrap();
+        let total: f64 = inner.stats.values().map(|s| s.throughput).sum();
+        if total > 0.0 { Some(total) } else { None }
+    }
+
+    pub fn record_ping_sent(&self, peer_id: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.pings.insert(peer_id.to_string(), Instant::now());
+    }
+
+    pub fn record_pong(&self, peer_id: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(sent_at) = inner.pings.remove(peer_id) {
+            let rtt = sent_at.elapsed().as_secs_f64();
+            inner.stats.entry(peer_id.to_string()).or_default().record_rtt(rtt);
+        }
+    }
+
+    /// Whether chunk `index` of `file_id` was asked of `peer_id` and hasn't come in yet.
+    pub fn is_requested(&self, peer_id: &str, file_id: &str, index: u64) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.inflight.get(&(file_id.to_string(), index)).is_some_and(|requests| requests.iter().any(|r| r.peer == peer_id))
+    }
+
+    /// Records a delivered chunk and returns the other peers that were asked
+    /// for it (endgame), so their requests can be cancelled.
+    pub fn record_chunk(&self, peer_id: &str, file_id: &str, index: u64, bytes: usize) -> Vec<String> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let Some(requests) = inner.inflight.remove(&(file_id.to_string(), index)) else {
+            return Vec::new();
+        };
+
+        if let Some(req) = requests.iter().find(|r| r.peer == peer_id) {
+            // With several requests pipelined to one peer, only the time since
+            // its previous delivery counts towards this chunk.
+            let started = match inner.last_delivery.get(peer_id) {
+                Some(last) if *last > req.sent_at => *last,
+                _ => req.sent_at,
+            };
+            let elapsed = now.duration_since(started).as_secs_f64().max(1e-3);
+            inner.stats.entry(peer_id.to_string()).or_default().record_throughput(bytes as f64 / elapsed);
+        }
+        inner.last_delivery.insert(peer_id.to_string(), now);
+
+        requests.into_iter().map(|r| r.peer).filter(|p| p != peer_id).collect()
+    }
+
+    /// The peer refused a request; drop it so it goes to someone else.
+    pub fn record_choked(&self, peer_id: &str, file_id: &str, index: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let key = (file_id.to_string(), index);
+        if let Some(requests) = inner.inflight.get_mut(&key) {
+            requests.retain(|r| r.peer != peer_id);
+            if requests.is_empty() { inner.inflight.remove(&key); }
+        }
+        inner.choked_until.insert(peer_id.to_string(), Instant::now() + CHOKE_BACKOFF);
+    }
+
+    /// The peer could not fetch a range from the internet; retry elsewhere.
+    pub fn record_failed(&self, peer_id: &str, file_id: &str, index: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let key = (file_id.to_string(), index);
+        if let Some(requests) = inner.inflight.get_mut(&key) {
+            requests.retain(|r| r.peer != peer_id);
+            if requests.is_empty() { inner.inflight.remove(&key); }
+        }
+        inner.stats.entry(peer_id.to_string()).or_default().fetch_failures += 1;
+        inner.choked_until.insert(peer_id.to_string(), Instant::now() + FETCH_FAILURE_BACKOFF);
+    }
+
+    /// Drops everything requested from a peer that went away so it gets re-requested.
+    pub fn peer_disconnected(&self, peer_id: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.pings.remove(peer_id);
+        inner.last_delivery.remove(peer_id);
+        for requests in inner.inflight.values_mut() {
+            requests.retain(|r| r.peer != peer_id);
+        }
+        inner.inflight.retain(|_, requests| !requests.is_empty());
+    }
+
+    /// Picks the requests to send next for `file_id`. `pending` are the chunk
+    /// indices not yet completed, in order; `peers` are the connected peers.
+    /// From holders, a chunk is only asked of peers that announced it, and
+    /// while any of them is a partial holder the rarest chunks go first.
+    /// From the internet, chunks go in order, cached ones to their cachers.
+    fn plan(&self, file_id: &str, chunk_size: u64, pending: &[u64], peers: &[String], sources: &Sources) -> Vec<(String, u64)> {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+        let now = Instant::now();
+
+        // Expire requests that took far longer than the peer's estimate.
+        let mut expired = Vec::new();
+        for ((fid, _), requests) in inner.inflight.iter_mut() {
+            if fid != file_id { continue; }
+            requests.retain(|r| {
+                let timeout = inner.stats.get(&r.peer).map(|s| s.request_timeout(chunk_size)).unwrap_or(MIN_REQUEST_TIMEOUT);
+                let keep = now.duration_since(r.sent_at) < timeout;
+                if !keep { expired.push(r.peer.clone()); }
+                keep
+            });
+        }
+        inner.inflight.retain(|_, requests| !requests.is_empty());
+        for peer in expired {
+            let stats = inner.stats.entry(peer).or_default();
+            stats.timeouts += 1;
+            stats.throughput /= 2.0;
+        }
+
+        // Spare request slots per peer, fastest first.
+        let mut load: HashMap<&str, usize> = HashMap::new();
+        for requests in inner.inflight.values() {
+            for r in requests {
+                *load.entry(r.peer.as_str()).or_default() += 1;
+            }
+        }
+        inner.choked_until.retain(|_, until| *until > now);
+        let mut slots: Vec<(String, usize, f64)> = peers
+            .iter()
+            .filter(|p| !inner.choked_until.contains_key(*p))
+            .map(|p| {
+                let stats = inner.stats.get(p).cloned().unwrap_or_default();
+                let busy = load.get(p.as_str()).copied().unwrap_or(0);
+                (p.clone(), stats.window(chunk_size).saturating_sub(busy), stats.throughput)
+            })
+            .collect();
+        slots.sort_by(|a, b| b.2.total_cmp(&a.2));
+        let available: Vec<String> = slots.iter().map(|(peer, _, _)| peer.clone()).collect();
+        let has = |peer: &str, index: u64| match sources {
+            Sources::Holders(holdings) => holds(holdings, peer, index),
+            Sources::Internet { cached } => {
+                holds(cached, peer, index) || !available.iter().any(|p| holds(cached, p, index))
+            }
+        };
+
+        let mut plan = Vec::new();
+        let mut unrequested: Vec<u64> = pending
+            .iter()
+            .copied()
+            .filter(|i| !inner.inflight.contains_key(&(file_id.to_string(), *i)))
+            .collect();
+        // With only whole-file holders every chunk is equally common, and
+        // fetching in order lets the file be used while it arrives.
+        let holders: Vec<_> = match sources {
+            Sources::Holders(holdings) => peers.iter().filter_map(|p| holdings.get(p)).collect(),
+            Sources::Internet { .. } => Vec::new(),
+        };
+        if holders.iter().any(|h| !h.all) {
+            let tie_break = &inner.tie_break;
+            unrequested.sort_by_cached_key(|i| {
+                let holders = holders.iter().filter(|h| h.has(*i)).count();
+                (holders, tie_break.hash_one((file_id, *i)))
+            });
+        }
+
+        if !unrequested.is_empty() {
+            let mut left = unrequested;
+            for (peer, free, _) in slots.iter_mut() {
+                while *free > 0 {
+                    let cached = match sources {
+                        Sources::Internet { cached } => left.iter().position(|i| holds(cached, peer, *i)),
+                        Sources::Holders(_) => None,
+                    };
+                    let Some(position) = cached.or_else(|| left.iter().position(|i| has(peer, *i))) else { break };
+                    plan.push((peer.clone(), left.remove(position)));
+                    *free -= 1;
+                }
+            }
+        } else {
+            // Endgame: everything left is already in flight. Ask other peers
+            // with spare capacity too and keep whichever answer arrives first.
+            for index in pending {
+                let asked: Vec<&str> = inner
+                    .inflight
+                    .get(&(file_id.to_string(), *index))
+                    .map(|reqs| reqs.iter().map(|r| r.peer.as_str()).collect())
+                    .unwrap_or_default();
+                let mut copies = asked.len();
+                for (peer, free, _) in slots.iter_mut() {
+                    if copies >= ENDGAME_REDUNDANCY { break; }
+                    if *free == 0 || asked.contains(&peer.as_str()) || !has(peer, *index) { continue; }
+                    plan.push((peer.clone(), *index));
+                    *free -= 1;
+                    copies += 1;
+                }
+            }
+        }
+
+        for (peer, index) in &plan {
+            inner
+                .inflight
+                .entry((file_id.to_string(), *index))
+                .or_default()
+                .push(Request { peer: peer.clone(), sent_at: now });
+        }
+        plan
+    }
+
+    fn forget_file(&self, file_id: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.inflight.retain(|(fid, _), _| fid != file_id);
+    }
+}
+
+/// Records a delivered chunk and cancels the duplicate requests made for it in endgame.
+pub fn complete_request(state: &AppState, peer_id: &str, file_id: &str, index: u64, bytes: usize) {
+    for other in state.scheduler.record_chunk(peer_id, file_id, index, bytes) {
+        state.send_to_peer(&other, Message::CancelChunk { file_id: file_id.to_string(), index });
+    }
+}
+
+/// Pulls every missing chunk of `file_id` from `peers`, giving faster peers
+/// more requests. An empty `peers` means every connected peer, including
+/// ones that connect later, and each chunk is asked of the peers that hold
+/// it. For URL transfers the peers fetch ranges from the internet instead,
+/// and `LOCAL_PEER` stands for this node's own connection. Returns whether
+/// the transfer completed. Peers are also asked which chunks of the URL
+/// their pod caches hold, and those chunks go to them. A download that gets
+/// nothing for `STALL_TIMEOUT` fails.
+#[instrument(name = "transfer", skip_all, fields(%file_id))]
+pub async fn run_download(state: Arc<AppState>, file_id: String, peers: Vec<String>) -> bool {
+    info!(peers = peers.len(), "Download started");
+    let url = state.chunk_manager.url(&file_id);
+    let etag = state.chunk_manager.etag(&file_id);
+    let mut own_cache = Holding::default();
+    if let Some(transfer) = state.chunk_manager.transfer(&file_id) {
+        if let Some(url) = &url {
+            own_cache.chunks = state.cache.cached_chunks(url, etag.as_deref(), transfer.total_size, transfer.chunk_size);
+        }
+        state.events.publish(Event::TransferCreated { transfer });
+    }
+    let started = Instant::now();
+    let mut remaining = u64::MAX;
+    let mut last_progress = Instant::now();
+    let result = loop {
+        if state.shutdown.is_cancelled() {
+            break Err("node shutting down".to_string());
+        }
+        let (Some(transfer), Some(pending)) = (
+            state.chunk_manager.transfer(&file_id),
+            state.chunk_manager.pending_chunks(&file_id),
+        ) else {
+            break Err("cancelled".to_string());
+        };
+        if pending.is_empty() {
+            break Ok(());
+        }
+        if transfer.paused {
+            // Chunks already requested may still land; nothing new goes out.
+            last_progress = Instant::now();
+            tokio::time::sleep(TICK).await;
+            continue;
+        }
+        if (pending.len() as u64) < remaining {
+            remaining = pending.len() as u64;
+            last_progress = Instant::now();
+            state.events.publish(Event::TransferProgress {
+                file_id: file_id.clone(),
+                completed_bytes: transfer.completed_bytes,
+                total_size: transfer.total_size,
+            });
+        } else if last_progress.elapsed() > STALL_TIMEOUT {
+            break Err("stalled".to_string());
+        }
+        let chunk_size = transfer.chunk_size;
+
+        // Peers that announce they are out of quota won't serve us anyway.
+        let connected: Vec<String> = {
+            let links = state.peer_links.lock().unwrap();
+            let beacons = state.peers.lock().unwrap();
+            let candidates: Vec<&String> = if peers.is_empty() { links.keys().collect() } else { peers.iter().collect() };
+            candidates
+                .into_iter()
+                .filter(|p| *p == LOCAL_PEER || links.contains_key(*p))
+                .filter(|p| !beacons.get(*p).is_some_and(|b| b.out_of_quota))
+                .cloned()
+                .collect()
+        };
+
+        // Peers we haven't asked yet learn we want the file, or the URL, and
+        // tell us what they hold of it.
+        let sources = match &url {
+            None => {
+                for peer in state.swarm.unasked(&file_id, &connected) {
+                    state.send_to_peer(&peer, Message::Interested { file_id: file_id.clone() });
+                }
+                Sources::Holders(state.swarm.holdings(&file_id))
+            }
+            Some(url) => {
+                let remote: Vec<String> = connected.iter().filter(|p| *p != LOCAL_PEER).cloned().collect();
+                let unasked = state.swarm.unasked(&file_id, &remote);
+                for peer in &unasked {
+                    let query = Message::CacheQuery {
+                        file_id: file_id.clone(),
+                        url: url.clone(),
+                        etag: etag.clone(),
+                        total_size: transfer.total_size,
+                        chunk_size,
+                    };
+                    state.send_to_peer(peer, query);
+                }
+                // Give them a moment to answer, or chunks they have cached get fetched again.
+                if !unasked.is_empty() {
+                    tokio::time::sleep(TICK).await;
+                    continue;
+                }
+                let mut cached = state.swarm.holdings(&file_id);
+                cached.insert(LOCAL_PEER.to_string(), own_cache.clone());
+                Sources::Internet { cached }
+            }
+        };
+
+        for (peer, index) in state.scheduler.plan(&file_id, chunk_size, &pending, &connected, &sources) {
+            let Some(url) = &url else {
+                state.send_to_peer(&peer, Message::RequestChunk { file_id: file_id.clone(), index });
+                continue;
+            };
+            let Some((start, end)) = state.chunk_manager.chunk_range(&file_id, index) else { continue };
+            if peer == LOCAL_PEER {
+                tokio::spawn(fetch_chunk_locally(state.clone(), file_id.clone(), index, url.clone(), start, end).in_current_span());
+            } else {
+                let request = Message::FetchRange { file_id: file_id.clone(), index, url: url.clone(), start, end, etag: etag.clone() };
+                state.send_to_peer(&peer, request);
+            }
+        }
+
+        tokio::time::sleep(TICK).await;
+    };
+    finish_download(&state, file_id, result, started)
+}
+
+/// Reports how a download ended, and forgets what was tracked for it.
+/// Returns whether it completed.
+pub fn finish_download(state: &AppState, file_id: String, result: Result<(), String>, started: Instant) -> bool {
+    state.scheduler.forget_file(&file_id);
+    state.swarm.forget_file(&file_id);
+    state.metrics.record_transfer(result.is_ok(), started.elapsed());
+    match result {
+        Ok(()) => {
+            info!("Download complete");
+            state.events.publish(Event::TransferCompleted { file_id });
+            true
+        }
+        Err(reason) => {
+            warn!(%reason, "Download failed");
+            state.events.publish(Event::TransferFailed { file_id, reason });
+            false
+        }
+    }
+}
@@ -1,15 +1,77 @@
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use crate::protocol::Beacon;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use crate::protocol::{Beacon, Manifest, Message};
+use crate::cache::PodCache;
 use crate::chunk::ChunkManager;
+use crate::devices::Devices;
+use crate::error::Result;
+use crate::events::EventBus;
+use crate::fetch::PeerClient;
+use crate::ledger::{Ledger, ServePolicy};
+use crate::metrics::Metrics;
+use crate::offer::Offers;
+use crate::ratelimit::RateLimiter;
+use crate::sandbox::Sandbox;
+use crate::scheduler::Scheduler;
+use crate::swarm::Swarm;
+use crate::usage::UsageTracker;
 
-pub trait PeerEmitter: Send + Sync + 'static {
-    fn emit(&self, peer: Beacon);
-}
+/// Where a `request_manifest` call waits for its reply.
+pub type ManifestWaiter = oneshot::Sender<Result<Manifest>>;
 
 #[derive(Default)]
 pub struct AppState {
     pub peers: Mutex<HashMap<String, Beacon>>, 
     pub active_connections: Mutex<HashMap<String, bool>>,
+    /// Outgoing message queue of each connected peer, keyed by device id.
+    pub peer_links: Mutex<HashMap<String, Sender<Message>>>,
     pub chunk_manager: Arc<ChunkManager>,
+    pub scheduler: Scheduler,
+    /// Which peers hold which chunks of the files being downloaded.
+    pub swarm: Swarm,
+    pub rate_limiter: RateLimiter,
+    pub usage: UsageTracker,
+    pub ledger: Ledger,
+    /// Paired and blocked devices.
+    pub devices: Devices,
+    pub serve_policy: Box<dyn ServePolicy>,
+    /// Local paths the API may share from and download to.
+    pub sandbox: Sandbox,
+    pub events: EventBus,
+    /// Files offered to and by peers.
+    pub offers: Offers,
+    /// Shared with `chunk_manager`.
+    pub metrics: Arc<Metrics>,
+    /// Callers waiting for a `Manifest` reply, keyed by the peer asked and
+    /// the file id, so only that peer's answer reaches them.
+    pub pending_manifests: Mutex<HashMap<(String, String), Vec<ManifestWaiter>>>,
+    /// Internet ranges kept for the pod. Holds nothing unless enabled.
+    pub cache: PodCache,
+    /// Whether chunks go out as raw payloads to peers that can take them.
+    pub raw_payloads: bool,
+    /// Client for internet fetches, shared so connections are reused.
+    pub http: reqwest::Client,
+    /// Whether peers may have this node fetch internet ranges for them.
+    pub serve_fetches: bool,
+    /// Client for fetches made for peers.
+    pub peer_http: PeerClient,
+    /// Cancelled when the node shuts down. Every long-running loop watches it.
+    pub shutdown: CancellationToken,
+    /// Tasks that must finish before shutdown is complete.
+    pub tasks: TaskTracker,
+}
+
+impl AppState {
+    /// Queues `msg` for a connected peer. Returns false if there is no live connection.
+    pub fn send_to_peer(&self, peer_id: &str, msg: Message) -> bool {
+        let links = self.peer_links.lock().unwrap();
+        match links.get(peer_id) {
+            Some(tx) => tx.try_send(msg).is_ok(),
+            None => false,
+        }
+    }
 }
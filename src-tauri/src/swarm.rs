@@ -0,0 +1,134 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use crate::bitfield::Bitfield;
+use crate::protocol::Message;
+use crate::state::AppState;
+
+/// The chunks of a file that a peer has told us it holds.
+#[derive(Debug, Clone, Default)]
+pub struct Holding {
+    /// The peer has the whole file.
+    pub all: bool,
+    pub chunks: Bitfield,
+}
+
+impl Holding {
+    pub fn has(&self, index: u64) -> bool {
+        self.all || self.chunks.has(index)
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    /// For each file we are downloading, the peers we asked what they hold.
+    asked: HashMap<String, HashSet<String>>,
+    /// What each of those peers that answered holds.
+    holdings: HashMap<String, HashMap<String, Holding>>,
+    /// For each file we hold some of, the peers downloading it from us.
+    watchers: HashMap<String, HashSet<String>>,
+}
+
+/// Who holds which chunks of the files in flight, so a download can draw on
+/// every holder, including peers still downloading the file themselves.
+#[derive(Default)]
+pub struct Swarm {
+    inner: Mutex<Inner>,
+}
+
+impl Swarm {
+    /// The peers among `peers` not yet asked what they hold of `file_id`.
+    /// They count as holding nothing until they answer.
+    pub fn unasked(&self, file_id: &str, peers: &[String]) -> Vec<String> {
+        let mut inner = self.inner.lock().unwrap();
+        let asked = inner.asked.entry(file_id.to_string()).or_default();
+        peers.iter().filter(|p| asked.insert(p.to_string())).cloned().collect()
+    }
+
+    /// What the peers that answered hold of `file_id`.
+    pub fn holdings(&self, file_id: &str) -> HashMap<String, Holding> {
+        self.inner.lock().unwrap().holdings.get(file_id).cloned().unwrap_or_default()
+    }
+
+    /// Records a `Bitfield` from a peer we asked.
+    pub fn record_bitfield(&self, peer_id: &str, file_id: &str, chunks: Bitfield) {
+        self.update(peer_id, file_id, |holding| holding.chunks = chunks);
+    }
+
+    /// Records a `Have` (`Some(index)`) or `HaveAll` (`None`) from a peer we asked.
+    pub fn record_have(&self, peer_id: &str, file_id: &str, index: Option<u64>) {
+        self.update(peer_id, file_id, |holding| match index {
+            Some(index) => holding.chunks.set(index),
+            None => holding.all = true,
+        });
+    }
+
+    fn update(&self, peer_id: &str, file_id: &str, change: impl FnOnce(&mut Holding)) {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+        if !inner.asked.get(file_id).is_some_and(|asked| asked.contains(peer_id)) {
+            return;
+        }
+        let holdings = inner.holdings.entry(file_id.to_string()).or_default();
+        change(holdings.entry(peer_id.to_string()).or_default());
+    }
+
+    /// Our download of `file_id` is over, so what peers hold of it no longer matters.
+    pub fn forget_file(&self, file_id: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.asked.remove(file_id);
+        inner.holdings.remove(file_id);
+    }
+
+    /// `peer_id` is downloading `file_id` from us and wants to hear about new chunks.
+    pub fn watch(&self, file_id: &str, peer_id: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.watchers.entry(file_id.to_string()).or_default().insert(peer_id.to_string());
+    }
+
+    pub fn watchers(&self, file_id: &str) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        inner.watchers.get(file_id).map(|w| w.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    /// Forgets a peer that went away. It is asked again if it comes back.
+    pub fn peer_disconnected(&self, peer_id: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        for asked in inner.asked.values_mut() {
+            asked.remove(peer_id);
+        }
+        for holdings in inner.holdings.values_mut() {
+            holdings.remove(peer_id);
+        }
+        for watchers in inner.watchers.values_mut() {
+            watchers.remove(peer_id);
+        }
+        inner.watchers.retain(|_, watchers| !watchers.is_empty());
+    }
+}
+
+/// Answers a peer's `Interested`: what we hold of the file now, and from then
+/// on a `Have` for every chunk we complete. Files we don't have get no answer.
+pub fn handle_interested(state: &AppState, peer_id: &str, file_id: String) {
+    let (Some(completed), Some(pending)) =
+        (state.chunk_manager.completed_chunks(&file_id), state.chunk_manager.pending_chunks(&file_id))
+    else {
+        return;
+    };
+    state.swarm.watch(&file_id, peer_id);
+    let reply = if pending.is_empty() {
+        Message::HaveAll { file_id }
+    } else {
+        Message::Bitfield { file_id, chunks: Bitfield::from_indices(completed) }
+    };
+    state.send_to_peer(peer_id, reply);
+}
+
+/// Tells the peers downloading `file_id` from us that we now hold chunk
+/// `index`, except `from`, who sent it to us.
+pub fn announce_chunk(state: &AppState, file_id: &str, index: u64, from: &str) {
+    for peer in state.swarm.watchers(file_id) {
+        if peer != from {
+            state.send_to_peer(&peer, Message::Have { file_id: file_id.to_string(), index });
+        }
+    }
+}
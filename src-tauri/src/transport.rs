@@ -1,62 +1,148 @@
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpStream};
-use crate::protocol::Message;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::oneshot;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
+use crate::chunk::{open_piece, read_pieces, MAX_CHUNK_SIZE};
+use crate::config::TransportConfig;
+use crate::error::{Error, Result};
+use crate::events::Event;
+use crate::fetch::{fetch_cached, LOCAL_PEER};
+use crate::ledger::Verdict;
+use crate::offer::{record_offer_chunk, valid_offer, OfferDirection, OfferStatus};
+use crate::protocol::{ErrorCode, Manifest, Message};
+use crate::scheduler::complete_request;
 use crate::state::AppState;
+use crate::swarm::{announce_chunk, handle_interested};
 
-pub async fn run_tcp_listener(my_id: String, port: u16, state: Arc<AppState>) {
-    let addr = format!("0.0.0.0:{}", port);
-    let listener = match TcpListener::bind(&addr).await {
-        Ok(l) => l,
-        Err(e) => {
-            eprintln!("Failed to bind TCP listener on {}: {}", addr, e);
-            return;
-        }
-    };
-    println!("[{}] TCP Listener running on {}", my_id, addr);
+/// Sent in the handshake. Version 2 added `RawData`.
+const PROTOCOL_VERSION: u8 = 2;
+const PING_INTERVAL: Duration = Duration::from_secs(2);
+const MANIFEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Chunks travel as JSON number arrays, which take up to 4 bytes per data byte.
+const MAX_FRAME_LEN: usize = 5 * MAX_CHUNK_SIZE as usize;
+/// Most bytes one `sendfile` call may copy, so a call that has to read from
+/// disk doesn't hold up a runtime worker for long.
+#[cfg(target_os = "linux")]
+const SENDFILE_STEP: u64 = 256 * 1024;
+/// Internet fetches one peer may have us make at once.
+const MAX_FETCHES_PER_PEER: usize = 16;
+/// Messages waiting to be written to one peer. A chunk sent as JSON is one
+/// message, so this holds at most 32 chunks.
+const OUTGOING_QUEUE: usize = 32;
+/// Chunk requests from one peer waiting to be served. Further ones are
+/// answered with `Unavailable`.
+const MAX_SERVE_QUEUE: usize = 64;
+
+/// Internet fetches in progress for a peer, keyed by (file_id, chunk index).
+type Fetches = Arc<Mutex<HashMap<(String, u64), JoinHandle<()>>>>;
+
+/// A chunk that goes out as a `RawData` frame followed by its bytes, copied
+/// straight from the files they are stored in.
+struct RawSend {
+    file_id: String,
+    index: u64,
+    /// (file, offset in the file, length) pieces, as from `locate_chunk`.
+    pieces: Vec<(PathBuf, u64, u64)>,
+}
+
+/// A queued `RequestChunk`.
+struct ServeRequest {
+    file_id: String,
+    index: u64,
+}
+
+pub async fn bind_tcp_listener(config: &TransportConfig) -> Result<TcpListener> {
+    let addr = SocketAddr::new(config.bind, config.port);
+    TcpListener::bind(&addr).await.map_err(|source| Error::Bind { service: "peer connections", addr: addr.to_string(), source })
+}
+
+pub async fn run_tcp_listener(listener: TcpListener, my_id: String, state: Arc<AppState>) {
+    if let Ok(addr) = listener.local_addr() {
+        info!(%addr, "TCP listener running");
+    }
 
     loop {
-        match listener.accept().await {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = state.shutdown.cancelled() => break,
+        };
+        match accepted {
             Ok((socket, addr)) => {
-                println!("[{}] New incoming TCP connection from: {:?}", my_id, addr);
                 let id_clone = my_id.clone();
                 let state_clone = state.clone();
-                tokio::spawn(async move {
+                let span = info_span!("peer", %addr, device = field::Empty);
+                state.tasks.spawn(async move {
+                    debug!("Incoming connection");
                     handle_connection(socket, id_clone, true, state_clone).await;
-                });
+                }.instrument(span));
             }
-            Err(e) => eprintln!("TCP Accept error: {}", e),
+            Err(e) => warn!(error = %e, "Failed to accept TCP connection"),
         }
     }
 }
 
 pub async fn connect_to_peer(peer_id: String, ip: String, port: u16, my_id: String, state: Arc<AppState>) {
     let addr = format!("{}:{}", ip, port);
-    println!("[{}] Attempting to connect to peer {} at {}", my_id, peer_id, addr);
+    let span = info_span!("peer", %addr, device = %peer_id);
+    async move {
+        debug!("Connecting");
+        match TcpStream::connect(&addr).await {
+            Ok(socket) => {
+                info!("Connected");
+                {
+                    let mut conns = state.active_connections.lock().unwrap();
+                    conns.insert(peer_id.clone(), true);
+                }
 
-    match TcpStream::connect(&addr).await {
-        Ok(socket) => {
-            println!("[{}] Connected to peer {}", my_id, peer_id);
-            {
-                let mut conns = state.active_connections.lock().unwrap();
-                conns.insert(peer_id.clone(), true);
+                handle_connection(socket, my_id, false, state.clone()).await;
+                info!("Disconnected");
             }
-            
-            handle_connection(socket, my_id, false, state.clone()).await;
-            
-            {
-                let mut conns = state.active_connections.lock().unwrap();
-                conns.remove(&peer_id);
+            Err(e) => {
+                state.metrics.connection_errors.inc();
+                warn!(error = %e, "Failed to connect");
             }
-            println!("Disconnected from peer {}", peer_id);
         }
-        Err(e) => {
-            eprintln!("[{}] Failed to connect to peer {}: {}", my_id, peer_id, e);
+        // Lets discovery try again at the peer's next beacon.
+        state.active_connections.lock().unwrap().remove(&peer_id);
+    }
+    .instrument(span)
+    .await
+}
+
+/// Asks a connected peer how `file_id` is chunked.
+pub async fn request_manifest(state: &AppState, peer_id: &str, file_id: &str) -> Result<Manifest> {
+    let (tx, rx) = oneshot::channel();
+    let key = (peer_id.to_string(), file_id.to_string());
+    state.pending_manifests.lock().unwrap().entry(key.clone()).or_default().push(tx);
+    let request = Message::RequestManifest { file_id: file_id.to_string() };
+    let manifest = if state.send_to_peer(peer_id, request) {
+        match tokio::time::timeout(MANIFEST_TIMEOUT, rx).await {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(_)) => Err(Error::Unavailable(format!("lost connection to {}", peer_id))),
+            Err(_) => Err(Error::Unavailable(format!("{} did not answer", peer_id))),
         }
+    } else {
+        Err(Error::Unavailable(format!("not connected to {}", peer_id)))
+    };
+    let mut pending = state.pending_manifests.lock().unwrap();
+    if let Some(waiters) = pending.get_mut(&key) {
+        waiters.retain(|w| !w.is_closed());
+        if waiters.is_empty() { pending.remove(&key); }
     }
+    manifest
 }
 
-async fn send_message(socket: &mut TcpStream, msg: &Message) -> std::io::Result<()> {
+async fn send_message<W: AsyncWrite + Unpin>(socket: &mut W, msg: &Message) -> io::Result<()> {
     let json = serde_json::to_string(msg).unwrap();
     let bytes = json.as_bytes();
     let len = bytes.len() as u32;
@@ -65,48 +151,698 @@ async fn send_message(socket: &mut TcpStream, msg: &Message) -> std::io::Result<
     Ok(())
 }
 
-async fn handle_connection(mut socket: TcpStream, my_id: String, _is_server: bool, state: Arc<AppState>) {
+/// Sends a `RawData` frame and then the bytes themselves. Once the frame is
+/// out exactly `len` bytes must follow, so the files are opened first; if
+/// one has gone away the peer gets an `Error` instead.
+async fn send_raw(socket: &mut OwnedWriteHalf, raw: RawSend) -> io::Result<()> {
+    let mut files = Vec::with_capacity(raw.pieces.len());
+    for (path, _, _) in &raw.pieces {
+        match open_piece(path).await {
+            Ok(file) => files.push(file.into_std().await),
+            Err(e) => {
+                warn!(file_id = %raw.file_id, index = raw.index, error = %e, "Can't serve chunk");
+                let reply = Message::Error {
+                    code: ErrorCode::Unavailable,
+                    message: e.to_string(),
+                    file_id: Some(raw.file_id),
+                    index: Some(raw.index),
+                };
+                return send_message(socket, &reply).await;
+            }
+        }
+    }
+    let len = raw.pieces.iter().map(|(_, _, len)| len).sum();
+    send_message(socket, &Message::RawData { file_id: raw.file_id, index: raw.index, len }).await?;
+    for (file, (_, offset, len)) in files.into_iter().zip(raw.pieces) {
+        copy_file(socket, file, offset, len).await?;
+    }
+    Ok(())
+}
+
+/// Copies `len` bytes of `file` from `offset` to the socket with `sendfile`,
+/// so they go from the page cache to the socket without passing through
+/// this process. Where the kernel or file system can't, the rest goes
+/// through `copy_buffered`.
+#[cfg(target_os = "linux")]
+async fn copy_file(socket: &mut OwnedWriteHalf, file: std::fs::File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    use tokio::io::Interest;
+    let stream: &TcpStream = socket.as_ref();
+    let mut offset = libc::off_t::try_from(offset).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let mut left = len;
+    while left > 0 {
+        stream.writable().await?;
+        let sent = stream.try_io(Interest::WRITABLE, || {
+            let count = left.min(SENDFILE_STEP) as usize;
+            // SAFETY: both descriptors stay open for the call, and `offset` is a valid off_t.
+            let sent = unsafe { libc::sendfile(stream.as_raw_fd(), file.as_raw_fd(), &mut offset, count) };
+            if sent < 0 { Err(io::Error::last_os_error()) } else { Ok(sent as u64) }
+        });
+        match sent {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file is shorter than the chunk")),
+            Ok(sent) => left -= sent,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) if sendfile_unsupported(&e) => {
+                debug!(error = %e, "sendfile unavailable, copying through a buffer");
+                return copy_buffered(socket, file, offset as u64, left).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Whether a `sendfile` error means it can't be used for this file at all.
+#[cfg(target_os = "linux")]
+fn sendfile_unsupported(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS | libc::EOPNOTSUPP))
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn copy_file(socket: &mut OwnedWriteHalf, file: std::fs::File, offset: u64, len: u64) -> io::Result<()> {
+    copy_buffered(socket, file, offset, len).await
+}
+
+/// Copies `len` bytes of `file` from `offset` to the socket through a small
+/// buffer, for platforms and files `sendfile` doesn't work with.
+async fn copy_buffered(socket: &mut OwnedWriteHalf, file: std::fs::File, offset: u64, len: u64) -> io::Result<()> {
+    use tokio::io::AsyncSeekExt;
+    let mut file = tokio::fs::File::from_std(file);
+    file.seek(io::SeekFrom::Start(offset)).await?;
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut left = len;
+    while left > 0 {
+        let want = left.min(buf.len() as u64) as usize;
+        let read = file.read(&mut buf[..want]).await?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file is shorter than the chunk"));
+        }
+        socket.write_all(&buf[..read]).await?;
+        left -= read as u64;
+    }
+    Ok(())
+}
+
+/// Reads the bytes that follow a `RawData` frame.
+async fn read_payload(reader: &mut OwnedReadHalf, len: u64) -> io::Result<Vec<u8>> {
+    if len > MAX_CHUNK_SIZE {
+        let reason = format!("raw payload of {} bytes is larger than a chunk", len);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+    }
+    let mut buf = vec![0u8; len as usize];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Reads one length-prefixed frame. A clean close shows up as `UnexpectedEof`.
+async fn read_frame(reader: &mut OwnedReadHalf) -> io::Result<Vec<u8>> {
+    let mut len_buf = [0u8; 4];
+    reader.read_exact(&mut len_buf).await?;
+    let msg_len = u32::from_le_bytes(len_buf) as usize;
+    if msg_len > MAX_FRAME_LEN {
+        let reason = format!("frame of {} bytes is over the {} byte limit", msg_len, MAX_FRAME_LEN);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+    }
+
+    let mut buf = vec![0u8; msg_len];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+async fn handle_connection(socket: TcpStream, my_id: String, is_server: bool, state: Arc<AppState>) {
+    let (mut reader, mut writer) = socket.into_split();
+
     // Send Handshake
-    let handshake = Message::Handshake { version: 1, device_id: my_id.clone() };
-    if let Err(e) = send_message(&mut socket, &handshake).await {
-        eprintln!("Failed to send handshake: {}", e);
+    let handshake = Message::Handshake { version: PROTOCOL_VERSION, device_id: my_id.clone() };
+    if let Err(e) = send_message(&mut writer, &handshake).await {
+        state.metrics.connection_errors.inc();
+        warn!(error = %e, "Failed to send handshake");
         return;
     }
 
+    // Everything after the handshake goes through the peer's outgoing queue,
+    // or for raw payloads a second queue the chunk server feeds. Both are
+    // bounded so a peer that doesn't read can't make us buffer without end:
+    // the chunk server and fetches wait for room, while replies from this
+    // loop are dropped rather than stop it reading.
+    let (tx, mut rx) = mpsc::channel::<Message>(OUTGOING_QUEUE);
+    let (raw_tx, mut raw_rx) = mpsc::channel::<RawSend>(OUTGOING_QUEUE);
+    // Runs until every sender is gone, so a final Goodbye still goes out before the socket closes.
+    state.tasks.spawn(async move {
+        loop {
+            let sent = tokio::select! {
+                msg = rx.recv() => match msg {
+                    Some(msg) => send_message(&mut writer, &msg).await,
+                    None => break,
+                },
+                Some(raw) = raw_rx.recv() => send_raw(&mut writer, raw).await,
+            };
+            if let Err(e) = sent {
+                debug!(error = %e, "Write failed");
+                return;
+            }
+        }
+        let _ = writer.shutdown().await;
+    }.in_current_span());
+
+    // Chunk requests are served one at a time from a queue, so a CancelChunk
+    // can still drop the ones that haven't been sent yet.
+    let serve_queue: Arc<Mutex<VecDeque<ServeRequest>>> = Arc::default();
+    let serve_notify = Arc::new(Notify::new());
+    let mut server: Option<JoinHandle<()>> = None;
+    let mut peer_id: Option<String> = None;
+    let mut pinger: Option<JoinHandle<()>> = None;
+    // Internet fetches we're making for the peer, so CancelChunk can abort them.
+    let fetches: Fetches = Arc::default();
+
     // Message Loop
     loop {
-        let mut len_buf = [0u8; 4];
-        if let Err(_) = socket.read_exact(&mut len_buf).await { return; }
-        let msg_len = u32::from_le_bytes(len_buf) as usize;
-        
-        if msg_len > 10 * 1024 * 1024 { return; } // 10MB limit
-
-        let mut buf = vec![0u8; msg_len];
-        if let Err(_) = socket.read_exact(&mut buf).await { return; }
-
-        if let Ok(msg) = serde_json::from_slice::<Message>(&buf) {
-            match msg {
-                Message::Handshake { version, device_id } => {
-                    println!("[{}] Handshake received from {} (v{})", my_id, device_id, version);
-                }
-                Message::Ping => { let _ = send_message(&mut socket, &Message::Pong).await; }
-                Message::Pong => {}
-                Message::RequestChunk { file_id, index } => {
-                    println!("[{}] Serving chunk {} for file {}", my_id, index, file_id);
-                    // READ Chunk from Disk
-                    if let Some(data) = state.chunk_manager.read_chunk(&file_id, index).await {
-                        let response = Message::ChunkData { file_id, index, data };
-                        let _ = send_message(&mut socket, &response).await;
-                    } else {
-                        eprintln!("Chunk not found!");
+        let frame = tokio::select! {
+            frame = read_frame(&mut reader) => frame,
+            _ = state.shutdown.cancelled() => {
+                let _ = tx.try_send(Message::Goodbye);
+                break;
+            }
+        };
+        let buf = match frame {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                debug!("Connection closed by peer");
+                break;
+            }
+            Err(e) => {
+                state.metrics.connection_errors.inc();
+                warn!(error = %e, "Connection lost");
+                break;
+            }
+        };
+
+        // A device blocked while connected is dropped at its next message (pings come every 2s).
+        if peer_id.as_ref().is_some_and(|peer| !state.devices.allows(peer)) {
+            info!("Dropping connection to blocked device");
+            break;
+        }
+
+        let msg = match serde_json::from_slice::<Message>(&buf) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!(error = %e, "Ignoring unreadable message");
+                continue;
+            }
+        };
+        // A raw payload follows its frame directly; from here on it is handled like any chunk.
+        let msg = match msg {
+            Message::RawData { file_id, index, len } => match read_payload(&mut reader, len).await {
+                Ok(data) => Message::ChunkData { file_id, index, data },
+                Err(e) => {
+                    state.metrics.connection_errors.inc();
+                    warn!(error = %e, "Connection lost");
+                    break;
+                }
+            },
+            msg => msg,
+        };
+        match msg {
+            Message::Handshake { version, device_id } => {
+                // The peer is who it said it was the first time; a second handshake is a protocol error.
+                if peer_id.is_some() {
+                    warn!("Closing connection after a second handshake");
+                    break;
+                }
+                // Outgoing connections know who they dialled; incoming ones learn it here.
+                if is_server { Span::current().record("device", device_id.as_str()); }
+                debug!(version, "Handshake received");
+                // The scheduler uses this id for our own internet fetches.
+                if device_id == LOCAL_PEER {
+                    warn!("Closing connection from a peer claiming the reserved id {}", LOCAL_PEER);
+                    break;
+                }
+                if !state.devices.allows(&device_id) {
+                    info!("Refusing connection from blocked or unpaired device");
+                    let message = "this device does not accept connections from you".to_string();
+                    let _ = tx.try_send(Message::Error { code: ErrorCode::NotPermitted, message, file_id: None, index: None });
+                    break;
+                }
+                state.peer_links.lock().unwrap().insert(device_id.clone(), tx.clone());
+                state.events.publish(Event::PeerConnected { device_id: device_id.clone() });
+                pinger = Some(spawn_pinger(device_id.clone(), tx.clone(), state.clone()));
+                let raw = (state.raw_payloads && version >= 2).then(|| raw_tx.clone());
+                server = Some(spawn_server(
+                    device_id.clone(),
+                    serve_queue.clone(),
+                    serve_notify.clone(),
+                    tx.clone(),
+                    raw,
+                    state.clone(),
+                ));
+                peer_id = Some(device_id);
+            }
+            // Turned into ChunkData above.
+            Message::RawData { .. } => {}
+            Message::Goodbye => {
+                info!("Peer is shutting down");
+                break;
+            }
+            Message::Ping => { let _ = tx.try_send(Message::Pong); }
+            Message::Pong => {
+                if let Some(peer) = &peer_id { state.scheduler.record_pong(peer); }
+            }
+            Message::RequestChunk { file_id, index } => {
+                if peer_id.is_none() { continue }
+                state.metrics.chunks_requested.inc();
+                let mut queue = serve_queue.lock().unwrap();
+                if queue.len() >= MAX_SERVE_QUEUE {
+                    drop(queue);
+                    let message = format!("more than {} chunk requests queued", MAX_SERVE_QUEUE);
+                    let reply = Message::Error { code: ErrorCode::Unavailable, message, file_id: Some(file_id), index: Some(index) };
+                    let _ = tx.try_send(reply);
+                    continue;
+                }
+                queue.push_back(ServeRequest { file_id, index });
+                drop(queue);
+                serve_notify.notify_one();
+            }
+            Message::CancelChunk { file_id, index } => {
+                serve_queue.lock().unwrap().retain(|r| !(r.file_id == file_id && r.index == index));
+                if let Some(fetch) = fetches.lock().unwrap().remove(&(file_id, index)) {
+                    fetch.abort();
+                }
+            }
+            Message::FetchRange { file_id, index, url, start, end, etag } => {
+                let Some(peer) = peer_id.clone() else { continue };
+                state.metrics.chunks_requested.inc();
+                let key = (file_id.clone(), index);
+                let mut running = fetches.lock().unwrap();
+                if running.len() >= MAX_FETCHES_PER_PEER && !running.contains_key(&key) {
+                    let _ = tx.try_send(Message::Choked { file_id, index });
+                    continue;
+                }
+                let fetch = spawn_fetch(peer, file_id, index, url, etag, start, end, fetches.clone(), tx.clone(), state.clone());
+                if let Some(replaced) = running.insert(key, fetch) {
+                    replaced.abort();
+                }
+            }
+            Message::FetchFailed { file_id, index, reason } => {
+                if let Some(peer) = &peer_id {
+                    warn!(%file_id, index, %reason, "Peer could not fetch chunk");
+                    state.metrics.chunks_failed.inc();
+                    state.scheduler.record_failed(peer, &file_id, index);
+                    state.events.publish(Event::ChunkFailed { file_id, index, peer: peer.clone(), reason });
+                }
+            }
+            Message::Interested { file_id } => {
+                if let Some(peer) = &peer_id { handle_interested(&state, peer, file_id); }
+            }
+            Message::CacheQuery { file_id, url, etag, total_size, chunk_size } => {
+                let chunks = state.cache.cached_chunks(&url, etag.as_deref(), total_size, chunk_size);
+                if chunks.count() > 0 {
+                    let _ = tx.try_send(Message::Bitfield { file_id, chunks });
+                }
+            }
+            Message::Bitfield { file_id, chunks } => {
+                let Some(peer) = &peer_id else { continue };
+                // Bits past the end of the file would make us hold a bitfield as large as the peer likes.
+                if !state.chunk_manager.chunk_count(&file_id).is_some_and(|count| chunks.fits(count)) {
+                    debug!(%file_id, "Ignoring bitfield that doesn't fit the file");
+                    continue;
+                }
+                debug!(%file_id, chunks = chunks.count(), "Peer holds part of the file");
+                state.swarm.record_bitfield(peer, &file_id, chunks);
+            }
+            Message::Have { file_id, index } => {
+                let Some(peer) = &peer_id else { continue };
+                if state.chunk_manager.chunk_count(&file_id).is_none_or(|count| index >= count) {
+                    debug!(%file_id, index, "Ignoring Have for a chunk the file doesn't have");
+                    continue;
+                }
+                state.swarm.record_have(peer, &file_id, Some(index));
+            }
+            Message::HaveAll { file_id } => {
+                if let Some(peer) = &peer_id { state.swarm.record_have(peer, &file_id, None); }
+            }
+            Message::Choked { file_id, index } => {
+                if let Some(peer) = &peer_id { state.scheduler.record_choked(peer, &file_id, index); }
+            }
+            Message::Error { code, message, file_id, index } => {
+                // Asking around for a manifest is expected to miss.
+                if code == ErrorCode::UnknownFile && index.is_none() {
+                    debug!(%message, file_id = file_id.as_deref(), "Peer doesn't have the file");
+                } else {
+                    warn!(?code, %message, file_id = file_id.as_deref(), index, "Peer reported an error");
+                }
+                match (file_id, index, &peer_id) {
+                    (Some(file_id), Some(index), Some(peer)) => {
+                        state.metrics.chunks_failed.inc();
+                        state.scheduler.record_failed(peer, &file_id, index);
+                        state.events.publish(Event::ChunkFailed { file_id, index, peer: peer.clone(), reason: message });
+                    }
+                    (Some(file_id), None, Some(peer)) => {
+                        let waiters = state.pending_manifests.lock().unwrap().remove(&(peer.clone(), file_id));
+                        for waiter in waiters.into_iter().flatten() {
+                            let _ = waiter.send(Err(Error::Remote { code, message: message.clone() }));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Message::Offer { offer_id, files } => {
+                let Some(peer) = &peer_id else { continue };
+                let valid = valid_offer(&files);
+                match valid.then(|| state.offers.receive(offer_id.clone(), peer, files)).flatten() {
+                    Some(offer) => {
+                        info!(%offer_id, files = offer.files.len(), bytes = offer.total_size, "Offer received");
+                        state.events.publish(Event::OfferReceived { offer });
+                    }
+                    None => {
+                        warn!(%offer_id, valid, "Declining offer");
+                        let _ = tx.try_send(Message::OfferReply { offer_id, accepted: false });
                     }
                 }
-                Message::ChunkData { file_id, index, data } => {
-                    println!("[{}] Received chunk {} for file {} ({} bytes)", my_id, index, file_id, data.len());
-                    // WRITE Chunk to Disk
-                    state.chunk_manager.write_chunk(&file_id, index, data).await;
+            }
+            Message::OfferReply { offer_id, accepted } => {
+                let ours = state.offers.get(&offer_id).is_some_and(|o| {
+                    o.direction == OfferDirection::Outgoing && o.status == OfferStatus::Pending && peer_id.as_ref() == Some(&o.peer)
+                });
+                if !ours { continue; }
+                info!(%offer_id, accepted, "Offer answered");
+                if accepted {
+                    let offer = state.offers.set_status(&offer_id, OfferStatus::Accepted);
+                    state.events.publish(Event::OfferAccepted { offer_id: offer_id.clone() });
+                    if offer.is_some_and(|o| o.status == OfferStatus::Completed) {
+                        state.events.publish(Event::OfferCompleted { offer_id });
+                    }
+                } else if let Some(offer) = state.offers.set_status(&offer_id, OfferStatus::Declined) {
+                    // The files were only shared for this offer.
+                    for file in &offer.files {
+                        state.chunk_manager.remove_transfer(&file.manifest.file_id);
+                    }
+                    state.events.publish(Event::OfferDeclined { offer_id });
+                }
+            }
+            Message::RequestManifest { file_id } => {
+                let reply = match state.chunk_manager.manifest(&file_id) {
+                    Some(manifest) => Message::Manifest(manifest),
+                    None => Message::Error {
+                        code: ErrorCode::UnknownFile,
+                        message: format!("no file {}", file_id),
+                        file_id: Some(file_id),
+                        index: None,
+                    },
+                };
+                let _ = tx.try_send(reply);
+            }
+            Message::Manifest(manifest) => {
+                let Some(peer) = &peer_id else { continue };
+                let waiters = state.pending_manifests.lock().unwrap().remove(&(peer.clone(), manifest.file_id.clone()));
+                for waiter in waiters.into_iter().flatten() {
+                    let _ = waiter.send(Ok(manifest.clone()));
+                }
+            }
+            Message::ChunkData { file_id, index, data } => {
+                debug!(%file_id, index, bytes = data.len(), "Received chunk");
+                let len = data.len();
+                // Only chunks we asked this peer for are taken. Late endgame
+                // duplicates and chunks of cancelled transfers are dropped.
+                let wanted = peer_id.as_ref().is_some_and(|peer| state.scheduler.is_requested(peer, &file_id, index))
+                    && !state.chunk_manager.is_chunk_completed(&file_id, index);
+                // WRITE Chunk to Disk
+                let written = if wanted { Some(state.chunk_manager.write_chunk(&file_id, index, data).await) } else { None };
+                if let Some(peer) = &peer_id {
+                    match written {
+                        Some(Ok(())) => {
+                            // Only chunks we asked for and kept count in the peer's favour.
+                            state.usage.record_download(peer, len as u64);
+                            state.ledger.record_received(peer, len as u64);
+                            complete_request(&state, peer, &file_id, index, len);
+                            announce_chunk(&state, &file_id, index, peer);
+                            record_offer_chunk(&state, peer, &file_id, index, len as u64);
+                            let verified = state.chunk_manager.is_verified(&file_id);
+                            state.events.publish(Event::ChunkStored { file_id: file_id.clone(), index, peer: peer.clone(), verified });
+                        }
+                        Some(Err(e)) => {
+                            state.scheduler.record_failed(peer, &file_id, index);
+                            let reason = e.to_string();
+                            warn!(%file_id, index, %reason, "Chunk failed");
+                            state.events.publish(Event::ChunkFailed { file_id: file_id.clone(), index, peer: peer.clone(), reason });
+                        }
+                        None => {}
+                    }
+                    state.metrics.record_received(peer, len as u64);
+                    // Not reading the next frame until the budget allows it
+                    // pushes back on the sender through TCP flow control.
+                    state.rate_limiter.acquire_download(peer, len as u64).await;
                 }
             }
         }
     }
+
+    if let Some(server) = server { server.abort(); }
+    if let Some(pinger) = pinger { pinger.abort(); }
+    for (_, fetch) in fetches.lock().unwrap().drain() { fetch.abort(); }
+    if let Some(peer) = peer_id {
+        let mut links = state.peer_links.lock().unwrap();
+        if links.get(&peer).is_some_and(|l| l.same_channel(&tx)) {
+            links.remove(&peer);
+        }
+        drop(links);
+        state.scheduler.peer_disconnected(&peer);
+        state.swarm.peer_disconnected(&peer);
+        state.rate_limiter.peer_disconnected(&peer);
+        state.metrics.peer_disconnected(&peer);
+        state.events.publish(Event::PeerDisconnected { device_id: peer });
+    }
+}
+
+/// Serves queued chunk requests from `peer_id` in order, within the upload
+/// budget. With `raw`, the data is left for the writer to copy from disk.
+fn spawn_server(
+    peer_id: String,
+    queue: Arc<Mutex<VecDeque<ServeRequest>>>,
+    notify: Arc<Notify>,
+    tx: Sender<Message>,
+    raw: Option<Sender<RawSend>>,
+    state: Arc<AppState>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            notify.notified().await;
+            loop {
+                let next = queue.lock().unwrap().pop_front();
+                let Some(ServeRequest { file_id, index }) = next else { break };
+                if state.usage.upload_quota_reached() {
+                    info!(%file_id, index, "Upload quota reached, not serving chunk");
+                    let _ = tx.send(Message::Choked { file_id, index }).await;
+                    continue;
+                }
+                match state.serve_policy.verdict(&peer_id, state.ledger.balance(&peer_id)) {
+                    Verdict::Serve => {}
+                    Verdict::Defer(delay) => tokio::time::sleep(delay).await,
+                    Verdict::Choke => {
+                        debug!(%file_id, index, "Choking");
+                        let _ = tx.send(Message::Choked { file_id, index }).await;
+                        continue;
+                    }
+                }
+                debug!(%file_id, index, "Serving chunk");
+                // READ Chunk from Disk, unless it goes out raw
+                let located = state.chunk_manager.locate_chunk(&file_id, index);
+                let read = match (located, &raw) {
+                    (Ok(pieces), Some(_)) => Ok((pieces, None)),
+                    (Ok(pieces), None) => read_pieces(&pieces).await.map(|data| (pieces, Some(data))).map_err(Error::from),
+                    (Err(e), _) => Err(e),
+                };
+                let (pieces, data) = match read {
+                    Ok(read) => read,
+                    Err(e) => {
+                        warn!(%file_id, index, error = %e, "Can't serve chunk");
+                        let reply = Message::Error { code: e.code(), message: e.to_string(), file_id: Some(file_id), index: Some(index) };
+                        let _ = tx.send(reply).await;
+                        continue;
+                    }
+                };
+                let len = pieces.iter().map(|(_, _, len)| len).sum();
+                state.rate_limiter.acquire_upload(&peer_id, len).await;
+                state.usage.record_upload(&peer_id, len);
+                state.ledger.record_given(&peer_id, len);
+                state.metrics.record_sent(&peer_id, len);
+                state.metrics.chunks_served.inc();
+                record_offer_chunk(&state, &peer_id, &file_id, index, len);
+                match (data, &raw) {
+                    (Some(data), _) => {
+                        let _ = tx.send(Message::ChunkData { file_id, index, data }).await;
+                    }
+                    (None, Some(raw)) => {
+                        let _ = raw.send(RawSend { file_id, index, pieces }).await;
+                    }
+                    (None, None) => {}
+                }
+            }
+        }
+    }.in_current_span())
+}
+
+/// Downloads a range from the internet on the peer's behalf, or takes it
+/// from the pod cache, and sends it back as a chunk within the proxy and
+/// upload budgets.
+#[allow(clippy::too_many_arguments)]
+fn spawn_fetch(
+    peer_id: String,
+    file_id: String,
+    index: u64,
+    url: String,
+    etag: Option<String>,
+    start: u64,
+    end: u64,
+    fetches: Fetches,
+    tx: Sender<Message>,
+    state: Arc<AppState>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let len = end.saturating_sub(start);
+        let checked = if end <= start { Err(format!("Empty range {}..{}", start, end)) } else { state.peer_http.check(&url) };
+        // A range in the pod cache costs no internet traffic, so the proxy limits don't apply.
+        let cached = if checked.is_ok() && len <= MAX_CHUNK_SIZE {
+            state.cache.get(&url, etag.as_deref(), start, end).await
+        } else {
+            None
+        };
+        let refused = (cached.is_none() && (!state.serve_fetches || state.usage.proxy_quota_reached()))
+            || state.usage.upload_quota_reached()
+            || state.serve_policy.verdict(&peer_id, state.ledger.balance(&peer_id)) == Verdict::Choke;
+        if let Err(reason) = checked {
+            warn!(%file_id, index, %url, %reason, "Refused fetch for peer");
+            let _ = tx.send(Message::FetchFailed { file_id: file_id.clone(), index, reason }).await;
+        } else if refused {
+            let _ = tx.send(Message::Choked { file_id: file_id.clone(), index }).await;
+        } else if len > MAX_CHUNK_SIZE {
+            let reason = format!("Range of {} bytes is larger than a chunk", len);
+            let _ = tx.send(Message::FetchFailed { file_id: file_id.clone(), index, reason }).await;
+        } else {
+            let fetched = match cached {
+                Some(data) => {
+                    state.metrics.cache_hits.inc();
+                    Ok(data)
+                }
+                None => {
+                    state.rate_limiter.acquire_proxy(len).await;
+                    let fetched = fetch_cached(&state, state.peer_http.client(), &url, etag.as_deref(), start, end).await;
+                    if fetched.is_ok() {
+                        state.usage.record_proxied(&peer_id, len);
+                    }
+                    fetched
+                }
+            };
+            match fetched {
+                Ok(data) => {
+                    state.rate_limiter.acquire_upload(&peer_id, len).await;
+                    state.usage.record_upload(&peer_id, len);
+                    state.ledger.record_given(&peer_id, len);
+                    state.metrics.record_sent(&peer_id, len);
+                    state.metrics.chunks_served.inc();
+                    let _ = tx.send(Message::ChunkData { file_id: file_id.clone(), index, data }).await;
+                }
+                Err(reason) => {
+                    warn!(%file_id, index, %url, %reason, "Fetch for peer failed");
+                    let _ = tx.send(Message::FetchFailed { file_id: file_id.clone(), index, reason }).await;
+                }
+            }
+        }
+        // Unless a newer fetch of the same range has taken the slot.
+        let mut running = fetches.lock().unwrap();
+        let key = (file_id, index);
+        if running.get(&key).is_some_and(|h| h.id() == tokio::task::id()) {
+            running.remove(&key);
+        }
+    }.in_current_span())
+}
+
+/// Pings the peer periodically so the scheduler has a fresh RTT estimate.
+fn spawn_pinger(peer_id: String, tx: Sender<Message>, state: Arc<AppState>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            state.scheduler.record_ping_sent(&peer_id);
+            if tx.send(Message::Ping).await.is_err() { break; }
+            tokio::time::sleep(PING_INTERVAL).await;
+        }
+    }.in_current_span())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str, data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("peapod_transport_{}_{}", name, std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    /// Runs `copy` against one end of a loopback connection and returns what the other end read.
+    async fn received<F, Fut>(copy: F) -> Vec<u8>
+    where
+        F: FnOnce(OwnedWriteHalf) -> Fut,
+        Fut: std::future::Future<Output = io::Result<()>>,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let reader = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut data = Vec::new();
+            socket.read_to_end(&mut data).await.unwrap();
+            data
+        });
+        let (_read, write) = TcpStream::connect(addr).await.unwrap().into_split();
+        copy(write).await.unwrap();
+        reader.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn copy_file_sends_the_requested_range() {
+        let data: Vec<u8> = (0..300_000u32).map(|i| i as u8).collect();
+        let path = temp_file("copy", &data);
+        let file = std::fs::File::open(&path).unwrap();
+        let got = received(|mut socket| async move {
+            copy_file(&mut socket, file, 1000, 200_000).await?;
+            socket.shutdown().await
+        }).await;
+        assert_eq!(got, &data[1000..201_000]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn buffered_copy_sends_the_same_range() {
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i * 7) as u8).collect();
+        let path = temp_file("buffered", &data);
+        let file = std::fs::File::open(&path).unwrap();
+        let got = received(|mut socket| async move {
+            copy_buffered(&mut socket, file, 1000, 200_000).await?;
+            socket.shutdown().await
+        }).await;
+        assert_eq!(got, &data[1000..201_000]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn copy_past_the_end_of_the_file_fails() {
+        let path = temp_file("short", &[1; 100]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (_read, mut write) = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap().into_split();
+        let err = copy_file(&mut write, std::fs::File::open(&path).unwrap(), 50, 100).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let err = copy_buffered(&mut write, std::fs::File::open(&path).unwrap(), 50, 100).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn falls_back_only_when_sendfile_is_unsupported() {
+        for errno in [libc::EINVAL, libc::ENOSYS, libc::EOPNOTSUPP] {
+            assert!(sendfile_unsupported(&io::Error::from_raw_os_error(errno)));
+        }
+        for errno in [libc::EPIPE, libc::ECONNRESET, libc::EIO] {
+            assert!(!sendfile_unsupported(&io::Error::from_raw_os_error(errno)));
+        }
+        assert!(!sendfile_unsupported(&io::Error::from(io::ErrorKind::WouldBlock)));
+    }
 }
@@ -0,0 +1,305 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+use crate::persist::{load_json, to_json, write_file};
+use crate::state::AppState;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+/// Daily records older than this are dropped when saving.
+const RETENTION_DAYS: i64 = 400;
+
+/// Data caps in bytes. `0` means no cap.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Quota {
+    /// Stop serving chunks to others once this much was uploaded today.
+    pub daily_upload: u64,
+    /// Stop serving chunks to others once this much was uploaded this month.
+    pub monthly_upload: u64,
+    /// Stop making internet fetches for others once this much was proxied this month.
+    pub monthly_proxy: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Counters {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub proxied: u64,
+}
+
+impl Counters {
+    fn add(&mut self, other: &Counters) {
+        self.uploaded += other.uploaded;
+        self.downloaded += other.downloaded;
+        self.proxied += other.proxied;
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageReport {
+    pub quota: Quota,
+    pub upload_quota_reached: bool,
+    pub proxy_quota_reached: bool,
+    pub today: Counters,
+    pub this_month: Counters,
+    pub peers_today: HashMap<String, Counters>,
+    pub peers_this_month: HashMap<String, Counters>,
+    /// Per day (`YYYY-MM-DD`, UTC), per peer.
+    pub days: BTreeMap<String, HashMap<String, Counters>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    days: BTreeMap<String, HashMap<String, Counters>>,
+    quota: Quota,
+    dirty: bool,
+}
+
+/// Bytes uploaded, downloaded and proxied per peer per day, persisted as JSON.
+#[derive(Default)]
+pub struct UsageTracker {
+    inner: Mutex<Inner>,
+    path: Option<PathBuf>,
+}
+
+impl UsageTracker {
+    /// Loads earlier usage from `path` (if it exists) and saves back to it.
+    pub fn load(path: Option<PathBuf>, quota: Quota) -> io::Result<Self> {
+        let days = load_json(path.as_deref())?;
+        Ok(Self {
+            inner: Mutex::new(Inner { days, quota, dirty: false }),
+            path,
+        })
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else { return Ok(()) };
+        let json = {
+            let mut inner = self.inner.lock().unwrap();
+            if !inner.dirty {
+                return Ok(());
+            }
+            drop_old_days(&mut inner.days, days_since_epoch());
+            inner.dirty = false;
+            to_json(&inner.days)?
+        };
+        write_file(path, &json)
+    }
+
+    pub fn quota(&self) -> Quota {
+        self.inner.lock().unwrap().quota.clone()
+    }
+
+    pub fn set_quota(&self, quota: Quota) {
+        self.inner.lock().unwrap().quota = quota;
+    }
+
+    pub fn record_upload(&self, peer_id: &str, bytes: u64) {
+        self.record(peer_id, |c| c.uploaded += bytes);
+    }
+
+    pub fn record_download(&self, peer_id: &str, bytes: u64) {
+        self.record(peer_id, |c| c.downloaded += bytes);
+    }
+
+    /// Internet bytes fetched on behalf of `peer_id`.
+    pub fn record_proxied(&self, peer_id: &str, bytes: u64) {
+        self.record(peer_id, |c| c.proxied += bytes);
+    }
+
+    fn record(&self, peer_id: &str, update: impl FnOnce(&mut Counters)) {
+        let mut inner = self.inner.lock().unwrap();
+        let day = inner.days.entry(today()).or_default();
+        update(day.entry(peer_id.to_string()).or_default());
+        inner.dirty = true;
+    }
+
+    /// Whether we have used up our allowance for serving other peers.
+    pub fn upload_quota_reached(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        let quota = &inner.quota;
+        let day = totals(&inner.days, &today());
+        let month = totals(&inner.days, &this_month());
+        (quota.daily_upload > 0 && day.uploaded >= quota.daily_upload)
+            || (quota.monthly_upload > 0 && month.uploaded >= quota.monthly_upload)
+    }
+
+    pub fn proxy_quota_reached(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        let month = totals(&inner.days, &this_month());
+        inner.quota.monthly_proxy > 0 && month.proxied >= inner.quota.monthly_proxy
+    }
+
+    pub fn report(&self) -> UsageReport {
+        let (today, month) = (today(), this_month());
+        let (quota, days) = {
+            let inner = self.inner.lock().unwrap();
+            (inner.quota.clone(), inner.days.clone())
+        };
+        UsageReport {
+            upload_quota_reached: self.upload_quota_reached(),
+            proxy_quota_reached: self.proxy_quota_reached(),
+            quota,
+            today: totals(&days, &today),
+            this_month: totals(&days, &month),
+            peers_today: per_peer(&days, &today),
+            peers_this_month: per_peer(&days, &month),
+            days,
+        }
+    }
+}
+
+/// Saves usage to disk every 30 seconds while the node runs, and once more on shutdown.
+pub async fn run_usage_flush(state: Arc<AppState>) {
+    let mut stopping = false;
+    while !stopping {
+        tokio::select! {
+            _ = tokio::time::sleep(FLUSH_INTERVAL) => {}
+            _ = state.shutdown.cancelled() => stopping = true,
+        }
+        if let Err(e) = state.usage.save() {
+            warn!(error = %e, "Failed to save usage");
+        }
+    }
+}
+
+/// Drops the records of days more than `RETENTION_DAYS` before `today`.
+fn drop_old_days(days: &mut BTreeMap<String, HashMap<String, Counters>>, today: i64) {
+    let oldest = date(today - RETENTION_DAYS);
+    days.retain(|day, _| *day >= oldest);
+}
+
+/// Sums every day whose key starts with `prefix` (a day or a month).
+fn totals(days: &BTreeMap<String, HashMap<String, Counters>>, prefix: &str) -> Counters {
+    let mut sum = Counters::default();
+    for peer in days.iter().filter(|(d, _)| d.starts_with(prefix)).flat_map(|(_, peers)| peers.values()) {
+        sum.add(peer);
+    }
+    sum
+}
+
+fn per_peer(days: &BTreeMap<String, HashMap<String, Counters>>, prefix: &str) -> HashMap<String, Counters> {
+    let mut sums: HashMap<String, Counters> = HashMap::new();
+    for (peer, counters) in days.iter().filter(|(d, _)| d.starts_with(prefix)).flat_map(|(_, peers)| peers.iter()) {
+        sums.entry(peer.clone()).or_default().add(counters);
+    }
+    sums
+}
+
+/// Today's date in UTC as `YYYY-MM-DD`.
+fn today() -> String {
+    date(days_since_epoch())
+}
+
+fn days_since_epoch() -> i64 {
+    (SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 86_400) as i64
+}
+
+/// Days since 1970-01-01 as `YYYY-MM-DD`, which sorts by date.
+fn date(days: i64) -> String {
+    let (y, m, d) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+/// The current month in UTC as `YYYY-MM`.
+fn this_month() -> String {
+    today()[..7].to_string()
+}
+
+/// Converts days since 1970-01-01 to a (year, month, day) date.
+/// See Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    (y, m, d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates_across_month_and_year_ends() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(-1), "1969-12-31");
+        assert_eq!(date(30), "1970-01-31");
+        assert_eq!(date(31), "1970-02-01");
+        assert_eq!(date(58), "1970-02-28");
+        assert_eq!(date(59), "1970-03-01");
+        assert_eq!(date(10_956), "1999-12-31");
+        assert_eq!(date(10_957), "2000-01-01");
+    }
+
+    #[test]
+    fn dates_in_leap_years() {
+        // 2000 is a leap year (divisible by 400), 2100 isn't (by 100), 2024 is.
+        assert_eq!(date(11_016), "2000-02-29");
+        assert_eq!(date(11_017), "2000-03-01");
+        assert_eq!(date(19_782), "2024-02-29");
+        assert_eq!(date(19_783), "2024-03-01");
+        assert_eq!(date(47_540), "2100-02-28");
+        assert_eq!(date(47_541), "2100-03-01");
+        assert_eq!(civil_from_days(19_722), (2023, 12, 31));
+    }
+
+    #[test]
+    fn keeps_400_days() {
+        let today = 20_000;
+        let mut days: BTreeMap<String, HashMap<String, Counters>> =
+            [today, today - 1, today - RETENTION_DAYS, today - RETENTION_DAYS - 1, today - 1000]
+                .into_iter()
+                .map(|d| (date(d), HashMap::new()))
+                .collect();
+        drop_old_days(&mut days, today);
+        let kept: Vec<String> = days.into_keys().collect();
+        assert_eq!(kept, vec![date(today - RETENTION_DAYS), date(today - 1), date(today)]);
+    }
+
+    #[test]
+    fn quotas_trip_at_their_limit() {
+        let tracker = UsageTracker::default();
+        assert!(!tracker.upload_quota_reached());
+        tracker.record_upload("a", 1000);
+        assert!(!tracker.upload_quota_reached(), "no quota set");
+
+        tracker.set_quota(Quota { daily_upload: 1500, ..Quota::default() });
+        tracker.record_upload("b", 499);
+        assert!(!tracker.upload_quota_reached());
+        tracker.record_upload("b", 1);
+        assert!(tracker.upload_quota_reached());
+
+        tracker.set_quota(Quota { monthly_upload: 2000, ..Quota::default() });
+        assert!(!tracker.upload_quota_reached());
+        tracker.record_download("a", 5000);
+        assert!(!tracker.upload_quota_reached(), "downloads don't count");
+        tracker.record_upload("a", 500);
+        assert!(tracker.upload_quota_reached());
+    }
+
+    #[test]
+    fn proxy_quota_counts_proxied_bytes() {
+        let tracker = UsageTracker::default();
+        tracker.set_quota(Quota { monthly_proxy: 100, ..Quota::default() });
+        tracker.record_upload("a", 1000);
+        assert!(!tracker.proxy_quota_reached());
+        tracker.record_proxied("a", 99);
+        assert!(!tracker.proxy_quota_reached());
+        tracker.record_proxied("b", 1);
+        assert!(tracker.proxy_quota_reached());
+        let report = tracker.report();
+        assert_eq!(report.this_month.proxied, 100);
+        assert_eq!(report.peers_today["a"].uploaded, 1000);
+    }
+}
@@ -1,99 +1,864 @@
 use axum::{
-    extract::State,
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{Path, Request, State},
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event as SseEvent, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     routing::{get, post},
     Json, Router,
 };
 use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::net::TcpListener;
-use peapod::state::AppState;
-use uuid::Uuid;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn, Instrument};
+use crate::chunk::{choose_chunk_size, TransferInfo};
+use crate::collection::{create_dirs, prepare_tree, scan_dir, validate_manifest};
+use crate::config::ApiConfig;
+use crate::error::{Error, Result};
+use crate::events::Event;
+use crate::fetch::{probe, start_url_download};
+use crate::metrics;
+use crate::offer::{valid_offer, OfferDirection, OfferInfo, OfferStatus, MAX_OFFER_FILES};
+use crate::protocol::{ErrorCode, Manifest, Message, OfferedFile};
+use crate::ratelimit::Limits;
+use crate::sandbox::relative_path;
+use crate::scheduler::run_download;
+use crate::state::AppState;
+use crate::transport::request_manifest;
+use crate::usage::Counters;
 
-#[derive(Serialize)]
-struct PeerInfo {
-    id: String,
-    name: String,
-    port: u16,
-    is_connected: bool,
+#[derive(Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub id: String,
+    pub name: String,
+    pub port: u16,
+    pub is_connected: bool,
+    /// Estimated bytes per second
+    pub throughput: f64,
+    /// Estimated round-trip time in seconds
+    pub rtt: f64,
+    pub out_of_quota: bool,
+    pub paired: bool,
+    pub blocked: bool,
 }
 
-#[derive(Serialize)]
-struct StatusResponse {
-    peers: Vec<PeerInfo>,
-    active_transfers: usize,
+#[derive(Serialize, Deserialize)]
+pub struct UsageSummary {
+    pub today: Counters,
+    pub this_month: Counters,
+    pub upload_quota_reached: bool,
+    pub proxy_quota_reached: bool,
 }
 
-#[derive(Deserialize)]
-struct TransferRequest {
-    file_path: String,
-    file_size: u64,
-    output_path: String,
+#[derive(Serialize, Deserialize)]
+pub struct StatusResponse {
+    pub peers: Vec<PeerInfo>,
+    pub active_transfers: usize,
+    pub usage: UsageSummary,
 }
 
-#[derive(Serialize)]
-struct TransferResponse {
-    transfer_id: String,
-    status: String,
+#[derive(Serialize, Deserialize)]
+pub struct TransferRequest {
+    /// File id as registered on the peers that hold it
+    pub file_id: String,
+    pub output_path: String,
+    /// Peers to pull from (defaults to every connected peer that holds the file)
+    #[serde(default)]
+    pub peers: Vec<String>,
+    /// Replace `output_path` if it already exists
+    #[serde(default)]
+    pub overwrite: bool,
 }
 
-pub async fn start_webserver(state: Arc<AppState>) {
+#[derive(Serialize, Deserialize)]
+pub struct DownloadRequest {
+    pub url: String,
+    /// Relative paths are placed in the download directory
+    pub output_path: String,
+    #[serde(default)]
+    pub overwrite: bool,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct ShareRequest {
+    /// Path of a local file or directory inside a share root, as seen by the daemon
+    pub path: String,
+    /// For a directory, start a new chunk at every file
+    #[serde(default)]
+    pub align_files: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OfferRequest {
+    /// Device id of a connected peer
+    pub peer: String,
+    /// Local files and directories inside the share roots, as seen by the daemon
+    pub paths: Vec<String>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct AcceptOfferRequest {
+    /// Directory to save the files in; relative paths are inside the download directory
+    #[serde(default)]
+    pub output_dir: String,
+    /// Replace files that already exist
+    #[serde(default)]
+    pub overwrite: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TransferResponse {
+    pub transfer_id: String,
+    pub status: String,
+}
+
+/// Who may use the API besides local processes.
+struct Access {
+    /// Required as `Authorization: Bearer <token>` on every request when set.
+    token: Option<String>,
+    /// Browser origins allowed to call the API cross-origin.
+    cors_origins: Vec<String>,
+    /// The TCP address the API is bound to; `None` on a Unix socket, where
+    /// the Host header isn't checked.
+    bind: Option<IpAddr>,
+}
+
+/// Where the control API listens.
+pub enum ApiListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, PathBuf),
+}
+
+/// Opens the control API's socket. A non-loopback address without a token is refused.
+pub async fn bind_webserver(config: &ApiConfig) -> Result<ApiListener> {
+    if config.socket.is_none() && !config.bind.is_loopback() && config.token.is_none() {
+        let message = format!("refusing to expose the control API on {} without an API token; set [api] token", config.bind);
+        return Err(Error::Forbidden(message));
+    }
+    #[cfg(unix)]
+    if let Some(path) = &config.socket {
+        return bind_unix(path)
+            .map(|listener| ApiListener::Unix(listener, path.clone()))
+            .map_err(|source| Error::Bind { service: "the control API", addr: path.display().to_string(), source });
+    }
+    #[cfg(not(unix))]
+    if config.socket.is_some() {
+        warn!("Unix sockets are not supported on this platform, using TCP");
+    }
+
+    let addr = SocketAddr::new(config.bind, config.port);
+    TcpListener::bind(addr)
+        .await
+        .map(ApiListener::Tcp)
+        .map_err(|source| Error::Bind { service: "the control API", addr: addr.to_string(), source })
+}
+
+pub async fn start_webserver(listener: ApiListener, state: Arc<AppState>, config: ApiConfig) {
+    let access = Arc::new(Access {
+        token: config.token.clone(),
+        cors_origins: config.cors_origins.clone(),
+        bind: match &listener {
+            ApiListener::Tcp(_) => Some(config.bind),
+            #[cfg(unix)]
+            ApiListener::Unix(..) => None,
+        },
+    });
+
     // Create the web server router
     let app = Router::new()
         .route("/status", get(get_status))
         .route("/transfer", post(start_transfer))
-        .with_state(state);
+        .route("/download", post(start_download))
+        .route("/share", post(share_file))
+        .route("/transfers", get(list_transfers))
+        .route("/transfers/:id", get(get_transfer).delete(cancel_transfer))
+        .route("/offers", get(list_offers).post(start_offer))
+        .route("/offers/:id", get(get_offer).delete(remove_offer))
+        .route("/offers/:id/accept", post(accept_offer_handler))
+        .route("/offers/:id/decline", post(decline_offer_handler))
+        .route("/limits", get(get_limits).put(set_limits))
+        .route("/usage", get(get_usage))
+        .route("/ledger", get(get_ledger))
+        .route("/events", get(stream_events))
+        .route("/metrics", get(get_metrics))
+        .layer(middleware::from_fn_with_state(access, check_access))
+        .with_state(state.clone());
 
-    // Bind to all interfaces (0.0.0.0) on port 8080
-    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    let endpoints = [
+        "Status endpoint: GET /status",
+        "Transfer endpoint: POST /transfer",
+        "URL download: POST /download",
+        "Share a file: POST /share",
+        "Transfers: GET /transfers, GET/DELETE /transfers/:id",
+        "Offers: GET/POST /offers, GET/DELETE /offers/:id, POST /offers/:id/accept, POST /offers/:id/decline",
+        "Bandwidth limits: GET/PUT /limits",
+        "Data usage: GET /usage",
+        "Peer balances: GET /ledger",
+        "Live events (SSE): GET /events",
+        "Prometheus metrics: GET /metrics",
+    ];
 
-    println!("🚀 Webserver started on http://0.0.0.0:8080");
-    println!("   - Status endpoint: GET /status");
-    println!("   - Transfer endpoint: POST /transfer");
+    match listener {
+        #[cfg(unix)]
+        ApiListener::Unix(listener, path) => {
+            info!(socket = %path.display(), "Control API running");
+            log_endpoints(&endpoints);
+            if let Err(e) = serve_unix(app, listener, &path, &state).await {
+                warn!(socket = %path.display(), error = %e, "Control socket failed");
+            }
+        }
+        ApiListener::Tcp(listener) => {
+            if let Ok(addr) = listener.local_addr() {
+                info!(%addr, "Control API running");
+            }
+            log_endpoints(&endpoints);
 
-    // Run the server
-    axum::serve(listener, app).await.unwrap();
+            // Run the server
+            if let Err(e) = axum::serve(listener, app).with_graceful_shutdown(state.shutdown.clone().cancelled_owned()).await {
+                warn!(error = %e, "Control API failed");
+            }
+        }
+    }
 }
 
-async fn get_status(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+fn log_endpoints(endpoints: &[&str]) {
+    for endpoint in endpoints {
+        debug!("{}", endpoint);
+    }
+}
+
+/// A Unix socket only the current user can connect to.
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // Bind under a temporary name and restrict it before it appears at `path`.
+    let staging = path.with_extension("new");
+    let _ = std::fs::remove_file(&staging);
+    let listener = tokio::net::UnixListener::bind(&staging)?;
+    std::fs::set_permissions(&staging, std::fs::Permissions::from_mode(0o600))?;
+    std::fs::rename(&staging, path)?;
+    Ok(listener)
+}
+
+/// Serves the API on the socket from `bind_unix`, removing it when done.
+/// On shutdown, open connections finish their current request and close.
+#[cfg(unix)]
+async fn serve_unix(app: Router, listener: tokio::net::UnixListener, path: &std::path::Path, state: &AppState) -> std::io::Result<()> {
+    use hyper::server::conn::http1;
+    use hyper_util::rt::TokioIo;
+    use hyper_util::service::TowerToHyperService;
+
+    loop {
+        let (stream, _) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = state.shutdown.cancelled() => break,
+        };
+        let service = TowerToHyperService::new(app.clone());
+        let shutdown = state.shutdown.clone();
+        state.tasks.spawn(async move {
+            let conn = http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+            tokio::pin!(conn);
+            let result = tokio::select! {
+                result = conn.as_mut() => result,
+                _ = shutdown.cancelled() => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(e) = result {
+                debug!(error = %e, "Control socket connection error");
+            }
+        });
+    }
+    std::fs::remove_file(path)
+}
+
+/// Rejects requests for a host name other than the API's own (so a web page
+/// can't reach it through DNS rebinding), from browser origins that aren't
+/// allowed (answering CORS preflights for those that are) and, if a token is
+/// configured, requests without it.
+async fn check_access(State(access): State<Arc<Access>>, request: Request, next: Next) -> Response {
+    if let Some(bind) = access.bind {
+        let host = request.headers().get(header::HOST).and_then(|h| h.to_str().ok());
+        if !host.is_some_and(|h| host_allowed(h, bind)) {
+            return (StatusCode::FORBIDDEN, "Host not allowed").into_response();
+        }
+    }
+    let origin = request.headers().get(header::ORIGIN).cloned();
+    if let Some(origin) = &origin {
+        let allowed = origin.to_str().is_ok_and(|o| access.cors_origins.iter().any(|allowed| allowed == o));
+        if !allowed {
+            return (StatusCode::FORBIDDEN, "Origin not allowed").into_response();
+        }
+        if request.method() == Method::OPTIONS {
+            let mut response = StatusCode::NO_CONTENT.into_response();
+            let headers = response.headers_mut();
+            headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_static("GET, POST, PUT, DELETE"));
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_static("authorization, content-type"));
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static("600"));
+            allow_origin(&mut response, origin);
+            return response;
+        }
+    }
+
+    if let Some(token) = &access.token {
+        let presented = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        if !presented.is_some_and(|p| constant_time_eq(p.as_bytes(), token.as_bytes())) {
+            return (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")], "Missing or wrong API token").into_response();
+        }
+    }
+
+    let mut response = next.run(request).await;
+    if let Some(origin) = &origin {
+        allow_origin(&mut response, origin);
+    }
+    response
+}
+
+/// Whether a Host header names this machine: `localhost`, a loopback
+/// address or the address the API is bound to (any address if it is bound
+/// to all of them).
+fn host_allowed(host: &str, bind: IpAddr) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']') {
+            Some((name, _)) => name,
+            None => return false,
+        },
+        None => host.rsplit_once(':').map_or(host, |(name, _)| name),
+    };
+    if name.eq_ignore_ascii_case("localhost") {
+        return true;
+    }
+    name.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback() || ip == bind || bind.is_unspecified())
+}
+
+fn allow_origin(response: &mut Response, origin: &HeaderValue) {
+    let headers = response.headers_mut();
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+    headers.insert(header::VARY, HeaderValue::from_static("Origin"));
+}
+
+/// Compares without returning early, so response timing doesn't leak the token.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Every discovered peer with its connection state and transfer stats.
+pub fn peer_list(state: &AppState) -> Vec<PeerInfo> {
     let peers = state.peers.lock().unwrap();
     let active_connections = state.active_connections.lock().unwrap();
+    let stats = state.scheduler.peer_stats();
 
-    let peer_info: Vec<PeerInfo> = peers
+    peers
         .values()
-        .map(|beacon| PeerInfo {
-            id: beacon.device_id.clone(),
-            name: beacon.name.clone(),
-            port: beacon.port,
-            is_connected: active_connections.get(&beacon.device_id).copied().unwrap_or(false),
+        .map(|beacon| {
+            let peer_stats = stats.get(&beacon.device_id).cloned().unwrap_or_default();
+            PeerInfo {
+                id: beacon.device_id.clone(),
+                name: beacon.name.clone(),
+                port: beacon.port,
+                is_connected: active_connections.get(&beacon.device_id).copied().unwrap_or(false),
+                throughput: peer_stats.throughput,
+                rtt: peer_stats.rtt,
+                out_of_quota: beacon.out_of_quota,
+                paired: state.devices.is_paired(&beacon.device_id),
+                blocked: state.devices.is_blocked(&beacon.device_id),
+            }
         })
-        .collect();
+        .collect()
+}
 
+async fn get_status(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let peer_info = peer_list(&state);
     let active_transfers = state.chunk_manager.get_active_transfers();
 
+    let usage = state.usage.report();
+
     let response = StatusResponse {
         peers: peer_info,
         active_transfers,
+        usage: UsageSummary {
+            today: usage.today,
+            this_month: usage.this_month,
+            upload_quota_reached: usage.upload_quota_reached,
+            proxy_quota_reached: usage.proxy_quota_reached,
+        },
     };
 
     Json(response)
 }
 
 async fn start_transfer(
-    State(_state): State<Arc<AppState>>,
-    Json(_request): Json<TransferRequest>,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
-    // In a real implementation, this would start a file transfer
-    // For now, we'll just simulate it
-    let transfer_id = uuid::Uuid::new_v4().to_string();
-
-    // This is where we would actually start the transfer using the chunk manager
-    // For now, we just return a success response
-    let response = TransferResponse {
-        transfer_id,
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<TransferRequest>,
+) -> Result<Json<TransferResponse>> {
+    transfer(state, request).await.map(Json)
+}
+
+/// Fetches the manifest from one of the peers and starts downloading from
+/// every peer that holds the file, or only from `request.peers` if given.
+pub async fn transfer(state: Arc<AppState>, request: TransferRequest) -> Result<TransferResponse> {
+    let sources = request.peers;
+    let peers = if sources.is_empty() {
+        state.peer_links.lock().unwrap().keys().cloned().collect()
+    } else {
+        sources.clone()
+    };
+    if peers.is_empty() {
+        return Err(Error::Unavailable("No connected peers".to_string()));
+    }
+
+    let mut manifest = Err(Error::NotFound("No peer has this file".to_string()));
+    for peer in &peers {
+        match request_manifest(&state, peer, &request.file_id).await {
+            Ok(found) => {
+                manifest = Ok(found);
+                break;
+            }
+            // Peers that don't have it leave the default error standing.
+            Err(Error::Remote { code: ErrorCode::UnknownFile, .. }) => {}
+            Err(e) => manifest = Err(e),
+        }
+    }
+    let manifest = manifest?;
+    validate_manifest(&manifest).map_err(Error::Invalid)?;
+
+    // A collection's output path is the directory its tree is recreated in.
+    let requested = std::path::Path::new(&request.output_path);
+    let output_path = match &manifest.collection {
+        Some(collection) => {
+            let root = state.sandbox.output_dir(requested).map_err(Error::Forbidden)?;
+            prepare_tree(&state.sandbox, &root, collection, request.overwrite).map_err(Error::Forbidden)?;
+            root
+        }
+        None => state.sandbox.output_path(requested, request.overwrite).map_err(Error::Forbidden)?,
+    };
+
+    state.chunk_manager.join_transfer(manifest, output_path.to_string_lossy().into_owned())?;
+    state.tasks.spawn(run_download(state.clone(), request.file_id.clone(), sources).in_current_span());
+
+    Ok(TransferResponse {
+        transfer_id: request.file_id,
+        status: "started".to_string(),
+    })
+}
+
+async fn start_download(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<DownloadRequest>,
+) -> Result<Json<TransferResponse>> {
+    download(state, request).await.map(Json)
+}
+
+/// Probes the URL and starts fetching it through the swarm.
+pub async fn download(state: Arc<AppState>, request: DownloadRequest) -> Result<TransferResponse> {
+    let output_path = state
+        .sandbox
+        .output_path(std::path::Path::new(&request.output_path), request.overwrite)
+        .map_err(Error::Forbidden)?;
+    let info = probe(&state.http, &request.url).await.map_err(Error::Upstream)?;
+    let output_path = output_path.to_string_lossy().into_owned();
+    let (file_id, download) = start_url_download(state.clone(), request.url, &info, output_path)?;
+
+    // A download that gives up is dropped, so clients polling it see it's gone.
+    let cleanup_state = state.clone();
+    let cleanup_id = file_id.clone();
+    state.tasks.spawn(async move {
+        if !download.await.unwrap_or(false) {
+            cleanup_state.chunk_manager.remove_transfer(&cleanup_id);
+        }
+    });
+
+    Ok(TransferResponse {
+        transfer_id: file_id,
         status: "started".to_string(),
+    })
+}
+
+async fn share_file(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<ShareRequest>,
+) -> Result<Json<Manifest>> {
+    share(&state, request).await.map(Json)
+}
+
+/// Registers a local file, or a directory as a collection, so peers can
+/// download it by the returned manifest's file id.
+pub async fn share(state: &AppState, request: ShareRequest) -> Result<Manifest> {
+    let requested = std::path::Path::new(&request.path);
+    let file_id = if requested.is_dir() {
+        let root = state.sandbox.share_dir(requested).map_err(Error::Forbidden)?;
+        let scan_root = root.clone();
+        let collection = tokio::task::spawn_blocking(move || scan_dir(&scan_root, request.align_files))
+            .await
+            .map_err(|e| Error::Io(std::io::Error::other(e)))??;
+        let size = collection.files.iter().map(|f| f.size).sum();
+        let chunk_size = choose_chunk_size(size, state.scheduler.total_throughput());
+        let root = root.to_string_lossy().into_owned();
+        state.chunk_manager.register_source_collection(root, collection, chunk_size).await?
+    } else {
+        let path = state.sandbox.share_path(requested).map_err(Error::Forbidden)?;
+        let size = tokio::fs::metadata(&path).await.map_err(|e| Error::NotFound(e.to_string()))?.len();
+        let chunk_size = choose_chunk_size(size, state.scheduler.total_throughput());
+        state.chunk_manager.register_source_file(path.to_string_lossy().into_owned(), size, chunk_size).await?
     };
+    if let Some(transfer) = state.chunk_manager.transfer(&file_id) {
+        state.events.publish(Event::TransferCreated { transfer });
+    }
+    state
+        .chunk_manager
+        .manifest(&file_id)
+        .ok_or_else(|| Error::NotFound("Share was cancelled".to_string()))
+}
+
+async fn start_offer(State(state): State<Arc<AppState>>, Json(request): Json<OfferRequest>) -> Result<Json<OfferInfo>> {
+    send_offer(&state, request).await.map(Json)
+}
+
+/// Shares the files and directories in `request.paths` and offers them to a
+/// peer, which pulls them once its user accepts. Each directory goes as one
+/// collection.
+pub async fn send_offer(state: &AppState, request: OfferRequest) -> Result<OfferInfo> {
+    if !state.peer_links.lock().unwrap().contains_key(&request.peer) {
+        return Err(Error::Unavailable(format!("not connected to {}", request.peer)));
+    }
+    if request.paths.is_empty() {
+        return Err(Error::Invalid("nothing to offer".to_string()));
+    }
+    if request.paths.len() > MAX_OFFER_FILES {
+        return Err(Error::Invalid(format!("more than {} paths", MAX_OFFER_FILES)));
+    }
+
+    let mut files = Vec::new();
+    for path in request.paths {
+        let shared = share(state, ShareRequest { path: path.clone(), ..Default::default() }).await;
+        match shared {
+            Ok(manifest) => files.push(OfferedFile { path: manifest.file_name.clone(), manifest }),
+            Err(e) => {
+                unshare(state, &files);
+                return Err(e);
+            }
+        }
+    }
+    if !valid_offer(&files) {
+        unshare(state, &files);
+        return Err(Error::Invalid("two of the offered paths have the same name".to_string()));
+    }
+    let offer = state.offers.create(&request.peer, files.clone());
+    if !state.send_to_peer(&request.peer, Message::Offer { offer_id: offer.offer_id.clone(), files }) {
+        state.offers.remove(&offer.offer_id);
+        unshare(state, &offer.files);
+        return Err(Error::Unavailable(format!("not connected to {}", request.peer)));
+    }
+    info!(offer_id = %offer.offer_id, peer = %request.peer, files = offer.files.len(), "Offer sent");
+    Ok(offer)
+}
+
+fn unshare(state: &AppState, files: &[OfferedFile]) {
+    for file in files {
+        state.chunk_manager.remove_transfer(&file.manifest.file_id);
+    }
+}
+
+async fn list_offers(State(state): State<Arc<AppState>>) -> Json<Vec<OfferInfo>> {
+    Json(state.offers.list())
+}
+
+async fn get_offer(State(state): State<Arc<AppState>>, Path(id): Path<String>) -> Result<Json<OfferInfo>, StatusCode> {
+    state.offers.get(&id).map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn accept_offer_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Json(request): Json<AcceptOfferRequest>,
+) -> Result<Json<OfferInfo>> {
+    accept_offer(state, &id, request).await.map(Json)
+}
+
+/// Accepts an incoming offer and starts downloading its files from the
+/// sender, laid out under `request.output_dir` as the sender had them.
+pub async fn accept_offer(state: Arc<AppState>, offer_id: &str, request: AcceptOfferRequest) -> Result<OfferInfo> {
+    let offer = pending_offer(&state, offer_id)?;
+    let dir = state.sandbox.output_dir(std::path::Path::new(&request.output_dir)).map_err(Error::Forbidden)?;
+    // Every target is checked before anything is downloaded.
+    let mut targets = Vec::new();
+    for file in &offer.files {
+        let relative = relative_path(&file.path).ok_or_else(|| Error::Invalid(format!("unsafe path {}", file.path)))?;
+        // The path comes from the peer, so its directories are made without following symlinks.
+        let target = match &file.manifest.collection {
+            Some(collection) => {
+                let root = create_dirs(&dir, &relative).map_err(Error::Forbidden)?;
+                prepare_tree(&state.sandbox, &root, collection, request.overwrite).map_err(Error::Forbidden)?;
+                root
+            }
+            None => {
+                let (Some(parent), Some(name)) = (relative.parent(), relative.file_name()) else {
+                    return Err(Error::Invalid(format!("unsafe path {}", file.path)));
+                };
+                let parent = create_dirs(&dir, parent).map_err(Error::Forbidden)?;
+                state.sandbox.output_path(&parent.join(name), request.overwrite).map_err(Error::Forbidden)?
+            }
+        };
+        targets.push(target);
+    }
+    let reply = Message::OfferReply { offer_id: offer_id.to_string(), accepted: true };
+    if !state.send_to_peer(&offer.peer, reply) {
+        return Err(Error::Unavailable(format!("not connected to {}", offer.peer)));
+    }
+
+    let offer = state.offers.set_status(offer_id, OfferStatus::Accepted).unwrap_or(offer);
+    for (file, target) in offer.files.iter().zip(targets) {
+        let file_id = file.manifest.file_id.clone();
+        state.chunk_manager.join_transfer(file.manifest.clone(), target.to_string_lossy().into_owned())?;
+        let download = run_download(state.clone(), file_id, vec![offer.peer.clone()]);
+        let (state, offer_id, path) = (state.clone(), offer_id.to_string(), file.path.clone());
+        state.clone().tasks.spawn(async move {
+            let still_going = || state.offers.get(&offer_id).is_some_and(|o| o.status == OfferStatus::Accepted);
+            if !download.await && still_going() {
+                state.offers.set_status(&offer_id, OfferStatus::Failed);
+                state.events.publish(Event::OfferFailed { offer_id, reason: format!("{} did not arrive", path) });
+            }
+        }.in_current_span());
+    }
+    info!(%offer_id, dir = %dir.display(), "Offer accepted");
+    state.events.publish(Event::OfferAccepted { offer_id: offer_id.to_string() });
+    if offer.status == OfferStatus::Completed {
+        state.events.publish(Event::OfferCompleted { offer_id: offer_id.to_string() });
+    }
+    Ok(offer)
+}
+
+async fn decline_offer_handler(State(state): State<Arc<AppState>>, Path(id): Path<String>) -> Result<StatusCode> {
+    decline_offer(&state, &id).map(|()| StatusCode::NO_CONTENT)
+}
+
+pub fn decline_offer(state: &AppState, offer_id: &str) -> Result<()> {
+    let offer = pending_offer(state, offer_id)?;
+    // The sender finds out when it next hears from us either way.
+    state.send_to_peer(&offer.peer, Message::OfferReply { offer_id: offer_id.to_string(), accepted: false });
+    state.offers.set_status(offer_id, OfferStatus::Declined);
+    info!(%offer_id, "Offer declined");
+    state.events.publish(Event::OfferDeclined { offer_id: offer_id.to_string() });
+    Ok(())
+}
+
+/// An incoming offer still waiting for an answer.
+fn pending_offer(state: &AppState, offer_id: &str) -> Result<OfferInfo> {
+    let offer = state
+        .offers
+        .get(offer_id)
+        .filter(|o| o.direction == OfferDirection::Incoming)
+        .ok_or_else(|| Error::NotFound(format!("no incoming offer {}", offer_id)))?;
+    if offer.status != OfferStatus::Pending {
+        return Err(Error::Invalid(format!("offer {} was already answered", offer_id)));
+    }
+    Ok(offer)
+}
+
+async fn remove_offer(State(state): State<Arc<AppState>>, Path(id): Path<String>) -> Result<StatusCode> {
+    cancel_offer(&state, &id).map(|()| StatusCode::NO_CONTENT)
+}
+
+/// Forgets an offer. A pending incoming one is declined first, and the files
+/// of an outgoing one stop being shared. Files of an accepted incoming offer
+/// keep downloading, as transfers of their own.
+pub fn cancel_offer(state: &AppState, offer_id: &str) -> Result<()> {
+    if pending_offer(state, offer_id).is_ok() {
+        decline_offer(state, offer_id)?;
+    }
+    let offer = state.offers.remove(offer_id).ok_or_else(|| Error::NotFound(format!("no offer {}", offer_id)))?;
+    if offer.direction == OfferDirection::Outgoing {
+        unshare(state, &offer.files);
+    }
+    Ok(())
+}
+
+async fn list_transfers(State(state): State<Arc<AppState>>) -> Json<Vec<TransferInfo>> {
+    Json(state.chunk_manager.transfers())
+}
+
+async fn get_transfer(State(state): State<Arc<AppState>>, Path(id): Path<String>) -> Result<Json<TransferInfo>, StatusCode> {
+    state.chunk_manager.transfer(&id).map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn cancel_transfer(State(state): State<Arc<AppState>>, Path(id): Path<String>) -> StatusCode {
+    if state.chunk_manager.remove_transfer(&id) { StatusCode::NO_CONTENT } else { StatusCode::NOT_FOUND }
+}
+
+/// Server-sent events, one JSON `Event` per message, named after its type.
+/// A subscriber that falls too far behind silently skips what it missed.
+async fn stream_events(State(state): State<Arc<AppState>>) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    let (tx, rx) = mpsc::channel(16);
+    state.tasks.spawn(forward_events(state.events.subscribe(), tx, state.shutdown.clone()));
+    Sse::new(ReceiverStream::new(rx)).keep_alive(KeepAlive::default())
+}
+
+/// Feeds one `/events` client until it disconnects or the node shuts down,
+/// which ends the response so the server can stop.
+async fn forward_events(
+    mut events: tokio::sync::broadcast::Receiver<Event>,
+    tx: mpsc::Sender<Result<SseEvent, Infallible>>,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = shutdown.cancelled() => return,
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+        let Ok(json) = serde_json::to_value(&event) else { continue };
+        let name = json["type"].as_str().unwrap_or("event").to_string();
+        if tx.send(Ok(SseEvent::default().event(name).data(json.to_string()))).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn get_limits(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(state.rate_limiter.limits())
+}
+
+async fn set_limits(State(state): State<Arc<AppState>>, Json(limits): Json<Limits>) -> impl IntoResponse {
+    state.rate_limiter.set_limits(limits);
+    Json(state.rate_limiter.limits())
+}
+
+async fn get_usage(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(state.usage.report())
+}
 
-    Ok(Json(response))
-}
\ No newline at end of file
+async fn get_ledger(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(state.ledger.balances())
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::Invalid(_) | Error::OutOfRange { .. } => StatusCode::BAD_REQUEST,
+            Error::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Upstream(_) | Error::Remote { .. } => StatusCode::BAD_GATEWAY,
+            Error::Io(_) | Error::Bind { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+/// Prometheus scrape target.
+async fn get_metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], metrics::render(&state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use tower::Service;
+
+    fn app(bind: Option<IpAddr>, token: Option<&str>) -> Router {
+        let access = Arc::new(Access {
+            token: token.map(String::from),
+            cors_origins: vec!["tauri://localhost".into()],
+            bind,
+        });
+        Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(access, check_access))
+    }
+
+    async fn status(mut app: Router, method: Method, headers: &[(header::HeaderName, &str)]) -> (StatusCode, Option<HeaderValue>) {
+        let mut request = Request::builder().method(method).uri("/");
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+        // A router is always ready, so it can be called straight away.
+        let response = app.call(request.body(Body::empty()).unwrap()).await.unwrap();
+        (response.status(), response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).cloned())
+    }
+
+    #[test]
+    fn host_must_name_this_machine() {
+        let loopback = IpAddr::from([127, 0, 0, 1]);
+        for host in ["localhost", "LocalHost:8080", "127.0.0.1:8080", "127.1.2.3", "[::1]:8080", "[::1]"] {
+            assert!(host_allowed(host, loopback), "{}", host);
+        }
+        for host in ["evil.example", "evil.example:8080", "localhost.evil.example", "192.168.1.5:8080", "", "[::1"] {
+            assert!(!host_allowed(host, loopback), "{}", host);
+        }
+        let lan = IpAddr::from([192, 168, 1, 5]);
+        assert!(host_allowed("192.168.1.5:8080", lan));
+        assert!(!host_allowed("192.168.1.6:8080", lan));
+        let any = IpAddr::from([0, 0, 0, 0]);
+        assert!(host_allowed("10.0.0.7:8080", any));
+        assert!(!host_allowed("evil.example", any));
+    }
+
+    #[tokio::test]
+    async fn rejects_spoofed_hosts() {
+        let bind = Some(IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(status(app(bind, None), Method::GET, &[(header::HOST, "localhost:8080")]).await.0, StatusCode::OK);
+        assert_eq!(status(app(bind, None), Method::GET, &[(header::HOST, "rebound.example:8080")]).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(status(app(bind, None), Method::GET, &[]).await.0, StatusCode::FORBIDDEN);
+        // Nothing to rebind on a Unix socket.
+        assert_eq!(status(app(None, None), Method::GET, &[(header::HOST, "rebound.example")]).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn only_allowed_origins() {
+        let bind = Some(IpAddr::from([127, 0, 0, 1]));
+        let host = (header::HOST, "localhost");
+        let (code, allow) = status(app(bind, None), Method::GET, &[host.clone(), (header::ORIGIN, "tauri://localhost")]).await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(allow.unwrap(), "tauri://localhost");
+        let (code, allow) = status(app(bind, None), Method::GET, &[host.clone(), (header::ORIGIN, "https://evil.example")]).await;
+        assert_eq!((code, allow), (StatusCode::FORBIDDEN, None));
+        let (code, _) = status(app(bind, None), Method::OPTIONS, &[host, (header::ORIGIN, "tauri://localhost")]).await;
+        assert_eq!(code, StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn token_is_required_when_set() {
+        let bind = Some(IpAddr::from([127, 0, 0, 1]));
+        let host = (header::HOST, "localhost");
+        let token = Some("secret");
+        assert_eq!(status(app(bind, token), Method::GET, std::slice::from_ref(&host)).await.0, StatusCode::UNAUTHORIZED);
+        let wrong = (header::AUTHORIZATION, "Bearer secreT");
+        assert_eq!(status(app(bind, token), Method::GET, &[host.clone(), wrong]).await.0, StatusCode::UNAUTHORIZED);
+        let right = (header::AUTHORIZATION, "Bearer secret");
+        assert_eq!(status(app(bind, token), Method::GET, &[host.clone(), right.clone()]).await.0, StatusCode::OK);
+        // The Host check comes first, so a stolen token can't be used through a rebound name.
+        assert_eq!(status(app(bind, token), Method::GET, &[(header::HOST, "rebound.example"), right]).await.0, StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn compares_tokens_whole() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secre"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+    }
+}
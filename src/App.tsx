@@ -15,12 +15,171 @@ interface Peer extends PeerPayload {
   lastSeen: number;
 }
 
+interface Limits {
+  upload: number;
+  download: number;
+  proxy: number;
+  peer_upload: number;
+  peer_download: number;
+  peers: Record<string, { upload: number; download: number }>;
+}
+
+interface Balance {
+  given: number;
+  received: number;
+}
+
+interface Transfer {
+  file_id: string;
+  file_name: string;
+  total_size: number;
+  completed_bytes: number;
+  source_path: string | null;
+  url: string | null;
+  paused: boolean;
+  files: number;
+}
+
+interface Offer {
+  offer_id: string;
+  peer: string;
+  direction: 'incoming' | 'outgoing';
+  files: { path: string }[];
+  total_size: number;
+  completed_bytes: number;
+  status: 'pending' | 'accepted' | 'declined' | 'completed' | 'failed';
+}
+
+interface DeviceLists {
+  paired: Record<string, string>;
+  blocked: string[];
+}
+
+interface CommandError {
+  kind: string;
+  message: string;
+}
+
+const describeError = (e: unknown) => {
+  const err = e as CommandError;
+  return err && err.kind ? `${err.kind.toUpperCase()}: ${err.message}` : String(e);
+};
+
+const formatBytes = (bytes: number) => {
+  const units = ['B', 'KB', 'MB', 'GB', 'TB'];
+  let i = 0;
+  while (bytes >= 1024 && i < units.length - 1) { bytes /= 1024; i++; }
+  return `${bytes.toFixed(i === 0 ? 0 : 1)} ${units[i]}`;
+};
+
+const LIMIT_FIELDS: (keyof Omit<Limits, 'peers'>)[] = ['upload', 'download', 'proxy', 'peer_upload', 'peer_download'];
+
+interface Config {
+  node: { name: string | null };
+  discovery: { port: number; interface: string; beacon_interval_secs: number };
+  transport: { port: number; bind: string };
+  api: { port: number; bind: string };
+  limits: Limits;
+}
+
 function App() {
   const [peers, setPeers] = useState<Peer[]>([]);
+  const [config, setConfig] = useState<Config | null>(null);
+  const [configStatus, setConfigStatus] = useState('');
+  const [limits, setLimits] = useState<Limits | null>(null);
+  const [limitsStatus, setLimitsStatus] = useState('');
+  const [balances, setBalances] = useState<Record<string, Balance>>({});
+  const [transfers, setTransfers] = useState<Transfer[]>([]);
+  const [offers, setOffers] = useState<Offer[]>([]);
+  const [offerDir, setOfferDir] = useState('');
+  const [devices, setDevices] = useState<DeviceLists>({ paired: {}, blocked: [] });
+  const [source, setSource] = useState('');
+  const [saveAs, setSaveAs] = useState('');
+  const [sharePath, setSharePath] = useState('');
+  const [actionStatus, setActionStatus] = useState('');
+
+  const refreshTransfers = () => invoke<Transfer[]>('list_transfers').then(setTransfers).catch(console.error);
+  const refreshDevices = () => invoke<DeviceLists>('list_devices').then(setDevices).catch(console.error);
+  const refreshOffers = () => invoke<Offer[]>('list_offers').then(setOffers).catch(console.error);
+
+  useEffect(() => {
+    const refresh = () => {
+      invoke<Record<string, Balance>>('get_balances').then(setBalances).catch(console.error);
+      refreshTransfers();
+      refreshDevices();
+      refreshOffers();
+    };
+    refresh();
+    const timer = setInterval(refresh, 2000);
+    return () => clearInterval(timer);
+  }, []);
+
+  const run = async (command: string, args: Record<string, unknown>, done: string) => {
+    try {
+      await invoke(command, args);
+      setActionStatus(done);
+    } catch (e) {
+      setActionStatus(describeError(e));
+    }
+    refreshTransfers();
+    refreshDevices();
+    refreshOffers();
+  };
+
+  const startDownload = async () => {
+    const outputPath = saveAs || source.split(/[?#]/)[0].split('/').pop() || 'download';
+    await run('start_download', { source, outputPath, overwrite: false }, 'DOWNLOAD_STARTED');
+  };
+
+  const shareFile = async () => {
+    try {
+      const manifest = await invoke<{ file_id: string }>('share_file', { path: sharePath });
+      setActionStatus(`SHARED: ${manifest.file_id}`);
+    } catch (e) {
+      setActionStatus(describeError(e));
+    }
+    refreshTransfers();
+  };
+
+  useEffect(() => {
+    invoke<Config>('get_config').then(setConfig).catch(console.error);
+    invoke<Limits>('get_limits').then(setLimits).catch(console.error);
+  }, []);
+
+  const applyLimits = async () => {
+    if (!limits) return;
+    try {
+      setLimits(await invoke<Limits>('set_limits', { limits }));
+      setLimitsStatus('APPLIED');
+    } catch (e) {
+      setLimitsStatus(describeError(e));
+    }
+  };
+
+  const saveConfig = async () => {
+    if (!config) return;
+    try {
+      await invoke('save_config', { config });
+      setConfigStatus('SAVED (RESTART TO APPLY)');
+    } catch (e) {
+      setConfigStatus(describeError(e));
+    }
+  };
 
   useEffect(() => {
     let unlisten: (() => void) | undefined;
+    let unlistenEvents: (() => void) | undefined;
     const setupListener = async () => {
+      unlistenEvents = await listen<{ type: string; device_id?: string }>('peapod-event', (event) => {
+        const { type, device_id } = event.payload;
+        if (type === 'peer_lost') {
+          setPeers((prev) => prev.map((p) => (p.id === device_id ? { ...p, status: 'inactive' } : p)));
+        } else if (type.startsWith('transfer_')) {
+          refreshTransfers();
+        } else if (type.startsWith('offer_')) {
+          refreshOffers();
+        }
+      });
       unlisten = await listen<PeerPayload>('peer-update', (event) => {
         const newPeer = event.payload;
         setPeers((prev) => {
@@ -35,7 +194,7 @@ function App() {
       });
     };
     setupListener();
-    return () => { if (unlisten) unlisten(); };
+    return () => { if (unlisten) unlisten(); if (unlistenEvents) unlistenEvents(); };
   }, []);
 
   return (
@@ -75,6 +234,32 @@ function App() {
                         <label>ADDR</label>
                         <span className="mono">{peer.ip}:{peer.port}</span>
                       </div>
+                      <div className="stat">
+                        <label>GIVEN</label>
+                        <span className="mono">{formatBytes(balances[peer.id]?.given ?? 0)}</span>
+                      </div>
+                      <div className="stat">
+                        <label>RECEIVED</label>
+                        <span className="mono">{formatBytes(balances[peer.id]?.received ?? 0)}</span>
+                      </div>
+                      <div className="stat">
+                        {peer.id in devices.paired ? (
+                          <button onClick={() => run('unpair_device', { deviceId: peer.id }, 'UNPAIRED')}>UNPAIR</button>
+                        ) : (
+                          <button onClick={() => run('pair_device', { deviceId: peer.id }, 'PAIRED')}>PAIR</button>
+                        )}
+                        <button
+                          onClick={() => run('send_offer', { peer: peer.id, paths: [sharePath] }, 'OFFER_SENT')}
+                          disabled={!sharePath || peer.status !== 'active'}
+                        >
+                          SEND
+                        </button>
+                        {devices.blocked.includes(peer.id) ? (
+                          <button onClick={() => run('unblock_device', { deviceId: peer.id }, 'UNBLOCKED')}>UNBLOCK</button>
+                        ) : (
+                          <button onClick={() => run('block_device', { deviceId: peer.id }, 'BLOCKED')}>BLOCK</button>
+                        )}
+                      </div>
                     </div>
                   </div>
                 ))}
@@ -86,21 +271,201 @@ function App() {
         <section className="panel" style={{ marginTop: '1rem' }}>
           <div className="panel-header">
             <h3>ACTIONS</h3>
+            <span className="count">{actionStatus}</span>
+          </div>
+          <div className="panel-body settings">
+            <label>
+              URL_OR_FILE_ID
+              <input value={source} onChange={(e) => setSource(e.target.value)} />
+            </label>
+            <label>
+              SAVE_AS
+              <input value={saveAs} placeholder="(download directory)" onChange={(e) => setSaveAs(e.target.value)} />
+            </label>
+            <button onClick={startDownload} disabled={!source}>DOWNLOAD</button>
+            <label>
+              SHARE_FILE
+              <input value={sharePath} onChange={(e) => setSharePath(e.target.value)} />
+            </label>
+            <button onClick={shareFile} disabled={!sharePath}>SHARE</button>
+          </div>
+        </section>
+
+        <section className="panel" style={{ marginTop: '1rem' }}>
+          <div className="panel-header">
+            <h3>OFFERS</h3>
+            <span className="count">{offers.length}</span>
+          </div>
+          <div className="panel-body">
+            <div className="settings">
+              <label>
+                SAVE_OFFERS_IN
+                <input value={offerDir} placeholder="(download directory)" onChange={(e) => setOfferDir(e.target.value)} />
+              </label>
+            </div>
+            <div className="grid">
+              {offers.map((o) => (
+                <div key={o.offer_id} className="card">
+                  <div className="card-header">
+                    <span className="peer-name">
+                      {o.files.length === 1 ? o.files[0].path : `${o.files.length} FILES`}
+                    </span>
+                    <span className="mono">{o.direction === 'incoming' ? 'FROM' : 'TO'} {o.peer.slice(0, 8)}</span>
+                  </div>
+                  <div className="card-body">
+                    <div className="stat">
+                      <label>STATUS</label>
+                      <span className="mono">{o.status.toUpperCase()}</span>
+                    </div>
+                    <div className="stat">
+                      <label>SIZE</label>
+                      <span className="mono">{formatBytes(o.completed_bytes)} / {formatBytes(o.total_size)}</span>
+                    </div>
+                    {o.direction === 'incoming' && o.status === 'pending' && (
+                      <div className="stat">
+                        <button onClick={() => run('accept_offer', { offerId: o.offer_id, outputDir: offerDir, overwrite: false }, 'OFFER_ACCEPTED')}>
+                          ACCEPT
+                        </button>
+                        <button onClick={() => run('decline_offer', { offerId: o.offer_id }, 'OFFER_DECLINED')}>DECLINE</button>
+                      </div>
+                    )}
+                  </div>
+                </div>
+              ))}
+            </div>
+          </div>
+        </section>
+
+        <section className="panel" style={{ marginTop: '1rem' }}>
+          <div className="panel-header">
+            <h3>TRANSFERS</h3>
+            <span className="count">{transfers.length}</span>
           </div>
-          <div className="panel-body actions-body">
-            <button onClick={async () => {
-              try {
-                const res = await invoke('start_test_transfer');
-                console.log(res);
-                alert(res);
-              } catch (e) {
-                console.error(e);
-              }
-            }}>
-              INITIATE_TEST_TRANSFER
-            </button>
+          <div className="panel-body">
+            <div className="grid">
+              {transfers.map((t) => (
+                <div key={t.file_id} className="card">
+                  <div className="card-header">
+                    <span className="peer-name">{t.files > 1 ? `${t.file_name} (${t.files} FILES)` : t.file_name}</span>
+                    <span className="mono">{t.source_path ? 'SHARING' : t.paused ? 'PAUSED' : `${Math.floor((100 * t.completed_bytes) / Math.max(t.total_size, 1))}%`}</span>
+                  </div>
+                  <div className="card-body">
+                    <div className="stat">
+                      <label>SIZE</label>
+                      <span className="mono">{formatBytes(t.completed_bytes)} / {formatBytes(t.total_size)}</span>
+                    </div>
+                    <div className="stat">
+                      <label>ID</label>
+                      <span className="mono">{t.file_id}</span>
+                    </div>
+                    <div className="stat">
+                      {!t.source_path && (t.paused ? (
+                        <button onClick={() => run('resume_transfer', { fileId: t.file_id }, 'RESUMED')}>RESUME</button>
+                      ) : (
+                        <button onClick={() => run('pause_transfer', { fileId: t.file_id }, 'PAUSED')}>PAUSE</button>
+                      ))}
+                      <button onClick={() => run('cancel_transfer', { fileId: t.file_id }, 'CANCELLED')}>CANCEL</button>
+                    </div>
+                  </div>
+                </div>
+              ))}
+            </div>
           </div>
         </section>
+
+        {limits && (
+          <section className="panel" style={{ marginTop: '1rem' }}>
+            <div className="panel-header">
+              <h3>BANDWIDTH_LIMITS (KB/S, 0 = UNLIMITED)</h3>
+              <span className="count">{limitsStatus}</span>
+            </div>
+            <div className="panel-body settings">
+              {LIMIT_FIELDS.map((field) => (
+                <label key={field}>
+                  {field.toUpperCase()}
+                  <input
+                    type="number"
+                    value={Math.round(limits[field] / 1024)}
+                    onChange={(e) => setLimits({ ...limits, [field]: Number(e.target.value) * 1024 })}
+                  />
+                </label>
+              ))}
+              <button onClick={applyLimits}>APPLY_LIMITS</button>
+            </div>
+          </section>
+        )}
+
+        {config && (
+          <section className="panel" style={{ marginTop: '1rem' }}>
+            <div className="panel-header">
+              <h3>SETTINGS</h3>
+              <span className="count">{configStatus}</span>
+            </div>
+            <div className="panel-body settings">
+              <label>
+                DEVICE_NAME
+                <input
+                  value={config.node.name ?? ''}
+                  onChange={(e) => setConfig({ ...config, node: { name: e.target.value || null } })}
+                />
+              </label>
+              <label>
+                DISCOVERY_PORT
+                <input
+                  type="number"
+                  value={config.discovery.port}
+                  onChange={(e) => setConfig({ ...config, discovery: { ...config.discovery, port: Number(e.target.value) } })}
+                />
+              </label>
+              <label>
+                MULTICAST_INTERFACE
+                <input
+                  value={config.discovery.interface}
+                  onChange={(e) => setConfig({ ...config, discovery: { ...config.discovery, interface: e.target.value } })}
+                />
+              </label>
+              <label>
+                BEACON_INTERVAL_SECS
+                <input
+                  type="number"
+                  value={config.discovery.beacon_interval_secs}
+                  onChange={(e) => setConfig({ ...config, discovery: { ...config.discovery, beacon_interval_secs: Number(e.target.value) } })}
+                />
+              </label>
+              <label>
+                TCP_PORT
+                <input
+                  type="number"
+                  value={config.transport.port}
+                  onChange={(e) => setConfig({ ...config, transport: { ...config.transport, port: Number(e.target.value) } })}
+                />
+              </label>
+              <label>
+                TCP_BIND
+                <input
+                  value={config.transport.bind}
+                  onChange={(e) => setConfig({ ...config, transport: { ...config.transport, bind: e.target.value } })}
+                />
+              </label>
+              <label>
+                API_PORT
+                <input
+                  type="number"
+                  value={config.api.port}
+                  onChange={(e) => setConfig({ ...config, api: { ...config.api, port: Number(e.target.value) } })}
+                />
+              </label>
+              <label>
+                API_BIND
+                <input
+                  value={config.api.bind}
+                  onChange={(e) => setConfig({ ...config, api: { ...config.api, bind: e.target.value } })}
+                />
+              </label>
+              <button onClick={saveConfig}>SAVE_CONFIG</button>
+            </div>
+          </section>
+        )}
       </main>
     </div>
   );